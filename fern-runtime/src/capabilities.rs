@@ -0,0 +1,258 @@
+use std::collections::BTreeSet;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+/// Custom wasm section a module can embed its capability manifest in, as JSON
+pub const CAPABILITY_SECTION: &str = "fern-capabilities";
+
+// Extism registers host functions under this import module
+pub(crate) const HOST_IMPORT_MODULE: &str = "extism:host/user";
+
+/// A group of host functions a guest can be granted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    Kv,
+    Sqlite,
+    Tcp,
+    Debug,
+    Gossip,
+    Rpc,
+    Schedule,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::Kv,
+        Capability::Sqlite,
+        Capability::Tcp,
+        Capability::Debug,
+        Capability::Gossip,
+        Capability::Rpc,
+        Capability::Schedule,
+    ];
+
+    /// Host functions attached when this capability is granted
+    pub fn host_functions(&self) -> &'static [&'static str] {
+        match self {
+            Capability::Kv => &[
+                "kv_store",
+                "kv_read",
+                "kv_delete",
+                "kv_list_keys",
+                "kv_range",
+                "kv_list_tables",
+                "kv_drop_table",
+                "kv_batch",
+                "kv_compare_and_swap",
+                "kv_store_bytes",
+                "kv_read_bytes",
+                "kv_entry_info",
+                "kv_watch",
+                "kv_unwatch",
+            ],
+            Capability::Sqlite => &[
+                "sqlite_execute_enhanced",
+                "sqlite_query_enhanced",
+                "sqlite_describe_table",
+                "sqlite_list_tables",
+                "sqlite_explain_query",
+                "sqlite_get_stats",
+                "sqlite_begin_transaction",
+                "sqlite_commit_transaction",
+                "sqlite_rollback_transaction",
+            ],
+            Capability::Tcp => &[
+                "tcp_connect",
+                "tcp_write",
+                "tcp_read",
+                "tcp_close",
+                "tcp_listen",
+            ],
+            Capability::Debug => &["guest_info", "guest_warn", "guest_error"],
+            Capability::Gossip => &[
+                "broadcast_msg",
+                "gossip_subscribe",
+                "gossip_unsubscribe",
+                "gossip_broadcast_to",
+            ],
+            Capability::Rpc => &["rpc_call"],
+            Capability::Schedule => &[
+                "tick_rate_set",
+                "schedule_create",
+                "schedule_cancel",
+                "timer_set",
+                "timer_cancel",
+            ],
+        }
+    }
+
+    /// The capability providing the host function `name`
+    pub fn for_host_function(name: &str) -> Option<Capability> {
+        Capability::ALL
+            .into_iter()
+            .find(|capability| capability.host_functions().contains(&name))
+    }
+}
+
+impl std::str::FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("unknown capability {s}"))
+    }
+}
+
+/// Capabilities a module asks for, either embedded in the module or submitted alongside it
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapabilityManifest {
+    #[serde(default)]
+    pub capabilities: BTreeSet<Capability>,
+}
+
+/// The manifest embedded in the module's `fern-capabilities` custom section, if it has one
+pub fn embedded_manifest(module: &[u8]) -> anyhow::Result<Option<CapabilityManifest>> {
+    for payload in wasmparser::Parser::new(0).parse_all(module) {
+        if let wasmparser::Payload::CustomSection(section) = payload?
+            && section.name() == CAPABILITY_SECTION
+        {
+            let manifest = serde_json::from_slice(section.data())
+                .map_err(|e| anyhow!("invalid {CAPABILITY_SECTION} section: {e}"))?;
+            return Ok(Some(manifest));
+        }
+    }
+    Ok(None)
+}
+
+/// Capabilities needed to satisfy the module's host function imports
+pub fn imported_capabilities(module: &[u8]) -> anyhow::Result<BTreeSet<Capability>> {
+    let mut capabilities = BTreeSet::new();
+    for payload in wasmparser::Parser::new(0).parse_all(module) {
+        let wasmparser::Payload::ImportSection(imports) = payload? else {
+            continue;
+        };
+        for import in imports {
+            let import = import?;
+            if import.module != HOST_IMPORT_MODULE {
+                continue;
+            }
+            let capability = Capability::for_host_function(import.name)
+                .ok_or_else(|| anyhow!("module imports unknown host function {}", import.name))?;
+            capabilities.insert(capability);
+        }
+    }
+    Ok(capabilities)
+}
+
+/// Work out which capabilities a module is granted.
+///
+/// The submitted manifest wins over one embedded in the module, without either the
+/// module is granted exactly what it imports. Modules which import host functions
+/// they didn't declare, or declare anything outside `allowed`, are rejected.
+/// `allowed` of None places no restriction.
+pub fn resolve_capabilities(
+    module: &[u8],
+    submitted: Option<&CapabilityManifest>,
+    allowed: Option<&BTreeSet<Capability>>,
+) -> anyhow::Result<BTreeSet<Capability>> {
+    let imported = imported_capabilities(module)?;
+    let declared = match submitted {
+        Some(manifest) => manifest.capabilities.clone(),
+        None => match embedded_manifest(module)? {
+            Some(manifest) => manifest.capabilities,
+            None => imported.clone(),
+        },
+    };
+
+    let undeclared: Vec<_> = imported.difference(&declared).collect();
+    if !undeclared.is_empty() {
+        return Err(anyhow!(
+            "module imports host functions for {undeclared:?} which its capability manifest doesn't declare"
+        ));
+    }
+
+    if let Some(allowed) = allowed {
+        let forbidden: Vec<_> = declared.difference(allowed).collect();
+        if !forbidden.is_empty() {
+            return Err(anyhow!(
+                "capabilities {forbidden:?} are not allowed on this node"
+            ));
+        }
+    }
+
+    Ok(declared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hand assembled module importing the given host functions, with an optional manifest section
+    fn module(imports: &[&str], section: Option<&str>) -> Vec<u8> {
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+
+        // type section: one `() -> ()` func type
+        wasm.extend([0x01, 0x04, 0x01, 0x60, 0x00, 0x00]);
+
+        let mut body = vec![imports.len() as u8];
+        for name in imports {
+            body.push(HOST_IMPORT_MODULE.len() as u8);
+            body.extend(HOST_IMPORT_MODULE.as_bytes());
+            body.push(name.len() as u8);
+            body.extend(name.as_bytes());
+            body.extend([0x00, 0x00]);
+        }
+        wasm.push(0x02);
+        wasm.push(body.len() as u8);
+        wasm.extend(body);
+
+        if let Some(section) = section {
+            let mut body = vec![CAPABILITY_SECTION.len() as u8];
+            body.extend(CAPABILITY_SECTION.as_bytes());
+            body.extend(section.as_bytes());
+            wasm.push(0x00);
+            wasm.push(body.len() as u8);
+            wasm.extend(body);
+        }
+        wasm
+    }
+
+    #[test]
+    fn capability_manifest_resolution() {
+        let plain = module(&["kv_read", "guest_info"], None);
+        assert_eq!(
+            imported_capabilities(&plain).unwrap(),
+            BTreeSet::from([Capability::Kv, Capability::Debug])
+        );
+        assert!(embedded_manifest(&plain).unwrap().is_none());
+
+        // Without a manifest the module gets exactly what it imports
+        assert_eq!(
+            resolve_capabilities(&plain, None, None).unwrap(),
+            BTreeSet::from([Capability::Kv, Capability::Debug])
+        );
+
+        // Embedded manifests can ask for more than is imported
+        let embedded = module(&["kv_read"], Some(r#"{"capabilities":["kv","gossip"]}"#));
+        assert_eq!(
+            resolve_capabilities(&embedded, None, None).unwrap(),
+            BTreeSet::from([Capability::Kv, Capability::Gossip])
+        );
+
+        // A submitted manifest overrides the embedded one and must cover the imports
+        let submitted = CapabilityManifest {
+            capabilities: BTreeSet::from([Capability::Gossip]),
+        };
+        assert!(resolve_capabilities(&embedded, Some(&submitted), None).is_err());
+
+        // Node policy
+        let allowed = BTreeSet::from([Capability::Kv, Capability::Debug]);
+        assert!(resolve_capabilities(&plain, None, Some(&allowed)).is_ok());
+        assert!(resolve_capabilities(&embedded, None, Some(&allowed)).is_err());
+
+        // Imports which don't belong to any capability
+        assert!(imported_capabilities(&module(&["launch_missiles"], None)).is_err());
+    }
+}
@@ -1,9 +1,14 @@
 use iroh::EndpointId;
+use serde::{Deserialize, Serialize};
 
 use crate::guest_fns::gossip::InboundGossipMsg;
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
 pub enum GossipMessage {
     GuestGossip(InboundGossipMsg),
+    /// A guest was deployed (created or updated) on `node_id`, which serves
+    /// the module with blake3 `hash` to peers that want to run it too
     GuestCreated {
         node_id: EndpointId,
         name: String,
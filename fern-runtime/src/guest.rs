@@ -1,6 +1,8 @@
-use std::path::PathBuf;
+use std::{collections::BTreeSet, path::PathBuf, time::Duration};
 
 use anyhow::anyhow;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
 use extism::{Manifest, Plugin, PluginBuilder, UserData, Wasm};
 use iroh::{
     Endpoint, EndpointId,
@@ -8,30 +10,145 @@ use iroh::{
 };
 
 use crate::{
+    capabilities::Capability,
     guest_fns::{
         self,
         gossip::{GuestGossip, InboundGossipMsg},
+        kv::{GuestKvData, KvChange},
+        rpc::{GuestRpc, RpcRequest, RpcResponse},
+        schedule::{DEFAULT_TICK_INTERVAL, GuestSchedule, ScheduledTask},
         sqlite_improved::GuestSqliteDbImproved,
+        tcp::{GuestTcp, TcpAccepted, TcpPermissions},
+        timer::{GuestTimers, TimerFired},
     },
+    http::{HttpRequest, HttpResponse},
     iroh_helpers::iroh_bundle,
+    limits::{GuestLimits, HostCallLimiter},
 };
 
-const MESSAGE_FN: &str = "gossipMessageHandler";
+pub(crate) const MESSAGE_FN: &str = "gossipMessageHandler";
+pub(crate) const RPC_FN: &str = "rpcHandler";
 const SQL_TEST: &str = "testEnhancedSql";
-const SHUTDOWN_FN: &str = "shutdown";
-const TICK_FN: &str = "tick";
-const INIT_FN: &str = "init";
+pub(crate) const SHUTDOWN_FN: &str = "shutdown";
+pub(crate) const TICK_FN: &str = "tick";
+pub(crate) const INIT_FN: &str = "init";
+pub(crate) const TCP_ACCEPTED_FN: &str = "tcpAccepted";
+pub(crate) const SCHEDULED_TASK_FN: &str = "scheduledTask";
+pub(crate) const TIMER_FIRED_FN: &str = "timerFired";
+pub(crate) const HTTP_HANDLER_FN: &str = "httpHandler";
+pub(crate) const KV_CHANGED_FN: &str = "kvChanged";
 
 pub type IrohBundle = (Endpoint, RouterBuilder, Vec<EndpointId>);
 
+/// How a round of handler export calls went. A trapped call is rolled back and
+/// the rest are still delivered, so failures are counted rather than returned
+#[derive(Debug, Default)]
+pub struct Delivery {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// The first call that failed, later ones are only counted
+    pub first_error: Option<anyhow::Error>,
+}
+
+impl Delivery {
+    fn record<T>(&mut self, export: &str, res: &Result<T, extism::Error>) {
+        match res {
+            Ok(_) => self.succeeded += 1,
+            Err(e) => {
+                self.failed += 1;
+                if self.first_error.is_none() {
+                    self.first_error = Some(anyhow!("{export} failed {e}"));
+                }
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: Delivery) {
+        self.succeeded += other.succeeded;
+        self.failed += other.failed;
+        if self.first_error.is_none() {
+            self.first_error = other.first_error;
+        }
+    }
+}
+
+/// Exports Fern calls into. All of them are optional, only the ones a module
+/// implements are called
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GuestExport {
+    Init,
+    Tick,
+    Shutdown,
+    GossipMessageHandler,
+    RpcHandler,
+    TcpAccepted,
+    ScheduledTask,
+    TimerFired,
+    HttpHandler,
+    KvChanged,
+}
+
+impl GuestExport {
+    pub const ALL: [GuestExport; 10] = [
+        GuestExport::Init,
+        GuestExport::Tick,
+        GuestExport::Shutdown,
+        GuestExport::GossipMessageHandler,
+        GuestExport::RpcHandler,
+        GuestExport::TcpAccepted,
+        GuestExport::ScheduledTask,
+        GuestExport::TimerFired,
+        GuestExport::HttpHandler,
+        GuestExport::KvChanged,
+    ];
+
+    pub fn function_name(&self) -> &'static str {
+        match self {
+            GuestExport::Init => INIT_FN,
+            GuestExport::Tick => TICK_FN,
+            GuestExport::Shutdown => SHUTDOWN_FN,
+            GuestExport::GossipMessageHandler => MESSAGE_FN,
+            GuestExport::RpcHandler => RPC_FN,
+            GuestExport::TcpAccepted => TCP_ACCEPTED_FN,
+            GuestExport::ScheduledTask => SCHEDULED_TASK_FN,
+            GuestExport::TimerFired => TIMER_FIRED_FN,
+            GuestExport::HttpHandler => HTTP_HANDLER_FN,
+            GuestExport::KvChanged => KV_CHANGED_FN,
+        }
+    }
+
+    /// Exports the plugin implements
+    pub fn detect(plugin: &Plugin) -> BTreeSet<GuestExport> {
+        GuestExport::ALL
+            .into_iter()
+            .filter(|export| plugin.function_exists(export.function_name()))
+            .collect()
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct GuestConfig {
     pub name: String,
     pub host_data_path: Option<PathBuf>,
+    pub tcp: TcpPermissions,
+    pub limits: GuestLimits,
+    /// Host functions attached to the plugin. None attaches everything
+    pub capabilities: Option<BTreeSet<Capability>>,
+}
+
+impl GuestConfig {
+    pub fn is_granted(&self, capability: Capability) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_none_or(|capabilities| capabilities.contains(&capability))
+    }
 }
 
 pub struct Guest {
     pub plugin: Plugin,
+    /// Detected when the plugin is built
+    pub exports: BTreeSet<GuestExport>,
     pub network_data: NetworkUserData,
     pub plugin_userdata: PluginUserData,
     pub router: Router,
@@ -39,9 +156,16 @@ pub struct Guest {
 }
 
 impl Guest {
-    pub async fn tick_gossip(&mut self) -> anyhow::Result<()> {
+    pub fn implements(&self, export: GuestExport) -> bool {
+        self.exports.contains(&export)
+    }
+
+    pub async fn tick_gossip(&mut self) -> anyhow::Result<Delivery> {
+        let Some(gossip) = &self.network_data.gossip else {
+            return Ok(Delivery::default());
+        };
         let msgs = {
-            let network_data = self.network_data.gossip.get()?;
+            let network_data = gossip.get()?;
             // Forced scope to drop this fella
             let mut locked = network_data.try_lock().map_err(|e| anyhow!("{e}"))?;
             let mut msgs = vec![];
@@ -51,25 +175,299 @@ impl Guest {
             msgs
         };
 
+        // Drained either way so messages don't pile up for a guest which ignores them
+        if !self.implements(GuestExport::GossipMessageHandler) {
+            return Ok(Delivery::default());
+        }
+
+        let mut delivery = Delivery::default();
         for msg in msgs {
             // This kinda isn't great since the guest could be failing
             // but its better than nothing atm
-            let _ = self.plugin.call::<InboundGossipMsg, ()>(MESSAGE_FN, msg);
+            let res = self.plugin.call::<InboundGossipMsg, ()>(MESSAGE_FN, msg);
+            self.rollback_on_error(&res);
+            delivery.record(MESSAGE_FN, &res);
+        }
+
+        Ok(delivery)
+    }
+
+    pub async fn tick_rpc(&mut self) -> anyhow::Result<Delivery> {
+        let Some(rpc) = &self.network_data.rpc else {
+            return Ok(Delivery::default());
+        };
+        let requests = {
+            let network_data = rpc.get()?;
+            let mut locked = network_data.try_lock().map_err(|e| anyhow!("{e}"))?;
+            let mut requests = vec![];
+            while let Ok(request) = locked.inbound_rx.try_recv() {
+                requests.push(request);
+            }
+            requests
+        };
+
+        let mut delivery = Delivery::default();
+        for request in requests {
+            if !self.implements(GuestExport::RpcHandler) {
+                let _ = request
+                    .reply
+                    .send(RpcResponse::error(format!("guest doesn't export {RPC_FN}")));
+                continue;
+            }
+            let res = self
+                .plugin
+                .call::<RpcRequest, RpcResponse>(RPC_FN, request.request);
+            self.rollback_on_error(&res);
+            delivery.record(RPC_FN, &res);
+            let response = res.unwrap_or_else(|e| RpcResponse::error(e.to_string()));
+            // The caller may have timed out already
+            let _ = request.reply.send(response);
+        }
+
+        Ok(delivery)
+    }
+
+    pub async fn tick_tcp(&mut self) -> anyhow::Result<Delivery> {
+        let Some(tcp) = &self.plugin_userdata.tcp else {
+            return Ok(Delivery::default());
+        };
+        let accepted = {
+            let tcp = tcp.get()?;
+            let mut locked = tcp.try_lock().map_err(|e| anyhow!("{e}"))?;
+            locked.accept_pending()
+        };
+
+        // Dropping the connections closes them
+        if !self.implements(GuestExport::TcpAccepted) {
+            return Ok(Delivery::default());
+        }
+
+        let mut delivery = Delivery::default();
+        for connection in accepted {
+            let res = self
+                .plugin
+                .call::<TcpAccepted, ()>(TCP_ACCEPTED_FN, connection);
+            self.rollback_on_error(&res);
+            delivery.record(TCP_ACCEPTED_FN, &res);
+        }
+
+        Ok(delivery)
+    }
+
+    /// Call `scheduledTask` for every schedule which has come due
+    pub fn tick_schedules(&mut self) -> anyhow::Result<Delivery> {
+        let Some(schedule) = &self.plugin_userdata.schedule else {
+            return Ok(Delivery::default());
+        };
+        let due = {
+            let schedule = schedule.get()?;
+            let mut locked = schedule.lock().unwrap();
+            locked.take_due(Utc::now())
+        };
+
+        if !self.implements(GuestExport::ScheduledTask) {
+            return Ok(Delivery::default());
+        }
+
+        let mut delivery = Delivery::default();
+        for task in due {
+            let res = self
+                .plugin
+                .call::<ScheduledTask, ()>(SCHEDULED_TASK_FN, task);
+            self.rollback_on_error(&res);
+            delivery.record(SCHEDULED_TASK_FN, &res);
+        }
+
+        Ok(delivery)
+    }
+
+    /// Call `timerFired` for every timer which has come due
+    pub fn tick_timers(&mut self) -> anyhow::Result<Delivery> {
+        let Some(timers) = &self.plugin_userdata.timers else {
+            return Ok(Delivery::default());
+        };
+        let fired = {
+            let timers = timers.get()?;
+            let mut locked = timers.lock().unwrap();
+            locked.take_due(Utc::now().timestamp_millis())
+        };
+
+        if !self.implements(GuestExport::TimerFired) {
+            return Ok(Delivery::default());
+        }
+
+        let mut delivery = Delivery::default();
+        for timer in fired {
+            let res = self.plugin.call::<TimerFired, ()>(TIMER_FIRED_FN, timer);
+            self.rollback_on_error(&res);
+            delivery.record(TIMER_FIRED_FN, &res);
         }
 
-        Ok(())
+        Ok(delivery)
+    }
+
+    /// Time until the next timer is due, zero if one is already due
+    pub fn next_timer(&self) -> Option<Duration> {
+        let timers = self.plugin_userdata.timers.as_ref()?.get().ok()?;
+        let due_at_ms = timers.lock().unwrap().next_due_ms()?;
+        let wait_ms = due_at_ms - Utc::now().timestamp_millis();
+        Some(Duration::from_millis(wait_ms.max(0) as u64))
+    }
+
+    /// Id the next timer will get, None without the schedule capability
+    pub fn next_timer_id(&self) -> Option<u64> {
+        let timers = self.plugin_userdata.timers.as_ref()?.get().ok()?;
+        Some(timers.lock().unwrap().next_id())
+    }
+
+    /// Forget timers set without `persist` before `before_id`. Called once a restart or
+    /// update has replaced the module which set them, a failed update leaves them alone
+    pub fn drop_transient_timers(&self, before_id: u64) {
+        if let Some(Ok(timers)) = self.plugin_userdata.timers.as_ref().map(|ud| ud.get()) {
+            timers.lock().unwrap().drop_transient(before_id);
+        }
+    }
+
+    /// Delete kv keys whose ttl has run out, returning how many were deleted
+    pub fn sweep_kv(&self) -> anyhow::Result<usize> {
+        let Some(kv) = &self.plugin_userdata.kv else {
+            return Ok(0);
+        };
+        let kv = kv.get()?;
+        let mut locked = kv.lock().unwrap();
+        locked.sweep_expired(Utc::now().timestamp_millis())
+    }
+
+    /// Call `kvChanged` for every committed change to a key the guest watches
+    pub fn tick_kv_changes(&mut self) -> anyhow::Result<Delivery> {
+        let Some(kv) = &self.plugin_userdata.kv else {
+            return Ok(Delivery::default());
+        };
+        // Released before calling the guest, kvChanged is free to use kv itself
+        let changes = {
+            let kv = kv.get()?;
+            let mut locked = kv.lock().unwrap();
+            locked.take_changes()
+        };
+
+        if !self.implements(GuestExport::KvChanged) {
+            return Ok(Delivery::default());
+        }
+
+        let mut delivery = Delivery::default();
+        for change in changes {
+            let res = self.plugin.call::<KvChange, ()>(KV_CHANGED_FN, change);
+            self.rollback_on_error(&res);
+            delivery.record(KV_CHANGED_FN, &res);
+        }
+
+        Ok(delivery)
+    }
+
+    /// How often `tick` should be called, None when the guest has nothing to tick
+    pub fn tick_interval(&self) -> Option<Duration> {
+        if !self.implements(GuestExport::Tick) {
+            return None;
+        }
+        match self.plugin_userdata.schedule.as_ref().map(|ud| ud.get()) {
+            Some(Ok(schedule)) => schedule.lock().unwrap().tick_interval(),
+            _ => Some(DEFAULT_TICK_INTERVAL),
+        }
+    }
+
+    /// Answer a request from the node's HTTP gateway. Failures become 5xx responses,
+    /// the delivery says whether the handler was called and how that went
+    pub fn handle_http(&mut self, request: HttpRequest) -> (HttpResponse, Delivery) {
+        let mut delivery = Delivery::default();
+        if !self.implements(GuestExport::HttpHandler) {
+            let response =
+                HttpResponse::text(404, format!("guest doesn't export {HTTP_HANDLER_FN}"));
+            return (response, delivery);
+        }
+        let res = self
+            .plugin
+            .call::<HttpRequest, HttpResponse>(HTTP_HANDLER_FN, request);
+        self.rollback_on_error(&res);
+        delivery.record(HTTP_HANDLER_FN, &res);
+        // Trap messages aren't for whoever is on the other end of the gateway
+        let response = res.unwrap_or_else(|e| {
+            log::warn!("{HTTP_HANDLER_FN} failed {e}");
+            HttpResponse::text(500, "guest failed to handle the request")
+        });
+        (response, delivery)
     }
 
     pub fn initialize(&mut self) -> anyhow::Result<()> {
-        Ok(self.plugin.call(INIT_FN, ())?)
+        if !self.implements(GuestExport::Init) {
+            return Ok(());
+        }
+        let res = self.plugin.call::<(), ()>(INIT_FN, ());
+        self.rollback_on_error(&res);
+        Ok(res?)
     }
 
     pub fn shutdown(&mut self) -> anyhow::Result<()> {
-        Ok(self.plugin.call(SHUTDOWN_FN, ())?)
+        let res = if self.implements(GuestExport::Shutdown) {
+            self.plugin.call::<(), ()>(SHUTDOWN_FN, ())
+        } else {
+            Ok(())
+        };
+        // Nothing is going to commit these once the guest is gone
+        self.rollback_transactions("guest shutdown");
+        Ok(res?)
     }
 
     pub fn tick(&mut self) -> anyhow::Result<()> {
-        Ok(self.plugin.call(TICK_FN, ())?)
+        if let Some(Ok(sqlite)) = self.plugin_userdata.sqlite.as_ref().map(|ud| ud.get()) {
+            sqlite.lock().unwrap().expire_transactions();
+        }
+
+        if !self.implements(GuestExport::Tick) {
+            return Ok(());
+        }
+        let res = self.plugin.call::<(), ()>(TICK_FN, ());
+        self.rollback_on_error(&res);
+        Ok(res?)
+    }
+
+    /// Call the shutdown export of a guest being replaced by one which shares its host
+    /// state. Unlike `shutdown` only the transactions the export leaves open are
+    /// rolled back, the replacement's are left alone
+    pub fn shutdown_replaced(&mut self) -> anyhow::Result<()> {
+        if !self.implements(GuestExport::Shutdown) {
+            return Ok(());
+        }
+        let sqlite = self
+            .plugin_userdata
+            .sqlite
+            .as_ref()
+            .and_then(|ud| ud.get().ok());
+        let depth = sqlite
+            .as_ref()
+            .map(|sqlite| sqlite.lock().unwrap().open_transaction_count());
+
+        let res = self.plugin.call::<(), ()>(SHUTDOWN_FN, ());
+        if let (Some(sqlite), Some(depth)) = (sqlite, depth) {
+            sqlite
+                .lock()
+                .unwrap()
+                .rollback_above(depth, "replaced guest shutdown");
+        }
+        Ok(res?)
+    }
+
+    /// Roll back any sqlite transactions the guest left open
+    pub fn rollback_transactions(&self, reason: &str) {
+        if let Some(Ok(sqlite)) = self.plugin_userdata.sqlite.as_ref().map(|ud| ud.get()) {
+            sqlite.lock().unwrap().rollback_all(reason);
+        }
+    }
+
+    // A trapped guest can't finish whatever transaction it had open
+    fn rollback_on_error<T>(&self, res: &Result<T, extism::Error>) {
+        if res.is_err() {
+            self.rollback_transactions("guest call failed");
+        }
     }
 
     pub fn get_node_id(&self) -> EndpointId {
@@ -77,8 +475,10 @@ impl Guest {
     }
 }
 
+// Userdata is only present for capabilities the guest was granted
 pub struct NetworkUserData {
-    pub gossip: UserData<GuestGossip>,
+    pub gossip: Option<UserData<GuestGossip>>,
+    pub rpc: Option<UserData<GuestRpc>>,
 }
 
 pub fn new_guest(
@@ -105,6 +505,7 @@ pub fn new_guest_with_userdata(
 
     let router = router.spawn();
     Ok(Guest {
+        exports: GuestExport::detect(&plugin),
         plugin,
         network_data,
         plugin_userdata,
@@ -115,7 +516,15 @@ pub fn new_guest_with_userdata(
 
 #[derive(Clone)]
 pub struct PluginUserData {
-    pub sqlite: UserData<GuestSqliteDbImproved>,
+    // The new module shares the open database but not the watches, see `GuestKvData::sharing`
+    pub kv: Option<UserData<GuestKvData>>,
+    pub sqlite: Option<UserData<GuestSqliteDbImproved>>,
+    // Not carried over on module updates, open connections belong to the old module
+    pub tcp: Option<UserData<GuestTcp>>,
+    // Registered again by the new module's init, same as tcp
+    pub schedule: Option<UserData<GuestSchedule>>,
+    // Carried over so persisted timers survive restarts and updates
+    pub timers: Option<UserData<GuestTimers>>,
 }
 
 pub fn new_plugin(
@@ -130,36 +539,113 @@ pub fn new_plugin(
     Option<NetworkUserData>,
 )> {
     let manifest = Manifest::new([guest_module]).with_config_key("id", uuid::Uuid::new_v4());
+    let manifest = config.limits.apply_to_manifest(manifest);
 
     let builder = PluginBuilder::new(manifest).with_wasi(true);
+    let mut builder = config.limits.apply_to_builder(builder);
 
-    let builder = guest_fns::kv::attach_guest_kv(builder, config.clone());
-    let (builder, sqlite) = guest_fns::sqlite_improved::attach_guest_sqlite_improved(
-        builder,
-        config.clone(),
-        existing_user_data.as_ref().map(|ud| ud.sqlite.clone()),
-    );
-    let mut builder = guest_fns::debug::attach_guest_debug(builder);
+    // Every host function shares one call budget
+    let limiter = HostCallLimiter::new(&config.limits);
 
-    let mut network_user_data = None;
-    if let Some((endpoint, router_builder, bootstrap)) = iroh {
-        // TODO we need to return the gossip_user_data somehow
-        let (new_builder, new_router, gossip_user_data) = guest_fns::gossip::attach_guest_gossip(
+    let mut kv = None;
+    if config.is_granted(Capability::Kv) {
+        let (new_builder, kv_user_data) = guest_fns::kv::attach_guest_kv(
             builder,
-            router_builder,
-            endpoint.clone(),
-            bootstrap.clone(),
+            config.clone(),
+            existing_user_data.as_ref().and_then(|ud| ud.kv.clone()),
+            &limiter,
         );
+        kv = Some(kv_user_data);
+        builder = new_builder;
+    }
 
-        iroh = Some((endpoint, new_router, bootstrap));
+    let mut sqlite = None;
+    if config.is_granted(Capability::Sqlite) {
+        let (new_builder, sqlite_user_data) =
+            guest_fns::sqlite_improved::attach_guest_sqlite_improved(
+                builder,
+                config.clone(),
+                existing_user_data.as_ref().and_then(|ud| ud.sqlite.clone()),
+                &limiter,
+            );
+        sqlite = Some(sqlite_user_data);
+        builder = new_builder;
+    }
 
-        network_user_data = Some(NetworkUserData {
-            gossip: gossip_user_data,
-        });
-        builder = new_builder
+    let mut tcp = None;
+    if config.is_granted(Capability::Tcp) {
+        let (new_builder, tcp_user_data) =
+            guest_fns::tcp::attach_guest_tcp(builder, config.clone(), &limiter);
+        tcp = Some(tcp_user_data);
+        builder = new_builder;
+    }
+
+    let mut schedule = None;
+    let mut timers = None;
+    if config.is_granted(Capability::Schedule) {
+        let (new_builder, schedule_user_data) =
+            guest_fns::schedule::attach_guest_schedule(builder, &limiter);
+        schedule = Some(schedule_user_data);
+        builder = new_builder;
+
+        let (new_builder, timer_user_data) = guest_fns::timer::attach_guest_timers(
+            builder,
+            config.clone(),
+            existing_user_data.as_ref().and_then(|ud| ud.timers.clone()),
+            &limiter,
+        );
+        timers = Some(timer_user_data);
+        builder = new_builder;
+    }
+
+    if config.is_granted(Capability::Debug) {
+        builder = guest_fns::debug::attach_guest_debug(builder, &limiter);
+    }
+
+    let mut network_user_data = None;
+    if let Some((endpoint, mut router_builder, bootstrap)) = iroh {
+        let mut network = NetworkUserData {
+            gossip: None,
+            rpc: None,
+        };
+
+        if config.is_granted(Capability::Gossip) {
+            let (new_builder, new_router, gossip_user_data) =
+                guest_fns::gossip::attach_guest_gossip(
+                    builder,
+                    router_builder,
+                    endpoint.clone(),
+                    bootstrap.clone(),
+                    &limiter,
+                );
+            network.gossip = Some(gossip_user_data);
+            builder = new_builder;
+            router_builder = new_router;
+        }
+
+        if config.is_granted(Capability::Rpc) {
+            let (new_builder, new_router, rpc_user_data) = guest_fns::rpc::attach_guest_rpc(
+                builder,
+                router_builder,
+                endpoint.clone(),
+                &limiter,
+            );
+            network.rpc = Some(rpc_user_data);
+            builder = new_builder;
+            router_builder = new_router;
+        }
+
+        iroh = Some((endpoint, router_builder, bootstrap));
+        network_user_data = Some(network);
     }
     let plugin = builder.build()?;
 
-    let ud = PluginUserData { sqlite };
+    let ud = PluginUserData {
+        kv,
+        sqlite,
+        tcp,
+        schedule,
+        timers,
+    };
     Ok((plugin, ud, iroh, network_user_data))
 }
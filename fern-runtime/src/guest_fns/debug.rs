@@ -2,12 +2,32 @@ use extism::{PTR, PluginBuilder, UserData, host_fn};
 use log::{error, info, warn};
 use std::thread;
 
-pub fn attach_guest_debug(builder: PluginBuilder) -> PluginBuilder {
+use crate::limits::HostCallLimiter;
+
+pub fn attach_guest_debug(builder: PluginBuilder, limiter: &HostCallLimiter) -> PluginBuilder {
     let user_data = UserData::new(());
     builder
-        .with_function("guest_info", [PTR], [], user_data.clone(), guest_info)
-        .with_function("guest_warn", [PTR], [], user_data.clone(), guest_warn)
-        .with_function("guest_error", [PTR], [], user_data.clone(), guest_error)
+        .with_function(
+            "guest_info",
+            [PTR],
+            [],
+            user_data.clone(),
+            limiter.limit("guest_info", guest_info),
+        )
+        .with_function(
+            "guest_warn",
+            [PTR],
+            [],
+            user_data.clone(),
+            limiter.limit("guest_warn", guest_warn),
+        )
+        .with_function(
+            "guest_error",
+            [PTR],
+            [],
+            user_data.clone(),
+            limiter.limit("guest_error", guest_error),
+        )
 }
 
 host_fn!(guest_info(_user_data: (); message: String) -> () {
@@ -1,32 +1,105 @@
-use anyhow::anyhow;
-use extism::{CurrentPlugin, FromBytes, Function, PTR, PluginBuilder, ToBytes, UserData, Val, ValType, host_fn, sdk::ExtismFunction};
+use std::collections::HashMap;
+
+use extism::{FromBytes, PTR, PluginBuilder, ToBytes, UserData, host_fn};
 use extism_convert::Json;
 use iroh::{Endpoint, EndpointId, protocol::RouterBuilder};
-use iroh_gossip::{ALPN, Gossip};
-use log::info;
+use iroh_gossip::{ALPN, Gossip, TopicId};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::task::JoinHandle;
+use tokio::{runtime::Handle, task::JoinHandle};
 use tokio_stream::StreamExt;
 
-const GLOBAL_TOPIC: &str = "fern-global";
+use crate::limits::HostCallLimiter;
+
+/// Topic every guest is subscribed to on startup. Guests which only care about
+/// their own topics can leave it with `gossip_unsubscribe`.
+pub const GLOBAL_TOPIC: &str = "fern-global";
 
-type OutboundSendChannel = tokio::sync::mpsc::Sender<OutboundGossipMsg>;
-type OutboundRecvChannel = tokio::sync::mpsc::Receiver<OutboundGossipMsg>;
+type OutboundSendChannel = tokio::sync::mpsc::Sender<Value>;
+type OutboundRecvChannel = tokio::sync::mpsc::Receiver<Value>;
 
 type InboundSendChannel = tokio::sync::mpsc::Sender<InboundGossipMsg>;
 type InboundRecvChannel = tokio::sync::mpsc::Receiver<InboundGossipMsg>;
 
 pub struct GuestGossip {
     gossip: Gossip,
-    global_handle: JoinHandle<anyhow::Result<()>>,
-    // Transmits messages to the iroh gossip layer to be broadcast 
-    // on the global gossip channel
-    outbound_tx: OutboundSendChannel,
+    bootstrap: Vec<EndpointId>,
+    // Runtime the per-topic tasks are spawned on. Host functions are called
+    // synchronously from the guest thread so we can't rely on `Handle::current`
+    runtime: Handle,
+    // Guest topic name -> active iroh gossip subscription
+    topics: HashMap<String, TopicSubscription>,
+    // Shared by every topic task to pass messages back to the guest
+    inbound_tx: InboundSendChannel,
     // Receives messages from the iroh gossip layer to be passed to guest
     pub inbound_rx: InboundRecvChannel,
 }
 
+struct TopicSubscription {
+    // Transmits messages to the iroh gossip layer to be broadcast on this topic
+    outbound_tx: OutboundSendChannel,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Drop for TopicSubscription {
+    fn drop(&mut self) {
+        // Dropping the iroh topic handles inside the task leaves the swarm
+        self.handle.abort();
+    }
+}
+
+impl GuestGossip {
+    /// Subscribe to `topic`. Returns false if the guest was already subscribed.
+    pub fn subscribe(&mut self, topic: &str) -> bool {
+        if self.topics.contains_key(topic) {
+            return false;
+        }
+
+        let (outbound_tx, outbound_rx) = tokio::sync::mpsc::channel(1000);
+        let handle = self.runtime.spawn(plugin_topic_gossip_task(
+            self.gossip.clone(),
+            topic.to_string(),
+            self.inbound_tx.clone(),
+            outbound_rx,
+            self.bootstrap.clone(),
+        ));
+
+        self.topics.insert(
+            topic.to_string(),
+            TopicSubscription {
+                outbound_tx,
+                handle,
+            },
+        );
+        true
+    }
+
+    /// Unsubscribe from `topic`. Returns false if the guest wasn't subscribed.
+    pub fn unsubscribe(&mut self, topic: &str) -> bool {
+        self.topics.remove(topic).is_some()
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &String> {
+        self.topics.keys()
+    }
+
+    fn broadcast(&self, topic: &str, content: Value) -> Result<(), extism::Error> {
+        let Some(subscription) = self.topics.get(topic) else {
+            return Err(extism::Error::msg(format!(
+                "not subscribed to gossip topic {topic}"
+            )));
+        };
+        subscription.outbound_tx.try_send(content)?;
+        Ok(())
+    }
+}
+
+/// Maps a guest facing topic name onto its iroh gossip topic
+pub fn topic_id(topic: &str) -> TopicId {
+    hmac_sha256::Hash::hash(topic.as_bytes()).into()
+}
+
 #[derive(Debug, Serialize, Deserialize, ToBytes)]
 #[encoding(Json)]
 pub struct InboundGossipMsg {
@@ -41,98 +114,156 @@ pub struct OutboundGossipMsg {
     pub content: Value,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GossipTopicInput {
+    pub topic: String,
+}
+
 pub fn attach_guest_gossip(
     plugin: PluginBuilder,
     mut router: RouterBuilder,
     endpoint: Endpoint,
     bootstrap: Vec<EndpointId>,
+    limiter: &HostCallLimiter,
 ) -> (PluginBuilder, RouterBuilder, UserData<GuestGossip>) {
-
-    // Global Gossip
     let gossip = Gossip::builder().spawn(endpoint.clone());
 
     router = router.accept(ALPN, gossip.clone());
 
-    let (outbound_tx, outbound_rx) = tokio::sync::mpsc::channel(1000);
     let (inbound_tx, inbound_rx) = tokio::sync::mpsc::channel(1000);
 
-    let global_handle = tokio::task::spawn(plugin_global_gossip_task(
-        gossip.clone(),
-        inbound_tx,
-        outbound_rx,
-        bootstrap,
-    ));
-
-    let gossip = UserData::new(GuestGossip {
+    let mut guest_gossip = GuestGossip {
         gossip,
-        global_handle,
-        outbound_tx,
+        bootstrap,
+        runtime: Handle::current(),
+        topics: HashMap::new(),
+        inbound_tx,
         inbound_rx,
-    });
+    };
+    guest_gossip.subscribe(GLOBAL_TOPIC);
+
+    let gossip = UserData::new(guest_gossip);
+
+    let plugin = plugin
+        .with_function(
+            "broadcast_msg",
+            [PTR],
+            [PTR],
+            gossip.clone(),
+            limiter.limit("broadcast_msg", broadcast_msg),
+        )
+        .with_function(
+            "gossip_subscribe",
+            [PTR],
+            [PTR],
+            gossip.clone(),
+            limiter.limit("gossip_subscribe", gossip_subscribe),
+        )
+        .with_function(
+            "gossip_unsubscribe",
+            [PTR],
+            [PTR],
+            gossip.clone(),
+            limiter.limit("gossip_unsubscribe", gossip_unsubscribe),
+        )
+        .with_function(
+            "gossip_broadcast_to",
+            [PTR],
+            [PTR],
+            gossip.clone(),
+            limiter.limit("gossip_broadcast_to", gossip_broadcast_to),
+        );
 
-    let plugin = plugin.with_function("broadcast_msg", [PTR], [PTR], gossip.clone(), broadcast_msg);
-    
     (plugin, router, gossip)
 }
 
-async fn plugin_global_gossip_task(
+async fn plugin_topic_gossip_task(
     gossip: Gossip,
+    topic: String,
     inbound_tx: InboundSendChannel,
-    outbound_rx: OutboundRecvChannel,
+    mut outbound_rx: OutboundRecvChannel,
     bootstrap: Vec<EndpointId>,
 ) -> anyhow::Result<()> {
-    let global_topic = hmac_sha256::Hash::hash(GLOBAL_TOPIC.as_bytes());
-    let mut started = gossip.subscribe(global_topic.into(), bootstrap).await?;
+    let mut started = gossip.subscribe(topic_id(&topic), bootstrap).await?;
 
-    info!("Gossip waiting for first peer connection");
+    info!("Gossip topic {topic} waiting for first peer connection");
     // Wait until we've connected to at least one peer
     started.joined().await?;
-    info!("Gossip has connected to a peer");
-
-    let (global_tx, mut global_rx) = started.split();
-
-    // Listen for guest broadcast requests and broadcast via iroh gossip
-    tokio::task::spawn(async move {
-        let mut recv_channel = outbound_rx;
-        let global_tx = global_tx;
-
-        while let Some(OutboundGossipMsg { topic, content }) = recv_channel.recv().await {
-            let inbound_msg = InboundGossipMsg {
-                topic,
-                content,
-            };
-            let bytes = serde_json::to_vec(&inbound_msg).unwrap();
-            let res = global_tx.broadcast(bytes.into()).await;
-            info!("guest gossip broadcast res {res:?}")
-        }
-    });
-
-    // Read incoming messages and send on inbound_tx to be passed to guest
-    while let Some(Ok(next)) = global_rx.next().await {
-        match next {
-            iroh_gossip::api::Event::Received(message) => {
-                if let Ok(msg) = serde_json::from_slice(&message.content) {
-                    let res = inbound_tx.send(msg).await;
-                }
+    info!("Gossip topic {topic} has connected to a peer");
+
+    let (topic_tx, mut topic_rx) = started.split();
+
+    loop {
+        tokio::select! {
+            // Listen for guest broadcast requests and broadcast via iroh gossip
+            content = outbound_rx.recv() => {
+                let Some(content) = content else {
+                    break;
+                };
+                let msg = InboundGossipMsg {
+                    topic: topic.clone(),
+                    content,
+                };
+                let bytes = serde_json::to_vec(&msg)?;
+                let res = topic_tx.broadcast(bytes.into()).await;
+                info!("guest gossip broadcast topic={topic} res {res:?}")
             }
-            event => {
-                info!("guest gossip event {event:?}");
+
+            // Read incoming messages and send on inbound_tx to be passed to guest
+            next = topic_rx.next() => {
+                let Some(Ok(next)) = next else {
+                    break;
+                };
+                match next {
+                    iroh_gossip::api::Event::Received(message) => {
+                        if let Ok(mut msg) = serde_json::from_slice::<InboundGossipMsg>(&message.content) {
+                            // The topic a message arrived on is decided by the swarm,
+                            // not whatever the sender put in the payload
+                            msg.topic = topic.clone();
+                            if inbound_tx.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    event => {
+                        info!("guest gossip topic={topic} event {event:?}");
+                    }
+                }
             }
         }
     }
+
+    warn!("guest gossip topic {topic} task exiting");
     Ok(())
 }
 
 host_fn!(broadcast_msg(user_data: GuestGossip; msg: OutboundGossipMsg) -> () {
-    execute_broadcast_msg(user_data, msg)
+    // Legacy broadcast, always goes out on the global topic
+    execute_broadcast(user_data, GLOBAL_TOPIC.to_string(), msg.content)
 });
 
-fn execute_broadcast_msg(
+host_fn!(gossip_broadcast_to(user_data: GuestGossip; msg: OutboundGossipMsg) -> () {
+    execute_broadcast(user_data, msg.topic, msg.content)
+});
+
+host_fn!(gossip_subscribe(user_data: GuestGossip; input: Json<GossipTopicInput>) -> bool {
+    let user_data = user_data.get()?;
+    let mut locked = user_data.lock().unwrap();
+    Ok(locked.subscribe(&input.0.topic))
+});
+
+host_fn!(gossip_unsubscribe(user_data: GuestGossip; input: Json<GossipTopicInput>) -> bool {
+    let user_data = user_data.get()?;
+    let mut locked = user_data.lock().unwrap();
+    Ok(locked.unsubscribe(&input.0.topic))
+});
+
+fn execute_broadcast(
     user_data: UserData<GuestGossip>,
-    msg: OutboundGossipMsg,
+    topic: String,
+    content: Value,
 ) -> Result<(), extism::Error> {
     let user_data = user_data.get()?;
     let locked = user_data.lock().unwrap();
-    locked.outbound_tx.try_send(msg)?;
-    Ok(())
+    locked.broadcast(&topic, content)
 }
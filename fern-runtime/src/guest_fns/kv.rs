@@ -1,15 +1,76 @@
-use crate::guest::GuestConfig;
-use extism::{PTR, PluginBuilder, UserData, host_fn};
+use std::{ops::Bound, sync::Arc};
+
+use crate::{guest::GuestConfig, guest_fns::sqlite_improved::EmptyInput, limits::HostCallLimiter};
+use chrono::Utc;
+use extism::{PTR, PluginBuilder, ToBytes, UserData, host_fn};
 use extism_convert::Json;
-use redb::{Database, ReadableDatabase, TableDefinition};
+use log::warn;
+use redb::{
+    AccessGuard, Database, ReadOnlyTable, ReadTransaction, ReadableDatabase, ReadableTable,
+    StorageError, TableDefinition, TableError, TableHandle, WriteTransaction,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+const DEFAULT_KV_PAGE_SIZE: usize = 100;
+// Bounds what a single call can pull into the guest's memory
+const MAX_KV_PAGE_SIZE: usize = 1000;
+const MAX_KV_BATCH_OPS: usize = 1000;
+// Expired keys deleted per sweep, a backlog is worked through over several sweeps
+const MAX_KV_SWEEP: usize = 1000;
+const MAX_KV_WATCHES: usize = 64;
+// Changes waiting for kvChanged, later ones are dropped until the guest catches up
+const MAX_PENDING_KV_CHANGES: usize = 1024;
+
+type KvTable<'a> = TableDefinition<'a, String, &'static [u8]>;
+// (table, key) for the bookkeeping tables below
+type EntryKey = (&'static str, &'static str);
+
+// Guests can't open tables with this prefix, it's used for kv's own bookkeeping
+const RESERVED_TABLE_PREFIX: &str = "__fern";
+// When keys stored with a ttl expire, by (table, key)
+const EXPIRY_TABLE: TableDefinition<EntryKey, i64> = TableDefinition::new("__fern_kv_expiry");
+// The same expiries by (expires at, table, key) so sweeps only visit keys which are due
+const EXPIRY_INDEX: TableDefinition<(i64, &str, &str), ()> =
+    TableDefinition::new("__fern_kv_expiry_index");
+// Content type of values stored with kv_store_bytes, keys without one hold JSON
+const CONTENT_TYPE_TABLE: TableDefinition<EntryKey, &str> =
+    TableDefinition::new("__fern_kv_content_type");
+const JSON_CONTENT_TYPE: &str = "application/json";
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+const MAX_CONTENT_TYPE_LEN: usize = 255;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct KvStoreInput {
     pub table: String,
     pub key: String,
     pub value: Value,
+    /// Delete the key after this many millis, it lives until overwritten when unset
+    #[serde(rename = "ttlMs", default)]
+    pub ttl_ms: Option<u64>,
+}
+
+/// Metadata for `kv_store_bytes`, the value itself is passed as raw bytes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvStoreBytesInput {
+    pub table: String,
+    pub key: String,
+    /// Defaults to `application/octet-stream`
+    #[serde(rename = "contentType")]
+    pub content_type: Option<String>,
+    #[serde(rename = "ttlMs", default)]
+    pub ttl_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct KvEntryInfo {
+    /// `application/json` for values stored with kv_store
+    #[serde(rename = "contentType")]
+    pub content_type: String,
+    pub size: u64,
+    #[serde(rename = "expiresAtMs")]
+    pub expires_at_ms: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -18,15 +79,208 @@ pub struct KvReadInput {
     pub key: String,
 }
 
+/// One write in a `kv_batch`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum KvOp {
+    Put {
+        table: String,
+        key: String,
+        value: Value,
+        #[serde(rename = "ttlMs", default)]
+        ttl_ms: Option<u64>,
+    },
+    Delete {
+        table: String,
+        key: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvBatchInput {
+    pub ops: Vec<KvOp>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvCompareAndSwapInput {
+    pub table: String,
+    pub key: String,
+    /// Value the key must currently hold, null when it must not exist
+    pub expected: Option<Value>,
+    /// Value to store, null deletes the key
+    pub new: Option<Value>,
+    /// Ttl for the new value, see `KvStoreInput`
+    #[serde(rename = "ttlMs", default)]
+    pub ttl_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvTableInput {
+    pub table: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvListKeysInput {
+    pub table: String,
+    #[serde(default)]
+    pub prefix: String,
+    /// `cursor` from the previous page, listing continues after it
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct KvKeysPage {
+    pub keys: Vec<String>,
+    /// Pass back to get the next page, None once every key has been listed
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KvRangeInput {
+    pub table: String,
+    /// Inclusive, unbounded when unset
+    pub start: Option<String>,
+    /// Exclusive, unbounded when unset
+    pub end: Option<String>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KvEntry {
+    pub key: String,
+    /// Null for binary values, which are read with kv_read_bytes
+    pub value: Value,
+    /// Only set for binary values
+    #[serde(rename = "contentType", skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct KvEntries {
+    pub entries: Vec<KvEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct KvTables {
+    pub tables: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct KvWatchInput {
+    pub table: String,
+    /// Only keys starting with this, every key in the table by default
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Delivered to `kvChanged` after a write to a key the guest watches commits
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct KvChange {
+    pub table: String,
+    pub key: String,
+    /// None when the key was created
+    pub old: Option<KvChangeValue>,
+    /// None when the key was deleted or expired
+    pub new: Option<KvChangeValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KvChangeValue {
+    /// Null for binary values, see `KvEntry`
+    pub value: Value,
+    #[serde(rename = "contentType", skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl KvChangeValue {
+    fn new(bytes: &[u8], content_type: Option<&str>) -> Result<Self, extism::Error> {
+        Ok(match content_type {
+            Some(content_type) => Self {
+                value: Value::Null,
+                content_type: Some(content_type.to_string()),
+            },
+            None => Self {
+                value: serde_json::from_slice(bytes)?,
+                content_type: None,
+            },
+        })
+    }
+}
+
 pub struct GuestKvData {
-    pub db: Database,
+    pub db: Arc<Database>,
+    // Like schedules these belong to the running module, see `GuestKvData::sharing`
+    watches: Vec<KvWatchInput>,
+    changes: Vec<KvChange>,
 }
 
 impl GuestKvData {
     pub fn new() -> Self {
         let file = tempfile::NamedTempFile::new().expect("failed to get temp file");
         let db = Database::create(file.path()).expect("failed to create db");
-        Self { db }
+        Self::with_db(db)
+    }
+
+    fn with_db(db: Database) -> Self {
+        Self {
+            db: Arc::new(db),
+            watches: vec![],
+            changes: vec![],
+        }
+    }
+
+    /// Same database as `other` without its watches, for the module replacing the one
+    /// `other` belongs to. redb only lets a database file be opened once
+    pub fn sharing(other: &GuestKvData) -> Self {
+        Self {
+            db: other.db.clone(),
+            watches: vec![],
+            changes: vec![],
+        }
+    }
+
+    pub fn watch(&mut self, watch: KvWatchInput) -> Result<bool, extism::Error> {
+        check_table_name(&watch.table)?;
+        if self.watches.contains(&watch) {
+            return Ok(false);
+        }
+        if self.watches.len() >= MAX_KV_WATCHES {
+            return Err(extism::Error::msg(format!(
+                "too many kv watches (max {MAX_KV_WATCHES})"
+            )));
+        }
+        self.watches.push(watch);
+        Ok(true)
+    }
+
+    pub fn unwatch(&mut self, watch: &KvWatchInput) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|existing| existing != watch);
+        self.watches.len() != before
+    }
+
+    /// Changes waiting to be delivered to `kvChanged`, oldest first
+    pub fn take_changes(&mut self) -> Vec<KvChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Queue what a transaction changed once it has committed
+    fn committed(&mut self, log: ChangeLog) {
+        let room = MAX_PENDING_KV_CHANGES.saturating_sub(self.changes.len());
+        if log.changes.len() > room {
+            warn!(
+                "dropping {} kv changes, kvChanged isn't keeping up",
+                log.changes.len() - room
+            );
+        }
+        self.changes.extend(log.changes.into_iter().take(room));
     }
 
     pub fn new_with_config(config: &GuestConfig) -> Self {
@@ -42,42 +296,184 @@ impl GuestKvData {
             }
 
             let db = Database::create(&full_path).expect("failed to create file-based db");
-            Self { db }
+            Self::with_db(db)
         } else {
             // Fall back to in-memory database
             Self::new()
         }
     }
+
+    /// Delete keys whose ttl has run out, returning how many were deleted. Guests
+    /// stop seeing a key once it expires, this only reclaims the space
+    pub fn sweep_expired(&mut self, now_ms: i64) -> Result<usize, extism::Error> {
+        let mut log = ChangeLog::expiring(self);
+        let tx = self.db.begin_write()?;
+        let due = {
+            let index = tx.open_table(EXPIRY_INDEX)?;
+            let mut due = vec![];
+            for entry in index
+                .range(..(now_ms.saturating_add(1), "", ""))?
+                .take(MAX_KV_SWEEP)
+            {
+                let (expiry, _) = entry?;
+                let (_, table, key) = expiry.value();
+                due.push((table.to_string(), key.to_string()));
+            }
+            due
+        };
+
+        if due.is_empty() {
+            tx.abort()?;
+            return Ok(0);
+        }
+        for (table, key) in &due {
+            remove(&tx, table, key, &mut log)?;
+        }
+        tx.commit()?;
+        self.committed(log);
+        Ok(due.len())
+    }
 }
 
-pub fn attach_guest_kv(builder: PluginBuilder, config: GuestConfig) -> PluginBuilder {
-    let user_data = UserData::new(GuestKvData::new_with_config(&config));
-    builder
-        .with_function("kv_store", [PTR], [PTR], user_data.clone(), kv_store)
-        .with_function("kv_read", [PTR], [PTR], user_data.clone(), kv_read)
+pub fn attach_guest_kv(
+    builder: PluginBuilder,
+    config: GuestConfig,
+    existing_user_data: Option<UserData<GuestKvData>>,
+    limiter: &HostCallLimiter,
+) -> (PluginBuilder, UserData<GuestKvData>) {
+    let data = match existing_user_data.as_ref().map(|ud| ud.get()) {
+        Some(Ok(existing)) => GuestKvData::sharing(&existing.lock().unwrap()),
+        _ => GuestKvData::new_with_config(&config),
+    };
+    let user_data = UserData::new(data);
+    let builder = builder
+        .with_function(
+            "kv_store",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_store", kv_store),
+        )
+        .with_function(
+            "kv_read",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_read", kv_read),
+        )
+        .with_function(
+            "kv_delete",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_delete", kv_delete),
+        )
+        .with_function(
+            "kv_list_keys",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_list_keys", kv_list_keys),
+        )
+        .with_function(
+            "kv_range",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_range", kv_range),
+        )
+        .with_function(
+            "kv_list_tables",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_list_tables", kv_list_tables),
+        )
+        .with_function(
+            "kv_drop_table",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_drop_table", kv_drop_table),
+        )
+        .with_function(
+            "kv_batch",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_batch", kv_batch),
+        )
+        .with_function(
+            "kv_compare_and_swap",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_compare_and_swap", kv_compare_and_swap),
+        )
+        .with_function(
+            "kv_store_bytes",
+            [PTR, PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_store_bytes", kv_store_bytes),
+        )
+        .with_function(
+            "kv_read_bytes",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_read_bytes", kv_read_bytes),
+        )
+        .with_function(
+            "kv_entry_info",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_entry_info", kv_entry_info),
+        )
+        .with_function(
+            "kv_watch",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_watch", kv_watch),
+        )
+        .with_function(
+            "kv_unwatch",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("kv_unwatch", kv_unwatch),
+        );
+
+    (builder, user_data)
 }
 
 host_fn!(kv_store(user_data : GuestKvData; input: Json<KvStoreInput>) -> bool {
-  store(user_data, input.0.table, input.0.key, input.0.value)
+  store(user_data, input.0, Utc::now().timestamp_millis())
 });
 
 fn store(
     user_data: UserData<GuestKvData>,
-    table: String,
-    key: String,
-    value: Value,
+    input: KvStoreInput,
+    now_ms: i64,
 ) -> Result<bool, extism::Error> {
     let data = user_data.get()?;
-    let data = data.lock().unwrap();
+    let mut data = data.lock().unwrap();
 
+    let mut log = ChangeLog::new(&data, now_ms);
     let tx = data.db.begin_write()?;
-    {
-        let mut table: redb::Table<'_, String, &[u8]> =
-            tx.open_table(TableDefinition::new(&table))?;
-        let bytes = serde_json::to_vec(&value)?;
-        table.insert(key, bytes.as_slice())?;
-    }
+    let expires_at_ms = expires_at(now_ms, input.ttl_ms);
+    put(
+        &tx,
+        &input.table,
+        &input.key,
+        &input.value,
+        expires_at_ms,
+        &mut log,
+    )?;
     tx.commit()?;
+    data.committed(log);
     Ok(true)
 }
 
@@ -85,14 +481,27 @@ fn read(
     user_data: UserData<GuestKvData>,
     table: String,
     key: String,
+    now_ms: i64,
 ) -> Result<Option<Value>, extism::Error> {
     let data = user_data.get()?;
     let data = data.lock().unwrap();
 
     let tx = data.db.begin_read()?;
 
-    let table: redb::ReadOnlyTable<String, &[u8]> = tx.open_table(TableDefinition::new(&table))?;
-    let res = match table.get(key)? {
+    // Tables only exist once something is stored in them
+    let Some(kv) = open_read_table(&tx, KvTable::new(&table))? else {
+        return Ok(None);
+    };
+    // Expired keys are left for the sweeper, they just read as absent
+    let expiry = open_read_table(&tx, EXPIRY_TABLE)?;
+    if is_expired(expiry.as_ref(), &table, &key, now_ms)? {
+        return Ok(None);
+    }
+    let content_types = open_read_table(&tx, CONTENT_TYPE_TABLE)?;
+    if content_type(content_types.as_ref(), &table, &key)?.is_some() {
+        return Err(binary_value_error(&key));
+    }
+    let res = match kv.get(key)? {
         Some(res) => {
             let res: Value = serde_json::from_slice(res.value())?;
             Some(res)
@@ -104,5 +513,1098 @@ fn read(
 }
 
 host_fn!(kv_read(user_data : GuestKvData; input: Json<KvReadInput>) -> Option<Value> {
-  read(user_data, input.0.table, input.0.key)
+  read(user_data, input.0.table, input.0.key, Utc::now().timestamp_millis())
+});
+
+host_fn!(kv_store_bytes(user_data : GuestKvData; input: Json<KvStoreBytesInput>, value: Vec<u8>) -> bool {
+  store_bytes(user_data, input.0, value, Utc::now().timestamp_millis())
 });
+
+host_fn!(kv_read_bytes(user_data : GuestKvData; input: Json<KvReadInput>) -> Option<Vec<u8>> {
+  read_bytes(user_data, input.0.table, input.0.key, Utc::now().timestamp_millis())
+});
+
+host_fn!(kv_entry_info(user_data : GuestKvData; input: Json<KvReadInput>) -> Option<KvEntryInfo> {
+  entry_info(user_data, input.0.table, input.0.key, Utc::now().timestamp_millis())
+});
+
+host_fn!(kv_watch(user_data : GuestKvData; input: Json<KvWatchInput>) -> bool {
+  let data = user_data.get()?;
+  let mut data = data.lock().unwrap();
+  data.watch(input.0)
+});
+
+host_fn!(kv_unwatch(user_data : GuestKvData; input: Json<KvWatchInput>) -> bool {
+  let data = user_data.get()?;
+  let mut data = data.lock().unwrap();
+  Ok(data.unwatch(&input.0))
+});
+
+host_fn!(kv_delete(user_data : GuestKvData; input: Json<KvReadInput>) -> bool {
+  delete(user_data, input.0.table, input.0.key, Utc::now().timestamp_millis())
+});
+
+host_fn!(kv_list_keys(user_data : GuestKvData; input: Json<KvListKeysInput>) -> KvKeysPage {
+  list_keys(user_data, input.0, Utc::now().timestamp_millis())
+});
+
+host_fn!(kv_range(user_data : GuestKvData; input: Json<KvRangeInput>) -> KvEntries {
+  range(user_data, input.0, Utc::now().timestamp_millis())
+});
+
+host_fn!(kv_list_tables(user_data : GuestKvData; _input: Json<EmptyInput>) -> KvTables {
+  list_tables(user_data)
+});
+
+host_fn!(kv_drop_table(user_data : GuestKvData; input: Json<KvTableInput>) -> bool {
+  drop_table(user_data, input.0.table, Utc::now().timestamp_millis())
+});
+
+host_fn!(kv_batch(user_data : GuestKvData; input: Json<KvBatchInput>) -> bool {
+  batch(user_data, input.0.ops, Utc::now().timestamp_millis())
+});
+
+host_fn!(kv_compare_and_swap(user_data : GuestKvData; input: Json<KvCompareAndSwapInput>) -> bool {
+  compare_and_swap(user_data, input.0, Utc::now().timestamp_millis())
+});
+
+fn open_read_table<K: redb::Key + 'static, V: redb::Value + 'static>(
+    tx: &ReadTransaction,
+    definition: TableDefinition<K, V>,
+) -> Result<Option<ReadOnlyTable<K, V>>, extism::Error> {
+    match tx.open_table(definition) {
+        Ok(table) => Ok(Some(table)),
+        Err(TableError::TableDoesNotExist(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn store_bytes(
+    user_data: UserData<GuestKvData>,
+    input: KvStoreBytesInput,
+    value: Vec<u8>,
+    now_ms: i64,
+) -> Result<bool, extism::Error> {
+    let content_type = input
+        .content_type
+        .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string());
+    if content_type.is_empty() || content_type.len() > MAX_CONTENT_TYPE_LEN {
+        return Err(extism::Error::msg(format!(
+            "content type must be 1 to {MAX_CONTENT_TYPE_LEN} bytes"
+        )));
+    }
+
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+
+    let mut log = ChangeLog::new(&data, now_ms);
+    let tx = data.db.begin_write()?;
+    let expires_at_ms = expires_at(now_ms, input.ttl_ms);
+    write_entry(
+        &tx,
+        &input.table,
+        &input.key,
+        &value,
+        Some(&content_type),
+        expires_at_ms,
+        &mut log,
+    )?;
+    tx.commit()?;
+    data.committed(log);
+    Ok(true)
+}
+
+/// Raw bytes of any value, values stored with kv_store come back as their JSON
+fn read_bytes(
+    user_data: UserData<GuestKvData>,
+    table: String,
+    key: String,
+    now_ms: i64,
+) -> Result<Option<Vec<u8>>, extism::Error> {
+    let data = user_data.get()?;
+    let data = data.lock().unwrap();
+
+    let tx = data.db.begin_read()?;
+    let Some(kv) = open_read_table(&tx, KvTable::new(&table))? else {
+        return Ok(None);
+    };
+    let expiry = open_read_table(&tx, EXPIRY_TABLE)?;
+    if is_expired(expiry.as_ref(), &table, &key, now_ms)? {
+        return Ok(None);
+    }
+    Ok(kv.get(key)?.map(|value| value.value().to_vec()))
+}
+
+fn entry_info(
+    user_data: UserData<GuestKvData>,
+    table: String,
+    key: String,
+    now_ms: i64,
+) -> Result<Option<KvEntryInfo>, extism::Error> {
+    let data = user_data.get()?;
+    let data = data.lock().unwrap();
+
+    let tx = data.db.begin_read()?;
+    let Some(kv) = open_read_table(&tx, KvTable::new(&table))? else {
+        return Ok(None);
+    };
+    let expiry = open_read_table(&tx, EXPIRY_TABLE)?;
+    if is_expired(expiry.as_ref(), &table, &key, now_ms)? {
+        return Ok(None);
+    }
+    let Some(value) = kv.get(key.clone())? else {
+        return Ok(None);
+    };
+
+    let content_types = open_read_table(&tx, CONTENT_TYPE_TABLE)?;
+    let expires_at_ms = match &expiry {
+        Some(expiry) => expiry
+            .get((table.as_str(), key.as_str()))?
+            .map(|expires_at_ms| expires_at_ms.value()),
+        None => None,
+    };
+    Ok(Some(KvEntryInfo {
+        content_type: content_type(content_types.as_ref(), &table, &key)?
+            .unwrap_or_else(|| JSON_CONTENT_TYPE.to_string()),
+        size: value.value().len() as u64,
+        expires_at_ms,
+    }))
+}
+
+/// Changes to watched keys made in a transaction, only queued once it commits
+struct ChangeLog {
+    watches: Vec<KvWatchInput>,
+    changes: Vec<KvChange>,
+    /// Old values which expired by then are reported as missing, the same as reads
+    /// see them. None when it's the expired keys being deleted
+    now_ms: Option<i64>,
+}
+
+impl ChangeLog {
+    fn new(data: &GuestKvData, now_ms: i64) -> Self {
+        Self {
+            watches: data.watches.clone(),
+            changes: vec![],
+            now_ms: Some(now_ms),
+        }
+    }
+
+    /// For the sweeper, watchers see expired keys deleted with their last value
+    fn expiring(data: &GuestKvData) -> Self {
+        Self {
+            watches: data.watches.clone(),
+            changes: vec![],
+            now_ms: None,
+        }
+    }
+
+    fn is_watched(&self, table: &str, key: &str) -> bool {
+        self.watches
+            .iter()
+            .any(|watch| watch.table == table && key.starts_with(&watch.prefix))
+    }
+
+    fn record(
+        &mut self,
+        table: &str,
+        key: &str,
+        old: Option<KvChangeValue>,
+        new: Option<KvChangeValue>,
+    ) {
+        self.changes.push(KvChange {
+            table: table.to_string(),
+            key: key.to_string(),
+            old,
+            new,
+        });
+    }
+}
+
+/// Value of a key as a watcher sees it, expired keys only have one without `now_ms`
+fn stored_value(
+    tx: &WriteTransaction,
+    table: &str,
+    key: &str,
+    now_ms: Option<i64>,
+) -> Result<Option<KvChangeValue>, extism::Error> {
+    if let Some(now_ms) = now_ms {
+        let expiry = tx.open_table(EXPIRY_TABLE)?;
+        if is_expired(Some(&expiry), table, key, now_ms)? {
+            return Ok(None);
+        }
+    }
+    let kv = tx.open_table(KvTable::new(table))?;
+    let Some(bytes) = kv.get(key.to_string())? else {
+        return Ok(None);
+    };
+    let content_types = tx.open_table(CONTENT_TYPE_TABLE)?;
+    let content_type = content_type(Some(&content_types), table, key)?;
+    Ok(Some(KvChangeValue::new(
+        bytes.value(),
+        content_type.as_deref(),
+    )?))
+}
+
+fn check_table_name(table: &str) -> Result<(), extism::Error> {
+    if table.starts_with(RESERVED_TABLE_PREFIX) {
+        return Err(extism::Error::msg(format!(
+            "kv table names can't start with {RESERVED_TABLE_PREFIX}"
+        )));
+    }
+    Ok(())
+}
+
+fn expires_at(now_ms: i64, ttl_ms: Option<u64>) -> Option<i64> {
+    ttl_ms.map(|ttl_ms| now_ms.saturating_add(i64::try_from(ttl_ms).unwrap_or(i64::MAX)))
+}
+
+fn is_expired(
+    expiry: Option<&impl ReadableTable<EntryKey, i64>>,
+    table: &str,
+    key: &str,
+    now_ms: i64,
+) -> Result<bool, extism::Error> {
+    let Some(expiry) = expiry else {
+        return Ok(false);
+    };
+    Ok(expiry
+        .get((table, key))?
+        .is_some_and(|expires_at_ms| expires_at_ms.value() <= now_ms))
+}
+
+/// Content type of a binary value, None for JSON
+fn content_type(
+    content_types: Option<&impl ReadableTable<EntryKey, &'static str>>,
+    table: &str,
+    key: &str,
+) -> Result<Option<String>, extism::Error> {
+    let Some(content_types) = content_types else {
+        return Ok(None);
+    };
+    Ok(content_types
+        .get((table, key))?
+        .map(|content_type| content_type.value().to_string()))
+}
+
+fn binary_value_error(key: &str) -> extism::Error {
+    extism::Error::msg(format!(
+        "key {key} holds binary data, read it with kv_read_bytes"
+    ))
+}
+
+/// Store a JSON value, replacing any ttl the key had
+fn put(
+    tx: &WriteTransaction,
+    table: &str,
+    key: &str,
+    value: &Value,
+    expires_at_ms: Option<i64>,
+    log: &mut ChangeLog,
+) -> Result<(), extism::Error> {
+    let bytes = serde_json::to_vec(value)?;
+    write_entry(tx, table, key, &bytes, None, expires_at_ms, log)
+}
+
+/// Store a value along with its bookkeeping, `content_type` is None for JSON
+fn write_entry(
+    tx: &WriteTransaction,
+    table: &str,
+    key: &str,
+    bytes: &[u8],
+    content_type: Option<&str>,
+    expires_at_ms: Option<i64>,
+    log: &mut ChangeLog,
+) -> Result<(), extism::Error> {
+    check_table_name(table)?;
+    let watched = log.is_watched(table, key);
+    let old = if watched {
+        stored_value(tx, table, key, log.now_ms)?
+    } else {
+        None
+    };
+
+    {
+        let mut kv = tx.open_table(KvTable::new(table))?;
+        kv.insert(key.to_string(), bytes)?;
+    }
+    set_content_type(tx, table, key, content_type)?;
+    set_expiry(tx, table, key, expires_at_ms)?;
+
+    if watched {
+        let new = KvChangeValue::new(bytes, content_type)?;
+        log.record(table, key, old, Some(new));
+    }
+    Ok(())
+}
+
+/// Returns true if the key was stored, whether or not it had expired
+fn remove(
+    tx: &WriteTransaction,
+    table: &str,
+    key: &str,
+    log: &mut ChangeLog,
+) -> Result<bool, extism::Error> {
+    check_table_name(table)?;
+    let old = if log.is_watched(table, key) {
+        stored_value(tx, table, key, log.now_ms)?
+    } else {
+        None
+    };
+
+    let removed = {
+        let mut kv = tx.open_table(KvTable::new(table))?;
+        kv.remove(key.to_string())?.is_some()
+    };
+    set_content_type(tx, table, key, None)?;
+    set_expiry(tx, table, key, None)?;
+
+    if old.is_some() {
+        log.record(table, key, old, None);
+    }
+    Ok(removed)
+}
+
+fn set_content_type(
+    tx: &WriteTransaction,
+    table: &str,
+    key: &str,
+    content_type: Option<&str>,
+) -> Result<(), extism::Error> {
+    let mut content_types = tx.open_table(CONTENT_TYPE_TABLE)?;
+    match content_type {
+        Some(content_type) => {
+            content_types.insert((table, key), content_type)?;
+        }
+        None => {
+            content_types.remove((table, key))?;
+        }
+    }
+    Ok(())
+}
+
+fn set_expiry(
+    tx: &WriteTransaction,
+    table: &str,
+    key: &str,
+    expires_at_ms: Option<i64>,
+) -> Result<(), extism::Error> {
+    let mut expiry = tx.open_table(EXPIRY_TABLE)?;
+    let mut index = tx.open_table(EXPIRY_INDEX)?;
+
+    let previous = expiry
+        .remove((table, key))?
+        .map(|previous| previous.value());
+    if let Some(previous) = previous {
+        index.remove((previous, table, key))?;
+    }
+    if let Some(expires_at_ms) = expires_at_ms {
+        expiry.insert((table, key), expires_at_ms)?;
+        index.insert((expires_at_ms, table, key), ())?;
+    }
+    Ok(())
+}
+
+fn page_size(limit: Option<usize>) -> usize {
+    limit
+        .unwrap_or(DEFAULT_KV_PAGE_SIZE)
+        .clamp(1, MAX_KV_PAGE_SIZE)
+}
+
+/// Returns true if the key existed
+fn delete(
+    user_data: UserData<GuestKvData>,
+    table: String,
+    key: String,
+    now_ms: i64,
+) -> Result<bool, extism::Error> {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+
+    let mut log = ChangeLog::new(&data, now_ms);
+    let tx = data.db.begin_write()?;
+    let expired = {
+        let expiry = tx.open_table(EXPIRY_TABLE)?;
+        is_expired(Some(&expiry), &table, &key, now_ms)?
+    };
+    let removed = remove(&tx, &table, &key, &mut log)?;
+    // Opening the table for writing creates it, don't leave an empty one behind
+    if removed {
+        tx.commit()?;
+        data.committed(log);
+    } else {
+        tx.abort()?;
+    }
+    Ok(removed && !expired)
+}
+
+/// Apply every op in one transaction, if any of them fails none are applied
+fn batch(
+    user_data: UserData<GuestKvData>,
+    ops: Vec<KvOp>,
+    now_ms: i64,
+) -> Result<bool, extism::Error> {
+    if ops.len() > MAX_KV_BATCH_OPS {
+        return Err(extism::Error::msg(format!(
+            "too many ops in batch (max {MAX_KV_BATCH_OPS})"
+        )));
+    }
+
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+
+    // Dropping the transaction on an error aborts it
+    let mut log = ChangeLog::new(&data, now_ms);
+    let tx = data.db.begin_write()?;
+    for op in ops {
+        match op {
+            KvOp::Put {
+                table,
+                key,
+                value,
+                ttl_ms,
+            } => put(
+                &tx,
+                &table,
+                &key,
+                &value,
+                expires_at(now_ms, ttl_ms),
+                &mut log,
+            )?,
+            KvOp::Delete { table, key } => {
+                remove(&tx, &table, &key, &mut log)?;
+            }
+        }
+    }
+    tx.commit()?;
+    data.committed(log);
+    Ok(true)
+}
+
+/// Returns true if the key held `expected` and was swapped
+fn compare_and_swap(
+    user_data: UserData<GuestKvData>,
+    input: KvCompareAndSwapInput,
+    now_ms: i64,
+) -> Result<bool, extism::Error> {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+
+    // redb allows a single writer, nothing can change the key between the read and the write
+    let mut log = ChangeLog::new(&data, now_ms);
+    let tx = data.db.begin_write()?;
+    let current: Option<Value> = {
+        let kv = tx.open_table(KvTable::new(&input.table))?;
+        let expiry = tx.open_table(EXPIRY_TABLE)?;
+        let content_types = tx.open_table(CONTENT_TYPE_TABLE)?;
+        let expired = is_expired(Some(&expiry), &input.table, &input.key, now_ms)?;
+        match kv.get(input.key.clone())? {
+            Some(_) if expired => None,
+            Some(_) if content_type(Some(&content_types), &input.table, &input.key)?.is_some() => {
+                return Err(binary_value_error(&input.key));
+            }
+            Some(current) => Some(serde_json::from_slice(current.value())?),
+            None => None,
+        }
+    };
+
+    if current != input.expected {
+        tx.abort()?;
+        return Ok(false);
+    }
+    match &input.new {
+        Some(new) => put(
+            &tx,
+            &input.table,
+            &input.key,
+            new,
+            expires_at(now_ms, input.ttl_ms),
+            &mut log,
+        )?,
+        None => {
+            remove(&tx, &input.table, &input.key, &mut log)?;
+        }
+    }
+    tx.commit()?;
+    data.committed(log);
+    Ok(true)
+}
+
+fn list_keys(
+    user_data: UserData<GuestKvData>,
+    input: KvListKeysInput,
+    now_ms: i64,
+) -> Result<KvKeysPage, extism::Error> {
+    let data = user_data.get()?;
+    let data = data.lock().unwrap();
+
+    let tx = data.db.begin_read()?;
+    let Some(table) = open_read_table(&tx, KvTable::new(&input.table))? else {
+        return Ok(KvKeysPage {
+            keys: vec![],
+            cursor: None,
+        });
+    };
+    let expiry = open_read_table(&tx, EXPIRY_TABLE)?;
+
+    // Keys sort bytewise, so every key with the prefix follows the prefix itself
+    let start = match input.cursor {
+        Some(cursor) if cursor >= input.prefix => Bound::Excluded(cursor),
+        _ => Bound::Included(input.prefix.clone()),
+    };
+    let limit = page_size(input.limit);
+
+    let mut keys = vec![];
+    let mut more = false;
+    for entry in table.range::<String>((start, Bound::Unbounded))? {
+        let (key, _) = entry?;
+        let key = key.value();
+        if !key.starts_with(&input.prefix) {
+            break;
+        }
+        if is_expired(expiry.as_ref(), &input.table, &key, now_ms)? {
+            continue;
+        }
+        if keys.len() == limit {
+            more = true;
+            break;
+        }
+        keys.push(key);
+    }
+
+    let cursor = more.then(|| keys.last().cloned()).flatten();
+    Ok(KvKeysPage { keys, cursor })
+}
+
+fn range(
+    user_data: UserData<GuestKvData>,
+    input: KvRangeInput,
+    now_ms: i64,
+) -> Result<KvEntries, extism::Error> {
+    let data = user_data.get()?;
+    let data = data.lock().unwrap();
+
+    let tx = data.db.begin_read()?;
+    let Some(table) = open_read_table(&tx, KvTable::new(&input.table))? else {
+        return Ok(KvEntries { entries: vec![] });
+    };
+    let expiry = open_read_table(&tx, EXPIRY_TABLE)?;
+    let content_types = open_read_table(&tx, CONTENT_TYPE_TABLE)?;
+
+    let start = input.start.map_or(Bound::Unbounded, Bound::Included);
+    let end = input.end.map_or(Bound::Unbounded, Bound::Excluded);
+    // redb panics on a range which ends before it starts
+    if let (Bound::Included(start), Bound::Excluded(end)) = (&start, &end)
+        && start >= end
+    {
+        return Ok(KvEntries { entries: vec![] });
+    }
+
+    let range = table.range::<String>((start, end))?;
+    let limit = page_size(input.limit);
+    // Expired keys are skipped rather than counted towards the limit
+    let live_entry = |key: String, value: &[u8]| -> Result<Option<KvEntry>, extism::Error> {
+        if is_expired(expiry.as_ref(), &input.table, &key, now_ms)? {
+            return Ok(None);
+        }
+        let entry = match content_type(content_types.as_ref(), &input.table, &key)? {
+            Some(content_type) => KvEntry {
+                key,
+                value: Value::Null,
+                content_type: Some(content_type),
+            },
+            None => KvEntry {
+                key,
+                value: serde_json::from_slice(value)?,
+                content_type: None,
+            },
+        };
+        Ok(Some(entry))
+    };
+    let entries = if input.reverse {
+        collect_entries(range.rev(), limit, live_entry)?
+    } else {
+        collect_entries(range, limit, live_entry)?
+    };
+    Ok(KvEntries { entries })
+}
+
+/// Take up to `limit` of the entries `decode` keeps
+fn collect_entries<'a>(
+    entries: impl Iterator<
+        Item = Result<(AccessGuard<'a, String>, AccessGuard<'a, &'static [u8]>), StorageError>,
+    >,
+    limit: usize,
+    decode: impl Fn(String, &[u8]) -> Result<Option<KvEntry>, extism::Error>,
+) -> Result<Vec<KvEntry>, extism::Error> {
+    let mut res = vec![];
+    for entry in entries {
+        if res.len() == limit {
+            break;
+        }
+        let (key, value) = entry?;
+        if let Some(entry) = decode(key.value(), value.value())? {
+            res.push(entry);
+        }
+    }
+    Ok(res)
+}
+
+fn list_tables(user_data: UserData<GuestKvData>) -> Result<KvTables, extism::Error> {
+    let data = user_data.get()?;
+    let data = data.lock().unwrap();
+
+    let tx = data.db.begin_read()?;
+    let tables = tx
+        .list_tables()?
+        .map(|table| table.name().to_string())
+        .filter(|name| !name.starts_with(RESERVED_TABLE_PREFIX))
+        .collect();
+    Ok(KvTables { tables })
+}
+
+/// Returns true if the table existed
+fn drop_table(
+    user_data: UserData<GuestKvData>,
+    table: String,
+    now_ms: i64,
+) -> Result<bool, extism::Error> {
+    check_table_name(&table)?;
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+
+    let mut log = ChangeLog::new(&data, now_ms);
+    let tx = data.db.begin_write()?;
+
+    // Watchers see each of their keys deleted
+    let exists = tx.list_tables()?.any(|handle| handle.name() == table);
+    if exists && log.watches.iter().any(|watch| watch.table == table) {
+        let watched = {
+            let kv = tx.open_table(KvTable::new(&table))?;
+            let mut watched = vec![];
+            for entry in kv.iter()? {
+                let (key, _) = entry?;
+                let key = key.value();
+                if log.is_watched(&table, &key) {
+                    watched.push(key);
+                }
+            }
+            watched
+        };
+        for key in &watched {
+            remove(&tx, &table, key, &mut log)?;
+        }
+    }
+
+    let dropped = tx.delete_table(KvTable::new(&table))?;
+
+    // Bookkeeping for the table's keys goes with it
+    let expiring = table_keys(&tx.open_table(EXPIRY_TABLE)?, &table)?;
+    for key in &expiring {
+        set_expiry(&tx, &table, key, None)?;
+    }
+    let binary = table_keys(&tx.open_table(CONTENT_TYPE_TABLE)?, &table)?;
+    for key in &binary {
+        set_content_type(&tx, &table, key, None)?;
+    }
+
+    tx.commit()?;
+    data.committed(log);
+    Ok(dropped)
+}
+
+/// Keys of `table` with an entry in one of the bookkeeping tables
+fn table_keys<V: redb::Value + 'static>(
+    meta: &impl ReadableTable<EntryKey, V>,
+    table: &str,
+) -> Result<Vec<String>, extism::Error> {
+    let mut keys = vec![];
+    for entry in meta.range((table, "")..)? {
+        let (entry_key, _) = entry?;
+        let (entry_table, key) = entry_key.value();
+        if entry_table != table {
+            break;
+        }
+        keys.push(key.to_string());
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_000;
+
+    fn store_all(user_data: &UserData<GuestKvData>, table: &str, keys: &[&str]) {
+        for key in keys {
+            store(
+                user_data.clone(),
+                KvStoreInput {
+                    table: table.to_string(),
+                    key: key.to_string(),
+                    value: Value::from(*key),
+                    ttl_ms: None,
+                },
+                NOW,
+            )
+            .expect("failed to store");
+        }
+    }
+
+    fn list(user_data: &UserData<GuestKvData>, prefix: &str, cursor: Option<String>) -> KvKeysPage {
+        list_keys(
+            user_data.clone(),
+            KvListKeysInput {
+                table: "users".to_string(),
+                prefix: prefix.to_string(),
+                cursor,
+                limit: Some(2),
+            },
+            NOW,
+        )
+        .expect("failed to list keys")
+    }
+
+    #[test]
+    fn kv_scans() {
+        let user_data = UserData::new(GuestKvData::new());
+        let kv = || user_data.clone();
+
+        // Missing tables read as empty rather than erroring
+        assert!(
+            read(kv(), "users".to_string(), "a".to_string(), NOW)
+                .unwrap()
+                .is_none()
+        );
+        assert!(!delete(kv(), "users".to_string(), "a".to_string(), NOW).unwrap());
+        assert!(list_tables(kv()).unwrap().tables.is_empty());
+
+        store_all(
+            &user_data,
+            "users",
+            &["user:1", "user:2", "user:3", "group:1"],
+        );
+        store_all(&user_data, "groups", &["a"]);
+
+        let page = list(&user_data, "user:", None);
+        assert_eq!(page.keys, vec!["user:1", "user:2"]);
+        let page = list(&user_data, "user:", page.cursor);
+        assert_eq!(page.keys, vec!["user:3"]);
+        assert_eq!(page.cursor, None);
+
+        let entries = range(
+            kv(),
+            KvRangeInput {
+                table: "users".to_string(),
+                start: Some("user:".to_string()),
+                end: Some("user:3".to_string()),
+                limit: None,
+                reverse: true,
+            },
+            NOW,
+        )
+        .unwrap()
+        .entries;
+        let keys: Vec<_> = entries.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, vec!["user:2", "user:1"]);
+        assert_eq!(entries[0].value, Value::from("user:2"));
+
+        assert!(delete(kv(), "users".to_string(), "user:2".to_string(), NOW).unwrap());
+        assert_eq!(
+            list(&user_data, "user:", None).keys,
+            vec!["user:1", "user:3"]
+        );
+
+        assert_eq!(list_tables(kv()).unwrap().tables, vec!["groups", "users"]);
+        assert!(drop_table(kv(), "groups".to_string(), NOW).unwrap());
+        assert!(!drop_table(kv(), "groups".to_string(), NOW).unwrap());
+        assert!(
+            read(kv(), "groups".to_string(), "a".to_string(), NOW)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn kv_batches_and_swaps() {
+        let user_data = UserData::new(GuestKvData::new());
+        let kv = || user_data.clone();
+        let get = |key: &str| read(kv(), "billing".to_string(), key.to_string(), NOW).unwrap();
+        let swap = |expected: Option<Value>, new: Option<Value>| {
+            compare_and_swap(
+                kv(),
+                KvCompareAndSwapInput {
+                    table: "billing".to_string(),
+                    key: "counter".to_string(),
+                    expected,
+                    new,
+                    ttl_ms: None,
+                },
+                NOW,
+            )
+            .unwrap()
+        };
+
+        let ops: Vec<KvOp> = serde_json::from_str(
+            r#"[
+                {"op": "put", "table": "billing", "key": "a", "value": 1},
+                {"op": "put", "table": "billing", "key": "b", "value": 2},
+                {"op": "delete", "table": "billing", "key": "a"}
+            ]"#,
+        )
+        .unwrap();
+        assert!(batch(kv(), ops, NOW).unwrap());
+        assert_eq!(get("a"), None);
+        assert_eq!(get("b"), Some(Value::from(2)));
+
+        // A table kv can't open makes the second op fail, rolling back the first
+        {
+            let data = user_data.get().unwrap();
+            let data = data.lock().unwrap();
+            let tx = data.db.begin_write().unwrap();
+            tx.open_table(TableDefinition::<u64, u64>::new("typed"))
+                .unwrap();
+            tx.commit().unwrap();
+        }
+        let put = |table: &str| KvOp::Put {
+            table: table.to_string(),
+            key: "c".to_string(),
+            value: Value::from(3),
+            ttl_ms: None,
+        };
+        assert!(batch(kv(), vec![put("billing"), put("typed")], NOW).is_err());
+        assert_eq!(get("c"), None);
+
+        assert!(swap(None, Some(Value::from(1))));
+        assert!(!swap(None, Some(Value::from(1))));
+        assert!(!swap(Some(Value::from(5)), Some(Value::from(6))));
+        assert!(swap(Some(Value::from(1)), Some(Value::from(2))));
+        assert_eq!(get("counter"), Some(Value::from(2)));
+        assert!(swap(Some(Value::from(2)), None));
+        assert_eq!(get("counter"), None);
+    }
+
+    #[test]
+    fn kv_keys_expire() {
+        let user_data = UserData::new(GuestKvData::new());
+        let kv = || user_data.clone();
+        let set = |key: &str, ttl_ms: Option<u64>| {
+            store(
+                kv(),
+                KvStoreInput {
+                    table: "sessions".to_string(),
+                    key: key.to_string(),
+                    value: Value::from(key),
+                    ttl_ms,
+                },
+                NOW,
+            )
+            .unwrap();
+        };
+        let get = |key: &str, now_ms: i64| {
+            read(kv(), "sessions".to_string(), key.to_string(), now_ms).unwrap()
+        };
+        let keys = |now_ms: i64| {
+            list_keys(
+                kv(),
+                KvListKeysInput {
+                    table: "sessions".to_string(),
+                    prefix: String::new(),
+                    cursor: None,
+                    limit: None,
+                },
+                now_ms,
+            )
+            .unwrap()
+            .keys
+        };
+
+        set("a", Some(100));
+        set("b", Some(500));
+        set("c", None);
+        // Storing again without a ttl keeps the key for good
+        set("d", Some(100));
+        set("d", None);
+
+        assert_eq!(get("a", NOW + 99), Some(Value::from("a")));
+        assert_eq!(get("a", NOW + 100), None);
+        assert_eq!(keys(NOW + 100), vec!["b", "c", "d"]);
+        assert!(!delete(kv(), "sessions".to_string(), "a".to_string(), NOW + 100).unwrap());
+
+        // Sweeping only reclaims the space, reads at that time already miss
+        let data = user_data.get().unwrap();
+        assert_eq!(data.lock().unwrap().sweep_expired(NOW + 1_000).unwrap(), 1);
+        assert_eq!(data.lock().unwrap().sweep_expired(NOW + 1_000).unwrap(), 0);
+        assert_eq!(keys(NOW), vec!["c", "d"]);
+
+        assert!(
+            store(
+                kv(),
+                KvStoreInput {
+                    table: EXPIRY_TABLE.name().to_string(),
+                    key: "a".to_string(),
+                    value: Value::Null,
+                    ttl_ms: None,
+                },
+                NOW,
+            )
+            .is_err()
+        );
+        assert_eq!(list_tables(kv()).unwrap().tables, vec!["sessions"]);
+    }
+
+    #[test]
+    fn kv_binary_values() {
+        let user_data = UserData::new(GuestKvData::new());
+        let kv = || user_data.clone();
+        let info = |key: &str| entry_info(kv(), "cache".to_string(), key.to_string(), NOW).unwrap();
+
+        store_bytes(
+            kv(),
+            KvStoreBytesInput {
+                table: "cache".to_string(),
+                key: "img".to_string(),
+                content_type: Some("image/png".to_string()),
+                ttl_ms: Some(60_000),
+            },
+            vec![0x89, b'P', b'N', b'G', 0xff],
+            NOW,
+        )
+        .unwrap();
+        store_all(&user_data, "cache", &["json"]);
+
+        assert_eq!(
+            read_bytes(kv(), "cache".to_string(), "img".to_string(), NOW).unwrap(),
+            Some(vec![0x89, b'P', b'N', b'G', 0xff])
+        );
+        let img = info("img").unwrap();
+        assert_eq!(img.content_type, "image/png");
+        assert_eq!(img.size, 5);
+        assert_eq!(img.expires_at_ms, Some(NOW + 60_000));
+
+        // JSON values read back as their encoding
+        assert_eq!(
+            read_bytes(kv(), "cache".to_string(), "json".to_string(), NOW).unwrap(),
+            Some(br#""json""#.to_vec())
+        );
+        assert_eq!(info("json").unwrap().content_type, JSON_CONTENT_TYPE);
+        assert!(info("missing").is_none());
+
+        // Binary values can't be read as JSON, scans list them without a value
+        assert!(read(kv(), "cache".to_string(), "img".to_string(), NOW).is_err());
+        let entries = range(
+            kv(),
+            KvRangeInput {
+                table: "cache".to_string(),
+                start: None,
+                end: None,
+                limit: None,
+                reverse: false,
+            },
+            NOW,
+        )
+        .unwrap()
+        .entries;
+        assert_eq!(entries[0].content_type.as_deref(), Some("image/png"));
+        assert_eq!(entries[0].value, Value::Null);
+        assert_eq!(entries[1].value, Value::from("json"));
+
+        // Storing JSON over a binary value drops its content type
+        store_all(&user_data, "cache", &["img"]);
+        assert_eq!(info("img").unwrap().content_type, JSON_CONTENT_TYPE);
+    }
+
+    #[test]
+    fn kv_watches_see_committed_changes() {
+        let user_data = UserData::new(GuestKvData::new());
+        let kv = || user_data.clone();
+        let data = user_data.get().unwrap();
+        let changes = || {
+            data.lock()
+                .unwrap()
+                .take_changes()
+                .into_iter()
+                .map(|change| {
+                    let value = |value: Option<KvChangeValue>| value.map(|value| value.value);
+                    (change.key, value(change.old), value(change.new))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let watch = KvWatchInput {
+            table: "views".to_string(),
+            prefix: "user:".to_string(),
+        };
+        assert!(data.lock().unwrap().watch(watch.clone()).unwrap());
+        assert!(!data.lock().unwrap().watch(watch.clone()).unwrap());
+
+        store_all(&user_data, "views", &["user:1", "other"]);
+        store_all(&user_data, "users", &["user:2"]);
+        store_all(&user_data, "views", &["user:1"]);
+        assert_eq!(
+            changes(),
+            vec![
+                ("user:1".to_string(), None, Some(Value::from("user:1"))),
+                (
+                    "user:1".to_string(),
+                    Some(Value::from("user:1")),
+                    Some(Value::from("user:1"))
+                ),
+            ]
+        );
+
+        // Nothing is queued for a batch that rolls back
+        let ops: Vec<KvOp> = serde_json::from_str(
+            r#"[
+                {"op": "put", "table": "views", "key": "user:3", "value": 3},
+                {"op": "put", "table": "__fern", "key": "a", "value": 1}
+            ]"#,
+        )
+        .unwrap();
+        assert!(batch(kv(), ops, NOW).is_err());
+        assert!(changes().is_empty());
+
+        assert!(delete(kv(), "views".to_string(), "user:1".to_string(), NOW).unwrap());
+        assert_eq!(
+            changes(),
+            vec![("user:1".to_string(), Some(Value::from("user:1")), None)]
+        );
+
+        // An expired key was already gone as far as the guest is concerned,
+        // only the sweeper reports it deleted
+        let session = |value: &str, ttl_ms: Option<u64>, now_ms: i64| {
+            let input = KvStoreInput {
+                table: "views".to_string(),
+                key: "user:6".to_string(),
+                value: Value::from(value),
+                ttl_ms,
+            };
+            store(kv(), input, now_ms).unwrap();
+        };
+        session("first", Some(1_000), NOW);
+        changes();
+        session("second", None, NOW + 1_000);
+        assert_eq!(
+            changes(),
+            vec![("user:6".to_string(), None, Some(Value::from("second")))]
+        );
+        session("third", Some(1_000), NOW);
+        changes();
+        assert_eq!(data.lock().unwrap().sweep_expired(NOW + 1_000).unwrap(), 1);
+        assert_eq!(
+            changes(),
+            vec![("user:6".to_string(), Some(Value::from("third")), None)]
+        );
+
+        // Dropping the table deletes every watched key in it
+        store_all(&user_data, "views", &["user:4", "user:5"]);
+        changes();
+        assert!(drop_table(kv(), "views".to_string(), NOW).unwrap());
+        assert_eq!(changes().len(), 2);
+
+        // A replacement module starts without watches
+        let replacement = GuestKvData::sharing(&data.lock().unwrap());
+        assert!(replacement.watches.is_empty());
+        assert!(data.lock().unwrap().unwatch(&watch));
+    }
+}
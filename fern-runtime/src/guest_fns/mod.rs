@@ -1,5 +1,8 @@
 pub mod debug;
 pub mod gossip;
 pub mod kv;
+pub mod rpc;
+pub mod schedule;
 pub mod sqlite_improved;
-pub mod tcp;
\ No newline at end of file
+pub mod tcp;
+pub mod timer;
\ No newline at end of file
@@ -0,0 +1,222 @@
+use std::{str::FromStr, time::Duration};
+
+use extism::{FromBytes, PTR, PluginBuilder, ToBytes, UserData, host_fn};
+use extism_convert::Json;
+use iroh::{
+    Endpoint, EndpointId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler, RouterBuilder},
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{runtime::Handle, sync::oneshot};
+
+use crate::limits::HostCallLimiter;
+
+pub const RPC_ALPN: &[u8] = b"fern/rpc/0";
+
+// Upper bound on a single request or response body
+const MAX_RPC_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+const DEFAULT_RPC_TIMEOUT_MS: u64 = 10_000;
+
+type InboundSendChannel = tokio::sync::mpsc::Sender<InboundRpcRequest>;
+type InboundRecvChannel = tokio::sync::mpsc::Receiver<InboundRpcRequest>;
+
+pub struct GuestRpc {
+    endpoint: Endpoint,
+    // Runtime outbound calls are driven on while the guest thread blocks
+    runtime: Handle,
+    // Receives requests from the rpc protocol handler to be passed to guest
+    pub inbound_rx: InboundRecvChannel,
+}
+
+/// A request from a remote guest waiting on this guest's `rpcHandler`
+pub struct InboundRpcRequest {
+    pub request: RpcRequest,
+    pub reply: oneshot::Sender<RpcResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RpcCallInput {
+    #[serde(rename = "endpointId")]
+    pub endpoint_id: String,
+    pub method: String,
+    pub payload: Value,
+    #[serde(rename = "timeoutMs")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct RpcRequest {
+    // Endpoint id of the calling guest, taken from the connection rather than the payload
+    pub from: String,
+    pub method: String,
+    pub payload: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromBytes, ToBytes)]
+#[encoding(Json)]
+pub struct RpcResponse {
+    pub payload: Value,
+    pub error: Option<String>,
+}
+
+impl RpcResponse {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            payload: Value::Null,
+            error: Some(message.into()),
+        }
+    }
+}
+
+// What actually goes over the wire. The caller's identity is added by the receiving side
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcWireRequest {
+    method: String,
+    payload: Value,
+}
+
+#[derive(Debug, Clone)]
+struct RpcProtocol {
+    inbound_tx: InboundSendChannel,
+}
+
+impl ProtocolHandler for RpcProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let from = connection.remote_id();
+        let (mut send, mut recv) = connection.accept_bi().await?;
+
+        let bytes = recv
+            .read_to_end(MAX_RPC_MESSAGE_SIZE)
+            .await
+            .map_err(AcceptError::from_err)?;
+
+        let response = match serde_json::from_slice::<RpcWireRequest>(&bytes) {
+            Ok(RpcWireRequest { method, payload }) => {
+                let (reply, reply_rx) = oneshot::channel();
+                let request = RpcRequest {
+                    from: from.to_string(),
+                    method,
+                    payload,
+                };
+
+                if self
+                    .inbound_tx
+                    .send(InboundRpcRequest { request, reply })
+                    .await
+                    .is_err()
+                {
+                    RpcResponse::error("guest is not accepting rpc requests")
+                } else {
+                    reply_rx
+                        .await
+                        .unwrap_or_else(|_| RpcResponse::error("guest dropped rpc request"))
+                }
+            }
+            Err(e) => RpcResponse::error(format!("malformed rpc request: {e}")),
+        };
+
+        let bytes = serde_json::to_vec(&response).map_err(AcceptError::from_err)?;
+        send.write_all(&bytes)
+            .await
+            .map_err(AcceptError::from_err)?;
+        send.finish().map_err(AcceptError::from_err)?;
+
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+pub fn attach_guest_rpc(
+    plugin: PluginBuilder,
+    router: RouterBuilder,
+    endpoint: Endpoint,
+    limiter: &HostCallLimiter,
+) -> (PluginBuilder, RouterBuilder, UserData<GuestRpc>) {
+    let (inbound_tx, inbound_rx) = tokio::sync::mpsc::channel(100);
+
+    let router = router.accept(RPC_ALPN, RpcProtocol { inbound_tx });
+
+    let rpc = UserData::new(GuestRpc {
+        endpoint,
+        runtime: Handle::current(),
+        inbound_rx,
+    });
+
+    let plugin = plugin.with_function(
+        "rpc_call",
+        [PTR],
+        [PTR],
+        rpc.clone(),
+        limiter.limit("rpc_call", rpc_call),
+    );
+
+    (plugin, router, rpc)
+}
+
+async fn send_rpc_request(
+    endpoint: Endpoint,
+    endpoint_id: EndpointId,
+    request: RpcWireRequest,
+) -> anyhow::Result<RpcResponse> {
+    let connection = endpoint.connect(endpoint_id, RPC_ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    send.write_all(&serde_json::to_vec(&request)?).await?;
+    send.finish()?;
+
+    let bytes = recv.read_to_end(MAX_RPC_MESSAGE_SIZE).await?;
+    connection.close(0u32.into(), b"done");
+
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+host_fn!(rpc_call(user_data: GuestRpc; input: Json<RpcCallInput>) -> RpcResponse {
+    execute_rpc_call(user_data, input.0)
+});
+
+fn execute_rpc_call(
+    user_data: UserData<GuestRpc>,
+    input: RpcCallInput,
+) -> Result<RpcResponse, extism::Error> {
+    let (endpoint, runtime) = {
+        let user_data = user_data.get()?;
+        let locked = user_data.lock().unwrap();
+        (locked.endpoint.clone(), locked.runtime.clone())
+    };
+
+    let endpoint_id = EndpointId::from_str(&input.endpoint_id)?;
+    let timeout = Duration::from_millis(input.timeout_ms.unwrap_or(DEFAULT_RPC_TIMEOUT_MS));
+    let request = RpcWireRequest {
+        method: input.method,
+        payload: input.payload,
+    };
+
+    // Host functions are synchronous so the call is driven on the runtime and
+    // the guest thread waits for the result. A guest calling itself will time out
+    // since it can't serve the request while blocked here.
+    let (tx, rx) = std::sync::mpsc::channel();
+    runtime.spawn(async move {
+        let res =
+            tokio::time::timeout(timeout, send_rpc_request(endpoint, endpoint_id, request)).await;
+        let _ = tx.send(res);
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(Ok(response))) => {
+            info!("guest rpc call to {endpoint_id} completed");
+            Ok(response)
+        }
+        Ok(Ok(Err(e))) => {
+            warn!("guest rpc call to {endpoint_id} failed {e}");
+            Ok(RpcResponse::error(e.to_string()))
+        }
+        Ok(Err(_)) | Err(_) => {
+            warn!("guest rpc call to {endpoint_id} timed out");
+            Ok(RpcResponse::error("rpc call timed out"))
+        }
+    }
+}
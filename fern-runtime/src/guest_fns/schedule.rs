@@ -0,0 +1,304 @@
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use extism::{PTR, PluginBuilder, ToBytes, UserData, host_fn};
+use extism_convert::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::limits::HostCallLimiter;
+
+/// How often guests are ticked unless they ask for something else
+pub const DEFAULT_TICK_INTERVAL: Duration = Duration::from_millis(200);
+const MIN_TICK_INTERVAL_MS: u64 = 10;
+// Anything more frequent than this belongs in tick
+const MIN_SCHEDULE_INTERVAL_MS: u64 = 1_000;
+const MAX_SCHEDULES: usize = 64;
+
+/// Tick rate and named schedules a guest registered. Like tcp handles these
+/// belong to the running module, guests register them again from `init`
+pub struct GuestSchedule {
+    // None when the guest turned ticking off
+    tick_interval: Option<Duration>,
+    schedules: BTreeMap<String, Schedule>,
+}
+
+struct Schedule {
+    trigger: Trigger,
+    next_run: DateTime<Utc>,
+}
+
+enum Trigger {
+    Cron(Box<cron::Schedule>),
+    Interval(TimeDelta),
+}
+
+impl Trigger {
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Trigger::Cron(schedule) => schedule.after(&after).next(),
+            Trigger::Interval(interval) => after.checked_add_signed(*interval),
+        }
+    }
+}
+
+/// Delivered to `scheduledTask` when one of the guest's schedules comes due
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct ScheduledTask {
+    pub name: String,
+    /// When the run was due, in unix millis. Late when the guest was busy
+    #[serde(rename = "scheduledAtMs")]
+    pub scheduled_at_ms: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TickRateInput {
+    /// Null turns ticking off
+    #[serde(rename = "intervalMs")]
+    pub interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduleCreateInput {
+    pub name: String,
+    /// `min hour day month weekday`, optionally with leading seconds and a trailing year. UTC
+    pub cron: Option<String>,
+    #[serde(rename = "intervalMs")]
+    pub interval_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduleNameInput {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct ScheduleInfo {
+    pub name: String,
+    #[serde(rename = "nextRunMs")]
+    pub next_run_ms: i64,
+}
+
+impl Default for GuestSchedule {
+    fn default() -> Self {
+        Self {
+            tick_interval: Some(DEFAULT_TICK_INTERVAL),
+            schedules: BTreeMap::new(),
+        }
+    }
+}
+
+impl GuestSchedule {
+    pub fn tick_interval(&self) -> Option<Duration> {
+        self.tick_interval
+    }
+
+    pub fn set_tick_interval(&mut self, interval_ms: Option<u64>) -> Result<(), extism::Error> {
+        if let Some(interval_ms) = interval_ms
+            && interval_ms < MIN_TICK_INTERVAL_MS
+        {
+            return Err(extism::Error::msg(format!(
+                "tick interval must be at least {MIN_TICK_INTERVAL_MS}ms"
+            )));
+        }
+        self.tick_interval = interval_ms.map(Duration::from_millis);
+        Ok(())
+    }
+
+    /// Add a schedule, replacing any existing one with the same name
+    pub fn create(
+        &mut self,
+        input: ScheduleCreateInput,
+        now: DateTime<Utc>,
+    ) -> Result<ScheduleInfo, extism::Error> {
+        if input.name.is_empty() {
+            return Err(extism::Error::msg("schedule name can't be empty"));
+        }
+        if !self.schedules.contains_key(&input.name) && self.schedules.len() >= MAX_SCHEDULES {
+            return Err(extism::Error::msg(format!(
+                "too many schedules (max {MAX_SCHEDULES})"
+            )));
+        }
+
+        let trigger = match (input.cron, input.interval_ms) {
+            (Some(expression), None) => Trigger::Cron(Box::new(parse_cron(&expression)?)),
+            (None, Some(interval_ms)) if interval_ms >= MIN_SCHEDULE_INTERVAL_MS => {
+                let interval = TimeDelta::try_milliseconds(interval_ms as i64)
+                    .ok_or_else(|| extism::Error::msg("schedule interval is too large"))?;
+                Trigger::Interval(interval)
+            }
+            (None, Some(_)) => {
+                return Err(extism::Error::msg(format!(
+                    "schedule interval must be at least {MIN_SCHEDULE_INTERVAL_MS}ms"
+                )));
+            }
+            _ => {
+                return Err(extism::Error::msg(
+                    "schedule needs exactly one of cron or intervalMs",
+                ));
+            }
+        };
+
+        let next_run = trigger.next_after(now).ok_or_else(|| {
+            extism::Error::msg(format!("schedule {} never runs", input.name))
+        })?;
+        self.schedules
+            .insert(input.name.clone(), Schedule { trigger, next_run });
+
+        Ok(ScheduleInfo {
+            name: input.name,
+            next_run_ms: next_run.timestamp_millis(),
+        })
+    }
+
+    pub fn cancel(&mut self, name: &str) -> bool {
+        self.schedules.remove(name).is_some()
+    }
+
+    /// Schedules due at `now`, moving each on to its next run. Runs missed while
+    /// the guest was busy or stopped are skipped rather than delivered in a burst
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> Vec<ScheduledTask> {
+        let mut due = vec![];
+        self.schedules.retain(|name, schedule| {
+            if schedule.next_run > now {
+                return true;
+            }
+            due.push(ScheduledTask {
+                name: name.clone(),
+                scheduled_at_ms: schedule.next_run.timestamp_millis(),
+            });
+            // A cron expression can run out, e.g. one pinned to a year
+            match schedule.trigger.next_after(now) {
+                Some(next_run) => {
+                    schedule.next_run = next_run;
+                    true
+                }
+                None => false,
+            }
+        });
+        due
+    }
+}
+
+// The cron crate wants seconds, take the usual five fields as well
+fn parse_cron(expression: &str) -> Result<cron::Schedule, extism::Error> {
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {expression}")
+    } else {
+        expression.to_string()
+    };
+    cron::Schedule::from_str(&expression)
+        .map_err(|e| extism::Error::msg(format!("invalid cron expression {expression}: {e}")))
+}
+
+pub fn attach_guest_schedule(
+    builder: PluginBuilder,
+    limiter: &HostCallLimiter,
+) -> (PluginBuilder, UserData<GuestSchedule>) {
+    let user_data = UserData::new(GuestSchedule::default());
+    let builder = builder
+        .with_function(
+            "tick_rate_set",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("tick_rate_set", tick_rate_set),
+        )
+        .with_function(
+            "schedule_create",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("schedule_create", schedule_create),
+        )
+        .with_function(
+            "schedule_cancel",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("schedule_cancel", schedule_cancel),
+        );
+
+    (builder, user_data)
+}
+
+host_fn!(tick_rate_set(user_data: GuestSchedule; input: Json<TickRateInput>) -> bool {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    data.set_tick_interval(input.0.interval_ms)?;
+    Ok(true)
+});
+
+host_fn!(schedule_create(user_data: GuestSchedule; input: Json<ScheduleCreateInput>) -> ScheduleInfo {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    data.create(input.0, Utc::now())
+});
+
+host_fn!(schedule_cancel(user_data: GuestSchedule; input: Json<ScheduleNameInput>) -> bool {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    Ok(data.cancel(&input.0.name))
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(
+        schedule: &mut GuestSchedule,
+        name: &str,
+        cron: Option<&str>,
+        interval_ms: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<ScheduleInfo, extism::Error> {
+        schedule.create(
+            ScheduleCreateInput {
+                name: name.to_string(),
+                cron: cron.map(str::to_string),
+                interval_ms,
+            },
+            now,
+        )
+    }
+
+    #[test]
+    fn schedules_come_due() {
+        let now = DateTime::parse_from_rfc3339("2025-01-01T10:30:00Z")
+            .unwrap()
+            .to_utc();
+        let mut schedule = GuestSchedule::default();
+
+        let hourly = create(&mut schedule, "compact", Some("0 * * * *"), None, now).unwrap();
+        assert_eq!(
+            hourly.next_run_ms,
+            now.timestamp_millis() + 30 * 60 * 1000
+        );
+        create(&mut schedule, "flush", None, Some(60_000), now).unwrap();
+
+        assert!(create(&mut schedule, "fast", None, Some(10), now).is_err());
+        assert!(create(&mut schedule, "both", Some("* * * * *"), Some(60_000), now).is_err());
+        assert!(create(&mut schedule, "bad", Some("not cron"), None, now).is_err());
+
+        assert!(schedule.take_due(now).is_empty());
+
+        // Both are late, each is only delivered once
+        let later = now + TimeDelta::hours(2);
+        let due: Vec<_> = schedule
+            .take_due(later)
+            .into_iter()
+            .map(|task| task.name)
+            .collect();
+        assert_eq!(due, vec!["compact", "flush"]);
+        assert!(schedule.take_due(later).is_empty());
+
+        assert!(schedule.cancel("flush"));
+        assert!(!schedule.cancel("flush"));
+        assert_eq!(schedule.take_due(later + TimeDelta::hours(1)).len(), 1);
+
+        assert!(schedule.set_tick_interval(Some(1)).is_err());
+        schedule.set_tick_interval(None).unwrap();
+        assert_eq!(schedule.tick_interval(), None);
+    }
+}
@@ -1,17 +1,30 @@
 use base64;
 use extism::{FromBytes, PTR, PluginBuilder, ToBytes, UserData, host_fn};
 use extism_convert::Json;
-use log::info;
+use log::{info, warn};
 use rusqlite::{ToSql, params_from_iter, types::ToSqlOutput};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
-use std::time::Instant;
-use crate::guest::GuestConfig;
+use std::time::{Duration, Instant};
+use crate::{guest::GuestConfig, limits::HostCallLimiter};
+
+// Transactions left open longer than this are rolled back by the host
+pub const DEFAULT_MAX_TRANSACTION_AGE: Duration = Duration::from_secs(30);
 
 pub struct GuestSqliteDbImproved {
     pub db: rusqlite::Connection,
     pub stats: QueryStats,
+    pub max_transaction_age: Duration,
+    // Open transactions, outermost first. Everything after the first entry is a SAVEPOINT
+    transactions: Vec<OpenTransaction>,
+    next_savepoint: u64,
+}
+
+struct OpenTransaction {
+    id: String,
+    savepoint: Option<String>,
+    started: Instant,
 }
 
 #[derive(Debug, Default)]
@@ -40,6 +53,9 @@ impl GuestSqliteDbImproved {
         Self {
             db,
             stats: QueryStats::default(),
+            max_transaction_age: DEFAULT_MAX_TRANSACTION_AGE,
+            transactions: vec![],
+            next_savepoint: 0,
         }
     }
 
@@ -89,7 +105,137 @@ impl GuestSqliteDbImproved {
         Self {
             db,
             stats: QueryStats::default(),
+            max_transaction_age: DEFAULT_MAX_TRANSACTION_AGE,
+            transactions: vec![],
+            next_savepoint: 0,
+        }
+    }
+
+    pub fn open_transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn begin_transaction(&mut self) -> rusqlite::Result<TransactionResult> {
+        let transaction_id = format!("tx_{}", uuid::Uuid::new_v4());
+
+        let savepoint = if self.transactions.is_empty() {
+            self.db.execute_batch("BEGIN TRANSACTION")?;
+            None
+        } else {
+            let name = format!("fern_sp_{}", self.next_savepoint);
+            self.next_savepoint += 1;
+            self.db.execute_batch(&format!("SAVEPOINT {name}"))?;
+            Some(name)
+        };
+
+        self.transactions.push(OpenTransaction {
+            id: transaction_id.clone(),
+            savepoint,
+            started: Instant::now(),
+        });
+
+        Ok(TransactionResult {
+            transaction_id,
+            success: true,
+            depth: self.transactions.len() as u64,
+        })
+    }
+
+    /// Commit the innermost open transaction. Committing an outer transaction
+    /// while nested ones are still open is rejected.
+    pub fn commit_transaction(
+        &mut self,
+        transaction_id: &str,
+    ) -> Result<TransactionResult, extism::Error> {
+        let index = self.transaction_index(transaction_id)?;
+        if index != self.transactions.len() - 1 {
+            return Err(extism::Error::msg(format!(
+                "transaction {transaction_id} has open nested transactions"
+            )));
+        }
+
+        match &self.transactions[index].savepoint {
+            Some(name) => self.db.execute_batch(&format!("RELEASE SAVEPOINT {name}"))?,
+            None => self.db.execute_batch("COMMIT")?,
         }
+        self.transactions.truncate(index);
+
+        Ok(TransactionResult {
+            transaction_id: transaction_id.to_string(),
+            success: true,
+            depth: self.transactions.len() as u64,
+        })
+    }
+
+    /// Roll back a transaction along with any transactions nested inside it
+    pub fn rollback_transaction(
+        &mut self,
+        transaction_id: &str,
+    ) -> Result<TransactionResult, extism::Error> {
+        let index = self.transaction_index(transaction_id)?;
+
+        match &self.transactions[index].savepoint {
+            Some(name) => self.db.execute_batch(&format!(
+                "ROLLBACK TO SAVEPOINT {name}; RELEASE SAVEPOINT {name}"
+            ))?,
+            None => self.db.execute_batch("ROLLBACK")?,
+        }
+        self.transactions.truncate(index);
+
+        Ok(TransactionResult {
+            transaction_id: transaction_id.to_string(),
+            success: true,
+            depth: self.transactions.len() as u64,
+        })
+    }
+
+    /// Roll back every open transaction. Used when the guest traps or is shut down
+    /// so the connection isn't left half open for the next call.
+    pub fn rollback_all(&mut self, reason: &str) {
+        self.rollback_above(0, reason);
+    }
+
+    /// Roll back the transactions opened after the first `depth`, the outer ones stay open
+    pub fn rollback_above(&mut self, depth: usize, reason: &str) {
+        let Some(tx) = self.transactions.get(depth) else {
+            return;
+        };
+        warn!(
+            "rolling back {} open sqlite transaction(s): {reason}",
+            self.transactions.len() - depth
+        );
+        let res = match &tx.savepoint {
+            Some(name) => self.db.execute_batch(&format!(
+                "ROLLBACK TO SAVEPOINT {name}; RELEASE SAVEPOINT {name}"
+            )),
+            None => self.db.execute_batch("ROLLBACK"),
+        };
+        self.transactions.truncate(depth);
+        if let Err(e) = res {
+            warn!("failed to rollback sqlite transaction {e}");
+        }
+    }
+
+    /// Roll back everything if the outermost transaction is older than `max_transaction_age`
+    pub fn expire_transactions(&mut self) {
+        let expired = self
+            .transactions
+            .first()
+            .is_some_and(|tx| tx.started.elapsed() > self.max_transaction_age);
+        if expired {
+            self.rollback_all("transaction exceeded max age");
+        }
+    }
+
+    fn transaction_index(&self, transaction_id: &str) -> Result<usize, extism::Error> {
+        self.transactions
+            .iter()
+            .position(|tx| tx.id == transaction_id)
+            .ok_or_else(|| {
+                extism::Error::msg(format!(
+                    "unknown or expired transaction {transaction_id}"
+                ))
+            })
     }
 
     pub fn record_query(&mut self, query_type: &str, execution_time_ms: f64) {
@@ -315,6 +461,8 @@ pub struct TransactionResult {
     #[serde(rename = "transactionId")]
     pub transaction_id: String,
     pub success: bool,
+    // Number of transactions still open after this operation
+    pub depth: u64,
 }
 
 // Database statistics
@@ -353,6 +501,7 @@ pub fn attach_guest_sqlite_improved(
     builder: PluginBuilder,
     config: GuestConfig,
     existing_user_data: Option<UserData<GuestSqliteDbImproved>>,
+    limiter: &HostCallLimiter,
 ) -> (PluginBuilder, UserData<GuestSqliteDbImproved>) {
     let user_data = existing_user_data.unwrap_or_else(|| UserData::new(GuestSqliteDbImproved::new_with_config(&config)));
     let builder = builder
@@ -361,63 +510,63 @@ pub fn attach_guest_sqlite_improved(
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_execute_enhanced,
+            limiter.limit("sqlite_execute_enhanced", sqlite_execute_enhanced),
         )
         .with_function(
             "sqlite_query_enhanced",
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_query_enhanced,
+            limiter.limit("sqlite_query_enhanced", sqlite_query_enhanced),
         )
         .with_function(
             "sqlite_describe_table",
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_describe_table,
+            limiter.limit("sqlite_describe_table", sqlite_describe_table),
         )
         .with_function(
             "sqlite_list_tables",
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_list_tables,
+            limiter.limit("sqlite_list_tables", sqlite_list_tables),
         )
         .with_function(
             "sqlite_explain_query",
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_explain_query,
+            limiter.limit("sqlite_explain_query", sqlite_explain_query),
         )
         .with_function(
             "sqlite_get_stats",
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_get_stats,
+            limiter.limit("sqlite_get_stats", sqlite_get_stats),
         )
         .with_function(
             "sqlite_begin_transaction",
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_begin_transaction,
+            limiter.limit("sqlite_begin_transaction", sqlite_begin_transaction),
         )
         .with_function(
             "sqlite_commit_transaction",
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_commit_transaction,
+            limiter.limit("sqlite_commit_transaction", sqlite_commit_transaction),
         )
         .with_function(
             "sqlite_rollback_transaction",
             [PTR],
             [PTR],
             user_data.clone(),
-            sqlite_rollback_transaction,
+            limiter.limit("sqlite_rollback_transaction", sqlite_rollback_transaction),
         );
 
     (builder, user_data)
@@ -470,7 +619,8 @@ fn execute_enhanced(
 ) -> Result<EnhancedSqlResult, extism::Error> {
     let start = Instant::now();
     let user_data_guard = user_data.get()?;
-    let user_data = user_data_guard.lock().unwrap();
+    let mut user_data = user_data_guard.lock().unwrap();
+    user_data.expire_transactions();
 
     let rows_affected = user_data
         .db
@@ -515,7 +665,8 @@ fn query_enhanced(
 ) -> Result<EnhancedSqlResult, extism::Error> {
     let start = Instant::now();
     let user_data_guard = user_data.get()?;
-    let user_data = user_data_guard.lock().unwrap();
+    let mut user_data = user_data_guard.lock().unwrap();
+    user_data.expire_transactions();
 
     // Get query plan for debugging
     let query_plan = if params.sql.trim_start().to_lowercase().starts_with("select") {
@@ -771,66 +922,38 @@ fn begin_transaction(
     user_data: UserData<GuestSqliteDbImproved>,
 ) -> Result<TransactionResult, extism::Error> {
     let user_data = user_data.get()?;
-    let user_data = user_data.lock().unwrap();
+    let mut user_data = user_data.lock().unwrap();
+    user_data.expire_transactions();
 
-    match user_data.db.execute("BEGIN TRANSACTION", []) {
-        Ok(_) => {
-            // Generate a simple transaction ID using timestamp
-            let transaction_id = format!(
-                "tx_{}",
-                std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_nanos()
-            );
-            Ok(TransactionResult {
-                transaction_id,
-                success: true,
-            })
-        }
-        Err(e) => Err(extism::Error::msg(format!(
-            "Failed to begin transaction: {}",
-            e
-        ))),
-    }
+    user_data
+        .begin_transaction()
+        .map_err(|e| extism::Error::msg(format!("Failed to begin transaction: {}", e)))
 }
 
 fn commit_transaction(
     user_data: UserData<GuestSqliteDbImproved>,
-    _transaction_id: String,
+    transaction_id: String,
 ) -> Result<TransactionResult, extism::Error> {
     let user_data = user_data.get()?;
-    let user_data = user_data.lock().unwrap();
+    let mut user_data = user_data.lock().unwrap();
+    user_data.expire_transactions();
 
-    match user_data.db.execute("COMMIT", []) {
-        Ok(_) => Ok(TransactionResult {
-            transaction_id: _transaction_id,
-            success: true,
-        }),
-        Err(e) => Err(extism::Error::msg(format!(
-            "Failed to commit transaction: {}",
-            e
-        ))),
-    }
+    user_data
+        .commit_transaction(&transaction_id)
+        .map_err(|e| extism::Error::msg(format!("Failed to commit transaction: {}", e)))
 }
 
 fn rollback_transaction(
     user_data: UserData<GuestSqliteDbImproved>,
-    _transaction_id: String,
+    transaction_id: String,
 ) -> Result<TransactionResult, extism::Error> {
     let user_data = user_data.get()?;
-    let user_data = user_data.lock().unwrap();
+    let mut user_data = user_data.lock().unwrap();
+    user_data.expire_transactions();
 
-    match user_data.db.execute("ROLLBACK", []) {
-        Ok(_) => Ok(TransactionResult {
-            transaction_id: _transaction_id,
-            success: true,
-        }),
-        Err(e) => Err(extism::Error::msg(format!(
-            "Failed to rollback transaction: {}",
-            e
-        ))),
-    }
+    user_data
+        .rollback_transaction(&transaction_id)
+        .map_err(|e| extism::Error::msg(format!("Failed to rollback transaction: {}", e)))
 }
 
 // fn get_query_type(sql: &str) -> String {
@@ -853,3 +976,77 @@ fn rollback_transaction(
 //         "OTHER".to_string()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(db: &GuestSqliteDbImproved) -> i64 {
+        db.db
+            .query_row("SELECT count(*) FROM items", [], |row| row.get(0))
+            .expect("failed to count items")
+    }
+
+    #[test]
+    fn sqlite_transaction_handles() {
+        let mut db = GuestSqliteDbImproved::new();
+        db.db
+            .execute_batch("CREATE TABLE items (id INTEGER PRIMARY KEY)")
+            .expect("failed to create table");
+
+        let outer = db.begin_transaction().expect("failed to begin outer");
+        assert_eq!(outer.depth, 1);
+        db.db.execute_batch("INSERT INTO items DEFAULT VALUES").unwrap();
+
+        let inner = db.begin_transaction().expect("failed to begin inner");
+        assert_eq!(inner.depth, 2);
+        db.db.execute_batch("INSERT INTO items DEFAULT VALUES").unwrap();
+
+        // Outer can't be committed while inner is open
+        assert!(db.commit_transaction(&outer.transaction_id).is_err());
+
+        // Rolling back the savepoint keeps the outer transaction's work
+        db.rollback_transaction(&inner.transaction_id)
+            .expect("failed to rollback inner");
+        assert_eq!(count(&db), 1);
+
+        // The inner id is now stale
+        assert!(db.commit_transaction(&inner.transaction_id).is_err());
+        assert!(db.commit_transaction("tx_unknown").is_err());
+
+        let res = db
+            .commit_transaction(&outer.transaction_id)
+            .expect("failed to commit outer");
+        assert_eq!(res.depth, 0);
+        assert_eq!(count(&db), 1);
+
+        // Anything left open is discarded by rollback_all
+        let tx = db.begin_transaction().expect("failed to begin");
+        db.db.execute_batch("INSERT INTO items DEFAULT VALUES").unwrap();
+        db.begin_transaction().expect("failed to begin nested");
+        db.rollback_all("test");
+        assert_eq!(db.open_transaction_count(), 0);
+        assert_eq!(count(&db), 1);
+        assert!(db.rollback_transaction(&tx.transaction_id).is_err());
+
+        // Expired transactions are rolled back
+        db.max_transaction_age = Duration::ZERO;
+        db.begin_transaction().expect("failed to begin");
+        db.db.execute_batch("INSERT INTO items DEFAULT VALUES").unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        db.expire_transactions();
+        assert_eq!(db.open_transaction_count(), 0);
+        assert_eq!(count(&db), 1);
+
+        // Only the transactions above the given depth are rolled back
+        let outer = db.begin_transaction().expect("failed to begin outer");
+        db.db.execute_batch("INSERT INTO items DEFAULT VALUES").unwrap();
+        db.begin_transaction().expect("failed to begin nested");
+        db.db.execute_batch("INSERT INTO items DEFAULT VALUES").unwrap();
+        db.rollback_above(1, "test");
+        assert_eq!(db.open_transaction_count(), 1);
+        db.commit_transaction(&outer.transaction_id)
+            .expect("failed to commit outer");
+        assert_eq!(count(&db), 2);
+    }
+}
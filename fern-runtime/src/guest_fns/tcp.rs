@@ -1,6 +1,423 @@
-use tokio::net::{TcpSocket, tcp, unix::SocketAddr};
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
 
+use base64::{Engine, engine::general_purpose::STANDARD};
+use extism::{PTR, PluginBuilder, ToBytes, UserData, host_fn};
+use extism_convert::Json;
+use log::info;
+use serde::{Deserialize, Serialize};
 
-pub struct Tcp {
+use crate::{guest::GuestConfig, limits::HostCallLimiter};
 
-}
\ No newline at end of file
+const DEFAULT_TCP_TIMEOUT_MS: u64 = 5_000;
+const MAX_TCP_READ_BYTES: usize = 1024 * 1024;
+// Connections and listeners share the same handle space
+const MAX_TCP_HANDLES: usize = 64;
+
+/// Operator granted TCP access. Entries are `host:port` where either side may be `*`,
+/// e.g. `localhost:5432` or `10.0.0.5:*`. Guests get no TCP access by default.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TcpPermissions {
+    #[serde(default)]
+    pub connect: Vec<String>,
+    #[serde(default)]
+    pub listen: Vec<String>,
+}
+
+impl TcpPermissions {
+    pub fn can_connect(&self, address: &str) -> bool {
+        self.connect
+            .iter()
+            .any(|rule| address_matches(rule, address))
+    }
+
+    pub fn can_listen(&self, address: &str) -> bool {
+        self.listen
+            .iter()
+            .any(|rule| address_matches(rule, address))
+    }
+}
+
+fn address_matches(rule: &str, address: &str) -> bool {
+    if rule == "*" {
+        return true;
+    }
+    let (Some((rule_host, rule_port)), Some((host, port))) =
+        (rule.rsplit_once(':'), address.rsplit_once(':'))
+    else {
+        return false;
+    };
+    (rule_host == "*" || rule_host.eq_ignore_ascii_case(host))
+        && (rule_port == "*" || rule_port == port)
+}
+
+pub struct GuestTcp {
+    permissions: TcpPermissions,
+    next_handle: u64,
+    connections: HashMap<u64, TcpStream>,
+    listeners: HashMap<u64, TcpListener>,
+}
+
+/// A connection accepted on one of the guest's listeners, delivered to `tcpAccepted`
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct TcpAccepted {
+    #[serde(rename = "listenerHandle")]
+    pub listener_handle: u64,
+    pub handle: u64,
+    #[serde(rename = "peerAddress")]
+    pub peer_address: String,
+}
+
+impl GuestTcp {
+    pub fn new(permissions: TcpPermissions) -> Self {
+        Self {
+            permissions,
+            next_handle: 1,
+            connections: HashMap::new(),
+            listeners: HashMap::new(),
+        }
+    }
+
+    fn allocate_handle(&mut self) -> Result<u64, extism::Error> {
+        if self.connections.len() + self.listeners.len() >= MAX_TCP_HANDLES {
+            return Err(extism::Error::msg(format!(
+                "too many open tcp handles (max {MAX_TCP_HANDLES})"
+            )));
+        }
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        Ok(handle)
+    }
+
+    fn connection(&mut self, handle: u64) -> Result<&mut TcpStream, extism::Error> {
+        self.connections
+            .get_mut(&handle)
+            .ok_or_else(|| extism::Error::msg(format!("unknown tcp handle {handle}")))
+    }
+
+    /// Accept any pending connections on the guest's listeners without blocking.
+    pub fn accept_pending(&mut self) -> Vec<TcpAccepted> {
+        let mut accepted = vec![];
+        let mut streams = vec![];
+        for (listener_handle, listener) in self.listeners.iter() {
+            loop {
+                match listener.accept() {
+                    Ok((stream, peer)) => streams.push((*listener_handle, stream, peer)),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        info!("tcp listener {listener_handle} accept failed {e}");
+                        break;
+                    }
+                }
+            }
+        }
+
+        for (listener_handle, stream, peer) in streams {
+            // Accepted sockets inherit non-blocking mode from the listener
+            if stream.set_nonblocking(false).is_err() {
+                continue;
+            }
+            match self.allocate_handle() {
+                Ok(handle) => {
+                    self.connections.insert(handle, stream);
+                    accepted.push(TcpAccepted {
+                        listener_handle,
+                        handle,
+                        peer_address: peer.to_string(),
+                    });
+                }
+                Err(e) => {
+                    info!("dropping tcp connection from {peer} {e}");
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            }
+        }
+        accepted
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub enum TcpEncoding {
+    #[default]
+    #[serde(rename = "utf8")]
+    Utf8,
+    #[serde(rename = "base64")]
+    Base64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpConnectInput {
+    pub address: String,
+    #[serde(rename = "timeoutMs")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpListenInput {
+    pub address: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpHandleInput {
+    pub handle: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpWriteInput {
+    pub handle: u64,
+    pub data: String,
+    pub encoding: Option<TcpEncoding>,
+    #[serde(rename = "timeoutMs")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpReadInput {
+    pub handle: u64,
+    #[serde(rename = "maxBytes")]
+    pub max_bytes: u64,
+    pub encoding: Option<TcpEncoding>,
+    #[serde(rename = "timeoutMs")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct TcpHandleResult {
+    pub handle: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct TcpReadResult {
+    pub data: String,
+    #[serde(rename = "bytesRead")]
+    pub bytes_read: u64,
+    // The peer closed its side of the connection
+    pub eof: bool,
+    #[serde(rename = "timedOut")]
+    pub timed_out: bool,
+}
+
+pub fn attach_guest_tcp(
+    builder: PluginBuilder,
+    config: GuestConfig,
+    limiter: &HostCallLimiter,
+) -> (PluginBuilder, UserData<GuestTcp>) {
+    let user_data = UserData::new(GuestTcp::new(config.tcp));
+    let builder = builder
+        .with_function(
+            "tcp_connect",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("tcp_connect", tcp_connect),
+        )
+        .with_function(
+            "tcp_write",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("tcp_write", tcp_write),
+        )
+        .with_function(
+            "tcp_read",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("tcp_read", tcp_read),
+        )
+        .with_function(
+            "tcp_close",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("tcp_close", tcp_close),
+        )
+        .with_function(
+            "tcp_listen",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("tcp_listen", tcp_listen),
+        );
+
+    (builder, user_data)
+}
+
+host_fn!(tcp_connect(user_data: GuestTcp; input: Json<TcpConnectInput>) -> TcpHandleResult {
+    connect(user_data, input.0)
+});
+
+host_fn!(tcp_write(user_data: GuestTcp; input: Json<TcpWriteInput>) -> u64 {
+    write(user_data, input.0)
+});
+
+host_fn!(tcp_read(user_data: GuestTcp; input: Json<TcpReadInput>) -> TcpReadResult {
+    read(user_data, input.0)
+});
+
+host_fn!(tcp_close(user_data: GuestTcp; input: Json<TcpHandleInput>) -> bool {
+    close(user_data, input.0.handle)
+});
+
+host_fn!(tcp_listen(user_data: GuestTcp; input: Json<TcpListenInput>) -> TcpHandleResult {
+    listen(user_data, input.0.address)
+});
+
+fn timeout(timeout_ms: Option<u64>) -> Duration {
+    // A zero duration is rejected by the socket timeout setters
+    Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TCP_TIMEOUT_MS).max(1))
+}
+
+fn connect(
+    user_data: UserData<GuestTcp>,
+    input: TcpConnectInput,
+) -> Result<TcpHandleResult, extism::Error> {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+
+    if !data.permissions.can_connect(&input.address) {
+        return Err(extism::Error::msg(format!(
+            "tcp connect to {} is not permitted",
+            input.address
+        )));
+    }
+    let handle = data.allocate_handle()?;
+
+    let timeout = timeout(input.timeout_ms);
+    let mut last_error = None;
+    for addr in input.address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                info!("guest tcp handle {handle} connected to {addr}");
+                data.connections.insert(handle, stream);
+                return Ok(TcpHandleResult { handle });
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(match last_error {
+        Some(e) => extism::Error::msg(format!("tcp connect to {} failed: {e}", input.address)),
+        None => extism::Error::msg(format!("{} did not resolve", input.address)),
+    })
+}
+
+fn write(user_data: UserData<GuestTcp>, input: TcpWriteInput) -> Result<u64, extism::Error> {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    let stream = data.connection(input.handle)?;
+
+    let bytes = match input.encoding.unwrap_or_default() {
+        TcpEncoding::Utf8 => input.data.into_bytes(),
+        TcpEncoding::Base64 => STANDARD.decode(input.data)?,
+    };
+
+    stream.set_write_timeout(Some(timeout(input.timeout_ms)))?;
+    stream.write_all(&bytes)?;
+    stream.flush()?;
+    Ok(bytes.len() as u64)
+}
+
+fn read(
+    user_data: UserData<GuestTcp>,
+    input: TcpReadInput,
+) -> Result<TcpReadResult, extism::Error> {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    let stream = data.connection(input.handle)?;
+
+    stream.set_read_timeout(Some(timeout(input.timeout_ms)))?;
+    let mut buf = vec![0; (input.max_bytes as usize).min(MAX_TCP_READ_BYTES)];
+    let (bytes_read, timed_out) = match stream.read(&mut buf) {
+        Ok(n) => (n, false),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (0, true),
+        Err(e) => return Err(e.into()),
+    };
+    buf.truncate(bytes_read);
+
+    let content = match input.encoding.unwrap_or_default() {
+        TcpEncoding::Utf8 => String::from_utf8_lossy(&buf).to_string(),
+        TcpEncoding::Base64 => STANDARD.encode(&buf),
+    };
+
+    Ok(TcpReadResult {
+        data: content,
+        bytes_read: bytes_read as u64,
+        eof: bytes_read == 0 && !timed_out && input.max_bytes > 0,
+        timed_out,
+    })
+}
+
+fn close(user_data: UserData<GuestTcp>, handle: u64) -> Result<bool, extism::Error> {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+
+    if let Some(stream) = data.connections.remove(&handle) {
+        let _ = stream.shutdown(Shutdown::Both);
+        return Ok(true);
+    }
+    Ok(data.listeners.remove(&handle).is_some())
+}
+
+fn listen(
+    user_data: UserData<GuestTcp>,
+    address: String,
+) -> Result<TcpHandleResult, extism::Error> {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+
+    if !data.permissions.can_listen(&address) {
+        return Err(extism::Error::msg(format!(
+            "tcp listen on {address} is not permitted"
+        )));
+    }
+    let handle = data.allocate_handle()?;
+
+    let listener = TcpListener::bind(&address)?;
+    // Polled from the guest instance task, see `Guest::tick_tcp`
+    listener.set_nonblocking(true)?;
+    info!("guest tcp handle {handle} listening on {address}");
+    data.listeners.insert(handle, listener);
+
+    Ok(TcpHandleResult { handle })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_permissions() {
+        let permissions = TcpPermissions {
+            connect: vec!["localhost:5432".to_string(), "10.0.0.5:*".to_string()],
+            listen: vec!["*:7000".to_string()],
+        };
+
+        assert!(permissions.can_connect("localhost:5432"));
+        assert!(permissions.can_connect("LOCALHOST:5432"));
+        assert!(!permissions.can_connect("localhost:5433"));
+        assert!(permissions.can_connect("10.0.0.5:22"));
+        assert!(!permissions.can_connect("10.0.0.6:22"));
+        assert!(!permissions.can_connect("localhost"));
+
+        assert!(permissions.can_listen("0.0.0.0:7000"));
+        assert!(!permissions.can_listen("0.0.0.0:7001"));
+
+        let none = TcpPermissions::default();
+        assert!(!none.can_connect("localhost:5432"));
+        assert!(!none.can_listen("0.0.0.0:7000"));
+
+        let all = TcpPermissions {
+            connect: vec!["*".to_string()],
+            listen: vec![],
+        };
+        assert!(all.can_connect("example.com:80"));
+    }
+}
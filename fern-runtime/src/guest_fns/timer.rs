@@ -0,0 +1,318 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::PathBuf,
+};
+
+use extism::{PTR, PluginBuilder, ToBytes, UserData, host_fn};
+use extism_convert::Json;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{guest::GuestConfig, limits::HostCallLimiter};
+
+const MAX_TIMERS: usize = 1024;
+// About a year, anything longer is better off as a schedule
+const MAX_TIMER_DELAY_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+/// Pending timers for a guest. Carried over when the guest is restarted or
+/// updated like sqlite, but only persisted timers make it across, see
+/// `Guest::drop_transient_timers`
+pub struct GuestTimers {
+    next_id: u64,
+    timers: BTreeMap<u64, Timer>,
+    // (due, id) so the next timer to fire is always first
+    wheel: BTreeSet<(i64, u64)>,
+    // Where persisted timers are saved, they only last as long as the node without one
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Timer {
+    id: u64,
+    due_at_ms: i64,
+    payload: Value,
+    persist: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedTimers {
+    next_id: u64,
+    timers: Vec<Timer>,
+}
+
+/// Delivered to `timerFired` once a timer's delay has passed
+#[derive(Debug, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct TimerFired {
+    pub id: u64,
+    pub payload: Value,
+    /// When the timer was due, in unix millis
+    #[serde(rename = "dueAtMs")]
+    pub due_at_ms: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimerSetInput {
+    #[serde(rename = "delayMs")]
+    pub delay_ms: u64,
+    #[serde(default)]
+    pub payload: Value,
+    /// Keep the timer across guest restarts, updates and node restarts
+    #[serde(default)]
+    pub persist: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimerCancelInput {
+    pub id: u64,
+}
+
+impl GuestTimers {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let mut timers = Self {
+            next_id: 1,
+            timers: BTreeMap::new(),
+            wheel: BTreeSet::new(),
+            path,
+        };
+        if let Err(e) = timers.load() {
+            warn!("failed to load saved guest timers {e}");
+        }
+        timers
+    }
+
+    pub fn new_with_config(config: &GuestConfig) -> Self {
+        // Next to the kv database, see `GuestKvData::new_with_config`
+        let path = config.host_data_path.as_ref().map(|host_data_path| {
+            let mut path = host_data_path.clone();
+            path.push(&config.name);
+            path.push("timers.json");
+            path
+        });
+        Self::new(path)
+    }
+
+    pub fn set(&mut self, input: TimerSetInput, now_ms: i64) -> Result<u64, extism::Error> {
+        if self.timers.len() >= MAX_TIMERS {
+            return Err(extism::Error::msg(format!(
+                "too many pending timers (max {MAX_TIMERS})"
+            )));
+        }
+        if input.delay_ms > MAX_TIMER_DELAY_MS {
+            return Err(extism::Error::msg(format!(
+                "timer delay can be at most {MAX_TIMER_DELAY_MS}ms"
+            )));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.insert(Timer {
+            id,
+            due_at_ms: now_ms + input.delay_ms as i64,
+            payload: input.payload,
+            persist: input.persist,
+        });
+
+        if input.persist {
+            self.save()?;
+        }
+        Ok(id)
+    }
+
+    pub fn cancel(&mut self, id: u64) -> Result<bool, extism::Error> {
+        let Some(timer) = self.timers.remove(&id) else {
+            return Ok(false);
+        };
+        self.wheel.remove(&(timer.due_at_ms, id));
+
+        if timer.persist {
+            self.save()?;
+        }
+        Ok(true)
+    }
+
+    /// Remove and return every timer due at `now_ms`, earliest first
+    pub fn take_due(&mut self, now_ms: i64) -> Vec<TimerFired> {
+        let mut fired = vec![];
+        let mut persisted = false;
+        while let Some(&(due_at_ms, id)) = self.wheel.first() {
+            if due_at_ms > now_ms {
+                break;
+            }
+            self.wheel.pop_first();
+            let Some(timer) = self.timers.remove(&id) else {
+                continue;
+            };
+            persisted |= timer.persist;
+            fired.push(TimerFired {
+                id,
+                payload: timer.payload,
+                due_at_ms,
+            });
+        }
+
+        // Timers are delivered at most once, a crash right after this drops them
+        if persisted && let Err(e) = self.save() {
+            warn!("failed to save guest timers {e}");
+        }
+        fired
+    }
+
+    /// Unix millis the next timer is due at
+    pub fn next_due_ms(&self) -> Option<i64> {
+        self.wheel.first().map(|(due_at_ms, _)| *due_at_ms)
+    }
+
+    /// Id the next timer will get, timers with lower ids were set before now
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Drop timers set before `before_id` which shouldn't outlive the module that set them
+    pub fn drop_transient(&mut self, before_id: u64) {
+        self.timers
+            .retain(|id, timer| timer.persist || *id >= before_id);
+        self.wheel.retain(|(_, id)| self.timers.contains_key(id));
+    }
+
+    fn insert(&mut self, timer: Timer) {
+        self.wheel.insert((timer.due_at_ms, timer.id));
+        self.timers.insert(timer.id, timer);
+    }
+
+    fn load(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let saved: SavedTimers = serde_json::from_slice(&bytes)?;
+        self.next_id = self.next_id.max(saved.next_id);
+        for timer in saved.timers {
+            self.insert(timer);
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let saved = SavedTimers {
+            next_id: self.next_id,
+            timers: self
+                .timers
+                .values()
+                .filter(|timer| timer.persist)
+                .cloned()
+                .collect(),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write then rename so a crash never leaves half a file behind
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&saved)?)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+pub fn attach_guest_timers(
+    builder: PluginBuilder,
+    config: GuestConfig,
+    existing_user_data: Option<UserData<GuestTimers>>,
+    limiter: &HostCallLimiter,
+) -> (PluginBuilder, UserData<GuestTimers>) {
+    let user_data = existing_user_data
+        .unwrap_or_else(|| UserData::new(GuestTimers::new_with_config(&config)));
+    let builder = builder
+        .with_function(
+            "timer_set",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("timer_set", timer_set),
+        )
+        .with_function(
+            "timer_cancel",
+            [PTR],
+            [PTR],
+            user_data.clone(),
+            limiter.limit("timer_cancel", timer_cancel),
+        );
+
+    (builder, user_data)
+}
+
+host_fn!(timer_set(user_data: GuestTimers; input: Json<TimerSetInput>) -> u64 {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    data.set(input.0, chrono::Utc::now().timestamp_millis())
+});
+
+host_fn!(timer_cancel(user_data: GuestTimers; input: Json<TimerCancelInput>) -> bool {
+    let data = user_data.get()?;
+    let mut data = data.lock().unwrap();
+    data.cancel(input.0.id)
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(timers: &mut GuestTimers, delay_ms: u64, persist: bool) -> u64 {
+        timers
+            .set(
+                TimerSetInput {
+                    delay_ms,
+                    payload: Value::from(delay_ms),
+                    persist,
+                },
+                1_000,
+            )
+            .expect("failed to set timer")
+    }
+
+    #[test]
+    fn timers_fire_in_order_and_persist() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("timers.json");
+
+        let mut timers = GuestTimers::new(Some(path.clone()));
+        let late = set(&mut timers, 500, true);
+        let early = set(&mut timers, 100, false);
+        let cancelled = set(&mut timers, 200, true);
+        assert_eq!(timers.next_due_ms(), Some(1_100));
+
+        assert!(timers.cancel(cancelled).unwrap());
+        assert!(!timers.cancel(cancelled).unwrap());
+        assert!(timers.take_due(1_099).is_empty());
+
+        // Only the persisted timer survives a restart
+        let reloaded = GuestTimers::new(Some(path.clone()));
+        assert_eq!(reloaded.next_due_ms(), Some(1_500));
+
+        let fired: Vec<_> = timers.take_due(2_000).into_iter().map(|t| t.id).collect();
+        assert_eq!(fired, vec![early, late]);
+        assert_eq!(timers.next_due_ms(), None);
+
+        // Ids aren't reused after a reload
+        let mut reloaded = GuestTimers::new(Some(path));
+        assert_eq!(reloaded.next_due_ms(), None);
+        assert!(set(&mut reloaded, 100, false) > cancelled);
+
+        let next_id = reloaded.next_id();
+        set(&mut reloaded, 100, false);
+        reloaded.drop_transient(next_id);
+        assert_eq!(reloaded.next_due_ms(), Some(1_100));
+        reloaded.drop_transient(u64::MAX);
+        assert_eq!(reloaded.next_due_ms(), None);
+    }
+}
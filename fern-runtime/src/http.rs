@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use extism::{FromBytes, ToBytes};
+use extism_convert::Json;
+use serde::{Deserialize, Serialize};
+
+/// How a body is carried in JSON. Anything that isn't valid UTF-8 goes as base64
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BodyEncoding {
+    #[default]
+    #[serde(rename = "utf8")]
+    Utf8,
+    #[serde(rename = "base64")]
+    Base64,
+}
+
+/// An HTTP request routed to a guest by the node's gateway, delivered to `httpHandler`
+#[derive(Debug, Clone, Serialize, Deserialize, ToBytes)]
+#[encoding(Json)]
+pub struct HttpRequest {
+    pub method: String,
+    /// Path below the guest's prefix, always starting with `/`
+    pub path: String,
+    /// Raw query string without the `?`
+    pub query: Option<String>,
+    /// Lowercase header names, repeated headers are joined with `, `
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+    #[serde(rename = "bodyEncoding", default)]
+    pub body_encoding: BodyEncoding,
+}
+
+/// What the guest's `httpHandler` answers with
+#[derive(Debug, Clone, Serialize, Deserialize, FromBytes)]
+#[encoding(Json)]
+pub struct HttpResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    #[serde(rename = "bodyEncoding", default)]
+    pub body_encoding: BodyEncoding,
+}
+
+impl HttpRequest {
+    pub fn new(
+        method: String,
+        path: String,
+        query: Option<String>,
+        headers: BTreeMap<String, String>,
+        body: Vec<u8>,
+    ) -> Self {
+        let (body, body_encoding) = encode_body(body);
+        Self {
+            method,
+            path,
+            query,
+            headers,
+            body,
+            body_encoding,
+        }
+    }
+}
+
+impl HttpResponse {
+    /// A plain text response from the host rather than the guest
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: BTreeMap::from([(
+                "content-type".to_string(),
+                "text/plain; charset=utf-8".to_string(),
+            )]),
+            body: body.into(),
+            body_encoding: BodyEncoding::Utf8,
+        }
+    }
+
+    pub fn body_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        match self.body_encoding {
+            BodyEncoding::Utf8 => Ok(self.body.clone().into_bytes()),
+            BodyEncoding::Base64 => Ok(STANDARD.decode(&self.body)?),
+        }
+    }
+}
+
+fn encode_body(body: Vec<u8>) -> (String, BodyEncoding) {
+    match String::from_utf8(body) {
+        Ok(body) => (body, BodyEncoding::Utf8),
+        Err(e) => (STANDARD.encode(e.as_bytes()), BodyEncoding::Base64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_bodies() {
+        let request = HttpRequest::new(
+            "POST".to_string(),
+            "/".to_string(),
+            None,
+            BTreeMap::new(),
+            b"hello".to_vec(),
+        );
+        assert_eq!(request.body, "hello");
+        assert_eq!(request.body_encoding, BodyEncoding::Utf8);
+
+        let request = HttpRequest::new(
+            "POST".to_string(),
+            "/".to_string(),
+            None,
+            BTreeMap::new(),
+            vec![0xff, 0x00],
+        );
+        assert_eq!(request.body_encoding, BodyEncoding::Base64);
+
+        // What a guest sends back
+        let response: HttpResponse =
+            serde_json::from_str(r#"{"status": 200, "body": "/wA=", "bodyEncoding": "base64"}"#)
+                .unwrap();
+        assert_eq!(response.body_bytes().unwrap(), vec![0xff, 0x00]);
+        assert!(response.headers.is_empty());
+    }
+}
@@ -1,4 +1,8 @@
+pub mod capabilities;
 pub mod gossip;
 pub mod guest;
 pub mod guest_fns;
+pub mod http;
 pub mod iroh_helpers;
+pub mod limits;
+pub mod validation;
@@ -0,0 +1,163 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use extism::{CurrentPlugin, Manifest, PluginBuilder, UserData, Val};
+use serde::{Deserialize, Serialize};
+
+/// Resource limits applied to a guest's plugin. Anything left unset is unlimited
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestLimits {
+    /// Max linear memory in 64KiB wasm pages
+    #[serde(default)]
+    pub max_memory_pages: Option<u32>,
+    /// Fuel (roughly wasm instructions) available to each export call
+    #[serde(default)]
+    pub fuel_per_call: Option<u64>,
+    /// Wall clock limit on each export call
+    #[serde(default)]
+    pub call_timeout_ms: Option<u64>,
+    /// Host function calls allowed per second. Bursts of up to a second's worth are allowed
+    #[serde(default)]
+    pub max_host_calls_per_sec: Option<u32>,
+}
+
+impl GuestLimits {
+    /// Fill any unset limit from `defaults`
+    pub fn or(&self, defaults: &GuestLimits) -> GuestLimits {
+        GuestLimits {
+            max_memory_pages: self.max_memory_pages.or(defaults.max_memory_pages),
+            fuel_per_call: self.fuel_per_call.or(defaults.fuel_per_call),
+            call_timeout_ms: self.call_timeout_ms.or(defaults.call_timeout_ms),
+            max_host_calls_per_sec: self
+                .max_host_calls_per_sec
+                .or(defaults.max_host_calls_per_sec),
+        }
+    }
+
+    pub fn apply_to_manifest(&self, mut manifest: Manifest) -> Manifest {
+        if let Some(pages) = self.max_memory_pages {
+            manifest = manifest.with_memory_max(pages);
+        }
+        if let Some(timeout_ms) = self.call_timeout_ms {
+            manifest = manifest.with_timeout(Duration::from_millis(timeout_ms));
+        }
+        manifest
+    }
+
+    pub fn apply_to_builder(&self, mut builder: PluginBuilder) -> PluginBuilder {
+        if let Some(fuel) = self.fuel_per_call {
+            builder = builder.with_fuel_limit(fuel);
+        }
+        builder
+    }
+}
+
+/// Token bucket shared by every host function of a single plugin
+#[derive(Clone, Default)]
+pub struct HostCallLimiter {
+    bucket: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+struct TokenBucket {
+    per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl HostCallLimiter {
+    pub fn new(limits: &GuestLimits) -> Self {
+        let bucket = limits.max_host_calls_per_sec.map(|per_sec| {
+            Arc::new(Mutex::new(TokenBucket {
+                per_sec: per_sec as f64,
+                tokens: per_sec as f64,
+                last_refill: Instant::now(),
+            }))
+        });
+        Self { bucket }
+    }
+
+    /// Take a token for a call to `name`. Errors (trapping the guest) once the budget is spent
+    pub fn check(&self, name: &str) -> Result<(), extism::Error> {
+        let Some(bucket) = &self.bucket else {
+            return Ok(());
+        };
+        let mut bucket = bucket.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.per_sec).min(bucket.per_sec);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(extism::Error::msg(format!(
+                "host call rate limit exceeded calling {name}"
+            )));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Wrap a host function so every call is charged against the limiter
+    pub fn limit<T, F>(
+        &self,
+        name: &'static str,
+        f: F,
+    ) -> impl Fn(&mut CurrentPlugin, &[Val], &mut [Val], UserData<T>) -> Result<(), extism::Error>
+    + Send
+    + Sync
+    + 'static
+    where
+        T: 'static,
+        F: Fn(&mut CurrentPlugin, &[Val], &mut [Val], UserData<T>) -> Result<(), extism::Error>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let limiter = self.clone();
+        move |plugin, inputs, outputs, user_data| {
+            limiter.check(name)?;
+            f(plugin, inputs, outputs, user_data)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_fallback_and_rate() {
+        let defaults = GuestLimits {
+            max_memory_pages: Some(256),
+            fuel_per_call: Some(1_000_000),
+            call_timeout_ms: Some(5_000),
+            max_host_calls_per_sec: None,
+        };
+        let limits = GuestLimits {
+            call_timeout_ms: Some(100),
+            max_host_calls_per_sec: Some(2),
+            ..Default::default()
+        }
+        .or(&defaults);
+
+        assert_eq!(limits.max_memory_pages, Some(256));
+        assert_eq!(limits.fuel_per_call, Some(1_000_000));
+        assert_eq!(limits.call_timeout_ms, Some(100));
+
+        // No rate configured never limits
+        let unlimited = HostCallLimiter::new(&defaults);
+        for _ in 0..1000 {
+            assert!(unlimited.check("kv_read").is_ok());
+        }
+
+        let limiter = HostCallLimiter::new(&limits);
+        assert!(limiter.check("kv_read").is_ok());
+        assert!(limiter.check("kv_read").is_ok());
+        assert!(limiter.check("kv_read").is_err());
+
+        // Clones share the same budget
+        assert!(limiter.clone().check("kv_store").is_err());
+    }
+}
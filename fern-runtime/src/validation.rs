@@ -0,0 +1,375 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use wasmparser::{CompositeInnerType, ExternalKind, FuncType, Payload, TypeRef, ValType};
+
+use crate::{
+    capabilities::{Capability, CapabilityManifest, HOST_IMPORT_MODULE, resolve_capabilities},
+    guest::{
+        GuestExport, KV_CHANGED_FN, MESSAGE_FN, RPC_FN, SCHEDULED_TASK_FN, TCP_ACCEPTED_FN,
+        TIMER_FIRED_FN,
+    },
+};
+
+// Import modules provided to every guest besides Fern's own host functions
+const EXTISM_KERNEL_MODULE: &str = "extism:host/env";
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+/// Exports only called when the guest is granted the capability that delivers to them
+const CAPABILITY_EXPORTS: [(Capability, &str); 3] = [
+    (Capability::Gossip, MESSAGE_FN),
+    (Capability::Rpc, RPC_FN),
+    (Capability::Tcp, TCP_ACCEPTED_FN),
+];
+
+// Host functions whose results are delivered to an export. Their capabilities also
+// cover host functions which don't need one, so only guests using these need the export
+const HOST_FUNCTION_EXPORTS: [(&str, &str); 3] = [
+    ("schedule_create", SCHEDULED_TASK_FN),
+    ("timer_set", TIMER_FIRED_FN),
+    ("kv_watch", KV_CHANGED_FN),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IssueKind {
+    /// Not a valid wasm module
+    InvalidModule,
+    /// Imports something Fern doesn't provide
+    UnknownImport,
+    /// Doesn't export something Fern would deliver to it
+    MissingExport,
+    /// An export Fern calls doesn't have the `() -> i32` signature Extism expects
+    ExportSignature,
+    /// The capability manifest doesn't cover the imports or isn't allowed on the node
+    Capabilities,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationIssue {
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+/// What was found checking a module against the host functions and exports Fern expects
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub module_hash: String,
+    pub size: usize,
+    /// Functions the module exports
+    pub exports: Vec<String>,
+    /// Fern host functions the module imports
+    pub host_imports: Vec<String>,
+    /// Capabilities the module would be granted, empty when they couldn't be resolved
+    pub capabilities: BTreeSet<Capability>,
+    /// Anything which stops the module from being deployed
+    pub errors: Vec<ValidationIssue>,
+    /// Things which will fail at runtime without stopping the deploy
+    pub warnings: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// All errors on one line, for APIs which only report a message
+    pub fn error_message(&self) -> String {
+        self.errors
+            .iter()
+            .map(|issue| issue.message.as_str())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn error(&mut self, kind: IssueKind, message: String) {
+        self.errors.push(ValidationIssue { kind, message });
+    }
+
+    fn warning(&mut self, kind: IssueKind, message: String) {
+        self.warnings.push(ValidationIssue { kind, message });
+    }
+}
+
+// The parts of the module validation looks at
+#[derive(Default)]
+struct ModuleInfo {
+    imports: Vec<(String, String)>,
+    // (name, signature) of every exported function
+    exports: Vec<(String, Option<FuncType>)>,
+}
+
+fn module_info(module: &[u8]) -> wasmparser::Result<ModuleInfo> {
+    let mut types = Vec::new();
+    // Type of every function, imported functions come first in the index space
+    let mut functions = Vec::new();
+    let mut info = ModuleInfo::default();
+    let mut exports = Vec::new();
+
+    for payload in wasmparser::Parser::new(0).parse_all(module) {
+        match payload? {
+            Payload::TypeSection(reader) => {
+                for rec_group in reader {
+                    for sub_type in rec_group?.into_types() {
+                        types.push(match sub_type.composite_type.inner {
+                            CompositeInnerType::Func(func) => Some(func),
+                            _ => None,
+                        });
+                    }
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import?;
+                    if let TypeRef::Func(type_index) = import.ty {
+                        functions.push(type_index);
+                    }
+                    info.imports
+                        .push((import.module.to_string(), import.name.to_string()));
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for type_index in reader {
+                    functions.push(type_index?);
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.kind == ExternalKind::Func {
+                        exports.push((export.name.to_string(), export.index));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    info.exports = exports
+        .into_iter()
+        .map(|(name, index)| {
+            let func = functions
+                .get(index as usize)
+                .and_then(|type_index| types.get(*type_index as usize).cloned().flatten());
+            (name, func)
+        })
+        .collect();
+    Ok(info)
+}
+
+/// Check a module before it's deployed. The capability arguments are the same
+/// as [`resolve_capabilities`]
+pub fn validate_module(
+    module: &[u8],
+    submitted: Option<&CapabilityManifest>,
+    allowed: Option<&BTreeSet<Capability>>,
+) -> ValidationReport {
+    let mut report = ValidationReport {
+        module_hash: blake3::hash(module).to_string(),
+        size: module.len(),
+        ..Default::default()
+    };
+
+    if let Err(e) = wasmparser::Validator::new().validate_all(module) {
+        report.error(
+            IssueKind::InvalidModule,
+            format!("invalid wasm module: {e}"),
+        );
+        return report;
+    }
+    let info = match module_info(module) {
+        Ok(info) => info,
+        Err(e) => {
+            report.error(
+                IssueKind::InvalidModule,
+                format!("invalid wasm module: {e}"),
+            );
+            return report;
+        }
+    };
+
+    let mut unknown_imports = false;
+    for (import_module, name) in &info.imports {
+        match import_module.as_str() {
+            HOST_IMPORT_MODULE if Capability::for_host_function(name).is_some() => {
+                report.host_imports.push(name.clone());
+            }
+            HOST_IMPORT_MODULE => {
+                unknown_imports = true;
+                report.error(
+                    IssueKind::UnknownImport,
+                    format!("imports unknown host function {name}"),
+                );
+            }
+            EXTISM_KERNEL_MODULE | WASI_MODULE => {}
+            _ => {
+                unknown_imports = true;
+                report.error(
+                    IssueKind::UnknownImport,
+                    format!("imports {name} from {import_module} which Fern doesn't provide"),
+                );
+            }
+        }
+    }
+
+    // Unknown imports already explain why capabilities can't be worked out
+    if !unknown_imports {
+        match resolve_capabilities(module, submitted, allowed) {
+            Ok(capabilities) => report.capabilities = capabilities,
+            Err(e) => report.error(IssueKind::Capabilities, e.to_string()),
+        }
+    }
+
+    report.exports = info.exports.iter().map(|(name, _)| name.clone()).collect();
+    let export = |name: &str| info.exports.iter().find(|(export, _)| export == name);
+
+    // Every export is optional, but a module without any is never called
+    if GuestExport::ALL
+        .iter()
+        .all(|guest_export| export(guest_export.function_name()).is_none())
+    {
+        report.warning(
+            IssueKind::MissingExport,
+            "doesn't export any function Fern calls, it will never run".to_string(),
+        );
+    }
+
+    for (capability, name) in CAPABILITY_EXPORTS {
+        if report.capabilities.contains(&capability) && export(name).is_none() {
+            report.warning(
+                IssueKind::MissingExport,
+                format!(
+                    "granted {capability:?} but doesn't export {name}, deliveries to it will be dropped"
+                ),
+            );
+        }
+    }
+
+    for (host_function, name) in HOST_FUNCTION_EXPORTS {
+        if report.host_imports.iter().any(|import| import == host_function)
+            && export(name).is_none()
+        {
+            report.warning(
+                IssueKind::MissingExport,
+                format!("imports {host_function} but doesn't export {name}, deliveries to it will be dropped"),
+            );
+        }
+    }
+
+    for guest_export in GuestExport::ALL {
+        let name = guest_export.function_name();
+        let Some((_, func)) = export(name) else {
+            continue;
+        };
+        let is_extism_export = func
+            .as_ref()
+            .is_some_and(|func| func.params().is_empty() && func.results() == [ValType::I32]);
+        if !is_extism_export {
+            report.error(
+                IssueKind::ExportSignature,
+                format!("export {name} should take no parameters and return i32"),
+            );
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest::{INIT_FN, SHUTDOWN_FN, TICK_FN};
+
+    // Hand assembled module with `() -> i32` exports and `() -> ()` host imports
+    fn module(imports: &[(&str, &str)], exports: &[&str]) -> Vec<u8> {
+        fn section(wasm: &mut Vec<u8>, id: u8, body: Vec<u8>) {
+            wasm.push(id);
+            wasm.push(body.len() as u8);
+            wasm.extend(body);
+        }
+        fn name(body: &mut Vec<u8>, name: &str) {
+            body.push(name.len() as u8);
+            body.extend(name.as_bytes());
+        }
+
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+
+        // type 0: () -> (), type 1: () -> i32
+        section(
+            &mut wasm,
+            0x01,
+            vec![0x02, 0x60, 0x00, 0x00, 0x60, 0x00, 0x01, 0x7f],
+        );
+
+        let mut body = vec![imports.len() as u8];
+        for (module, field) in imports {
+            name(&mut body, module);
+            name(&mut body, field);
+            body.extend([0x00, 0x00]);
+        }
+        section(&mut wasm, 0x02, body);
+
+        let mut body = vec![exports.len() as u8];
+        body.extend(exports.iter().map(|_| 0x01));
+        section(&mut wasm, 0x03, body);
+
+        let mut body = vec![exports.len() as u8];
+        for (i, export) in exports.iter().enumerate() {
+            name(&mut body, export);
+            body.extend([0x00, (imports.len() + i) as u8]);
+        }
+        section(&mut wasm, 0x07, body);
+
+        // Each body just returns 0
+        let mut body = vec![exports.len() as u8];
+        for _ in exports {
+            body.extend([0x04, 0x00, 0x41, 0x00, 0x0b]);
+        }
+        section(&mut wasm, 0x0a, body);
+        wasm
+    }
+
+    #[test]
+    fn module_validation() {
+        let valid = module(
+            &[
+                (HOST_IMPORT_MODULE, "kv_read"),
+                (HOST_IMPORT_MODULE, "broadcast_msg"),
+            ],
+            &[INIT_FN, TICK_FN, SHUTDOWN_FN],
+        );
+        let report = validate_module(&valid, None, None);
+        assert!(report.is_valid(), "{:?}", report.errors);
+        assert_eq!(report.host_imports, vec!["kv_read", "broadcast_msg"]);
+        assert_eq!(
+            report.capabilities,
+            BTreeSet::from([Capability::Kv, Capability::Gossip])
+        );
+        // Gossip is granted without a handler for it
+        assert_eq!(report.warnings.len(), 1);
+
+        // Node policy
+        let allowed = BTreeSet::from([Capability::Kv]);
+        let report = validate_module(&valid, None, Some(&allowed));
+        assert_eq!(report.errors[0].kind, IssueKind::Capabilities);
+
+        let report = validate_module(
+            &module(
+                &[("env", "abort"), (HOST_IMPORT_MODULE, "nope")],
+                &[INIT_FN],
+            ),
+            None,
+            None,
+        );
+        let kinds: Vec<_> = report.errors.iter().map(|issue| issue.kind).collect();
+        assert_eq!(kinds, vec![IssueKind::UnknownImport, IssueKind::UnknownImport]);
+
+        // Exports are optional, a module without any is still deployable
+        let report = validate_module(&module(&[], &[]), None, None);
+        assert!(report.is_valid(), "{:?}", report.errors);
+        assert_eq!(report.warnings[0].kind, IssueKind::MissingExport);
+
+        let report = validate_module(b"not wasm", None, None);
+        assert_eq!(report.errors[0].kind, IssueKind::InvalidModule);
+    }
+}
@@ -1,21 +1,60 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
 use axum::{
     Json, Router,
-    extract::{Path, State},
+    extract::{DefaultBodyLimit, Path, State},
     http::StatusCode,
+    middleware,
     response::{IntoResponse, Response},
     routing::{delete, get, post, put},
 };
+use axum_server::tls_rustls::RustlsConfig;
+use fern_runtime::validation::ValidationReport;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     Server,
-    server::{CreateResponse, GuestInfo, UpdateResponse, RemoveResponse},
+    server::{CreateResponse, DeployResponse, GuestInfo, ModuleHistoryEntry, ModuleOptions, RollbackTarget, UpdateResponse, RemoveResponse},
 };
 
+pub mod auth;
+pub use auth::{ApiAuth, ApiAuthConfig, ApiScope};
+
 pub mod client;
 pub use client::*;
 
-pub async fn api_server(server: Server) {
+pub mod admin_client;
+pub use admin_client::*;
+
+pub mod upload;
+pub use upload::{DEFAULT_MAX_MODULE_SIZE, ModuleUpload};
+
+pub const DEFAULT_API_LISTEN: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), 3000);
+
+/// PEM encoded certificate chain and private key for serving the API over https
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Where the management API is served
+#[derive(Debug, Clone)]
+pub struct ApiListen {
+    pub addr: SocketAddr,
+    pub tls: Option<ApiTlsConfig>,
+    /// Also serve (without TLS) on a unix socket
+    pub unix_socket: Option<PathBuf>,
+    /// Largest module accepted by the upload routes
+    pub max_module_size: usize,
+}
+
+pub async fn api_server(server: Server, auth: ApiAuth, listen: ApiListen) -> anyhow::Result<()> {
+    if !auth.is_enabled() {
+        log::warn!("API authentication is not configured, anyone who can reach the API can manage guests");
+    }
+
     let app = Router::new()
         .route(
             "/api/guest",
@@ -23,36 +62,144 @@ pub async fn api_server(server: Server) {
         )
         // {name} is how we define path params not :name
         .route("/api/guest/{name}", delete(remove_module))
+        // Raw application/wasm or multipart uploads, avoids sending modules as JSON arrays
+        .route(
+            "/api/guest/{name}/module",
+            post(upload_create_module)
+                .put(upload_update_module)
+                .layer(DefaultBodyLimit::max(listen.max_module_size)),
+        )
+        .route(
+            "/api/guest/{name}/deploy",
+            post(upload_deploy_module).layer(DefaultBodyLimit::max(listen.max_module_size)),
+        )
+        .route("/api/guest/{name}/history", get(module_history))
+        .route("/api/guest/{name}/rollback", post(rollback_module))
+        .route("/api/deploy", post(deploy_module))
+        .route(
+            "/api/validate",
+            post(validate_module).layer(DefaultBodyLimit::max(listen.max_module_size)),
+        )
+        .layer(middleware::from_fn_with_state(Arc::new(auth), auth::require_auth))
         .with_state(server);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let unix = async {
+        let Some(path) = &listen.unix_socket else {
+            return anyhow::Ok(());
+        };
+        // Left behind by a previous run
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        log::info!("API listening on unix socket {}", path.display());
+        axum::serve(listener, app.clone()).await?;
+        Ok(())
+    };
+
+    let tcp = async {
+        match &listen.tls {
+            Some(tls) => {
+                // iroh brings in its own rustls provider so pick one explicitly
+                let _ = rustls::crypto::ring::default_provider().install_default();
+                let config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path).await?;
+                log::info!("API listening on https://{}", listen.addr);
+                axum_server::bind_rustls(listen.addr, config)
+                    .serve(app.clone().into_make_service())
+                    .await?;
+            }
+            None => {
+                let listener = tokio::net::TcpListener::bind(listen.addr).await?;
+                log::info!("API listening on http://{}", listen.addr);
+                axum::serve(listener, app.clone()).await?;
+            }
+        }
+        anyhow::Ok(())
+    };
+
+    tokio::try_join!(tcp, unix)?;
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateModule {
     guest_name: String,
     module: Vec<u8>,
+    #[serde(flatten)]
+    options: ModuleOptions,
 }
 
 async fn create_module(
     State(server): State<Server>,
-    Json(CreateModule { guest_name, module }): Json<CreateModule>,
+    Json(CreateModule { guest_name, module, options }): Json<CreateModule>,
 ) -> Result<Json<CreateResponse>, AppError> {
-    Ok(Json(server.create_module(guest_name, module).await?))
+    Ok(Json(server.create_module(guest_name, module, options).await?))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UpdateModule {
     guest_name: String,
     module: Vec<u8>,
+    #[serde(flatten)]
+    options: ModuleOptions,
 }
 
 async fn update_module(
     State(server): State<Server>,
-    Json(UpdateModule { guest_name, module }): Json<UpdateModule>,
+    Json(UpdateModule { guest_name, module, options }): Json<UpdateModule>,
+) -> Result<Json<UpdateResponse>, AppError> {
+    Ok(Json(server.update_module(guest_name, module, options).await?))
+}
+
+async fn upload_create_module(
+    State(server): State<Server>,
+    Path(name): Path<String>,
+    ModuleUpload { module, options }: ModuleUpload,
+) -> Result<Json<CreateResponse>, AppError> {
+    Ok(Json(server.create_module(name, module, options).await?))
+}
+
+async fn upload_update_module(
+    State(server): State<Server>,
+    Path(name): Path<String>,
+    ModuleUpload { module, options }: ModuleUpload,
 ) -> Result<Json<UpdateResponse>, AppError> {
-    Ok(Json(server.update_module(guest_name, module).await?))
+    Ok(Json(server.update_module(name, module, options).await?))
+}
+
+/// Same checks as a deploy, without deploying. Takes the same bodies as the upload routes
+async fn validate_module(
+    State(server): State<Server>,
+    ModuleUpload { module, options }: ModuleUpload,
+) -> Result<Json<ValidationReport>, AppError> {
+    Ok(Json(server.validate_module(module, options.capabilities).await?))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployModule {
+    guest_name: String,
+    module: Vec<u8>,
+    #[serde(flatten)]
+    options: ModuleOptions,
+    /// Announce the deployment so trusting cluster peers run it too
+    #[serde(default)]
+    cluster: bool,
+}
+
+async fn deploy_module(
+    State(server): State<Server>,
+    Json(DeployModule { guest_name, module, options, cluster }): Json<DeployModule>,
+) -> Result<Json<DeployResponse>, AppError> {
+    Ok(Json(server.deploy_module(guest_name, module, options, cluster).await?))
+}
+
+async fn upload_deploy_module(
+    State(server): State<Server>,
+    Path(name): Path<String>,
+    ModuleUpload { module, options }: ModuleUpload<DeployOptions>,
+) -> Result<Json<DeployResponse>, AppError> {
+    let DeployOptions { options, cluster } = options;
+    Ok(Json(server.deploy_module(name, module, options, cluster).await?))
 }
 
 async fn list_guests(State(server): State<Server>) -> Result<Json<Vec<GuestInfo>>, AppError> {
@@ -66,6 +213,28 @@ async fn remove_module(
     Ok(Json(server.remove_module(name).await?))
 }
 
+async fn module_history(
+    State(server): State<Server>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<ModuleHistoryEntry>>, AppError> {
+    Ok(Json(server.module_history(name).await?))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackModule {
+    /// History id or module hash, defaults to the previous module
+    #[serde(default)]
+    to: Option<RollbackTarget>,
+}
+
+async fn rollback_module(
+    State(server): State<Server>,
+    Path(name): Path<String>,
+    Json(RollbackModule { to }): Json<RollbackModule>,
+) -> Result<Json<UpdateResponse>, AppError> {
+    Ok(Json(server.rollback_module(name, to).await?))
+}
+
 // Make our own error that wraps `anyhow::Error`.
 struct AppError(anyhow::Error);
 
@@ -0,0 +1,251 @@
+use anyhow::{Result, anyhow};
+use iroh::{Endpoint, EndpointId, SecretKey, discovery::dns::DnsDiscovery};
+use fern_runtime::{capabilities::CapabilityManifest, validation::ValidationReport};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    FernApiClient,
+    server::{
+        CreateResponse, DeployResponse, GuestInfo, ModuleHistoryEntry, ModuleOptions, RemoveResponse,
+        RollbackTarget, UpdateResponse,
+        admin::{ADMIN_ALPN, AdminRequest, AdminResponse, MAX_ADMIN_MESSAGE_SIZE},
+    },
+};
+
+/// Manages a node over its fern/admin ALPN rather than HTTP, so the node
+/// doesn't need an open port. The node must allow-list this client's endpoint id.
+#[derive(Debug, Clone)]
+pub struct FernAdminClient {
+    endpoint: Endpoint,
+    node: EndpointId,
+}
+
+impl FernAdminClient {
+    /// Bind an endpoint with `secret` as its identity for talking to `node`
+    pub async fn connect(secret: SecretKey, node: EndpointId) -> Result<Self> {
+        let endpoint = Endpoint::builder()
+            .discovery(DnsDiscovery::n0_dns())
+            .secret_key(secret)
+            .bind()
+            .await?;
+
+        Ok(Self { endpoint, node })
+    }
+
+    /// Use an existing endpoint, e.g. from another Fern node
+    pub fn with_endpoint(endpoint: Endpoint, node: EndpointId) -> Self {
+        Self { endpoint, node }
+    }
+
+    async fn request<T: DeserializeOwned>(&self, request: AdminRequest) -> Result<T> {
+        let connection = self.endpoint.connect(self.node, ADMIN_ALPN).await?;
+        let (mut send, mut recv) = connection.open_bi().await?;
+
+        send.write_all(&request.encode()?).await?;
+        send.finish()?;
+
+        let bytes = recv.read_to_end(MAX_ADMIN_MESSAGE_SIZE).await?;
+        connection.close(0u32.into(), b"done");
+
+        match serde_json::from_slice(&bytes)? {
+            AdminResponse::Ok(value) => Ok(serde_json::from_value(value)
+                .map_err(|e| anyhow!("Failed to parse response: {}", e))?),
+            AdminResponse::Error(message) => Err(anyhow!("Admin request failed: {}", message)),
+        }
+    }
+
+    pub async fn list_guests(&self) -> Result<Vec<GuestInfo>> {
+        self.request(AdminRequest::ListGuests).await
+    }
+
+    pub async fn create_guest_with_options(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+    ) -> Result<CreateResponse> {
+        self.request(AdminRequest::CreateModule {
+            guest_name,
+            module,
+            options,
+        })
+        .await
+    }
+
+    pub async fn update_guest_with_options(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+    ) -> Result<UpdateResponse> {
+        self.request(AdminRequest::UpdateModule {
+            guest_name,
+            module,
+            options,
+        })
+        .await
+    }
+
+    pub async fn deploy_guest(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+        cluster: bool,
+    ) -> Result<DeployResponse> {
+        self.request(AdminRequest::DeployModule {
+            guest_name,
+            module,
+            options,
+            cluster,
+        })
+        .await
+    }
+
+    pub async fn validate_module(
+        &self,
+        module: Vec<u8>,
+        capabilities: Option<CapabilityManifest>,
+    ) -> Result<ValidationReport> {
+        self.request(AdminRequest::ValidateModule {
+            module,
+            capabilities,
+        })
+        .await
+    }
+
+    pub async fn remove_guest(&self, guest_name: String) -> Result<RemoveResponse> {
+        self.request(AdminRequest::RemoveModule { guest_name }).await
+    }
+
+    pub async fn module_history(&self, guest_name: &str) -> Result<Vec<ModuleHistoryEntry>> {
+        self.request(AdminRequest::ModuleHistory {
+            guest_name: guest_name.to_string(),
+        })
+        .await
+    }
+
+    pub async fn rollback_guest(
+        &self,
+        guest_name: &str,
+        to: Option<RollbackTarget>,
+    ) -> Result<UpdateResponse> {
+        self.request(AdminRequest::RollbackModule {
+            guest_name: guest_name.to_string(),
+            to,
+        })
+        .await
+    }
+
+    pub async fn health_check(&self) -> bool {
+        self.list_guests().await.is_ok()
+    }
+}
+
+/// Either way of managing a node, for callers which support both
+#[derive(Debug, Clone)]
+pub enum ManagementClient {
+    Http(FernApiClient),
+    Iroh(FernAdminClient),
+}
+
+impl ManagementClient {
+    pub async fn list_guests(&self) -> Result<Vec<GuestInfo>> {
+        match self {
+            ManagementClient::Http(client) => client.list_guests().await,
+            ManagementClient::Iroh(client) => client.list_guests().await,
+        }
+    }
+
+    pub async fn create_guest_with_options(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+    ) -> Result<CreateResponse> {
+        match self {
+            ManagementClient::Http(client) => {
+                client.create_guest_with_options(guest_name, module, options).await
+            }
+            ManagementClient::Iroh(client) => {
+                client.create_guest_with_options(guest_name, module, options).await
+            }
+        }
+    }
+
+    pub async fn update_guest_with_options(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+    ) -> Result<UpdateResponse> {
+        match self {
+            ManagementClient::Http(client) => {
+                client.update_guest_with_options(guest_name, module, options).await
+            }
+            ManagementClient::Iroh(client) => {
+                client.update_guest_with_options(guest_name, module, options).await
+            }
+        }
+    }
+
+    pub async fn deploy_guest(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+        cluster: bool,
+    ) -> Result<DeployResponse> {
+        match self {
+            ManagementClient::Http(client) => {
+                client.deploy_guest(guest_name, module, options, cluster).await
+            }
+            ManagementClient::Iroh(client) => {
+                client.deploy_guest(guest_name, module, options, cluster).await
+            }
+        }
+    }
+
+    pub async fn validate_module(
+        &self,
+        module: Vec<u8>,
+        capabilities: Option<CapabilityManifest>,
+    ) -> Result<ValidationReport> {
+        match self {
+            ManagementClient::Http(client) => client.validate_module(module, capabilities).await,
+            ManagementClient::Iroh(client) => client.validate_module(module, capabilities).await,
+        }
+    }
+
+    pub async fn remove_guest(&self, guest_name: String) -> Result<RemoveResponse> {
+        match self {
+            ManagementClient::Http(client) => client.remove_guest(guest_name).await,
+            ManagementClient::Iroh(client) => client.remove_guest(guest_name).await,
+        }
+    }
+
+    pub async fn module_history(&self, guest_name: &str) -> Result<Vec<ModuleHistoryEntry>> {
+        match self {
+            ManagementClient::Http(client) => client.module_history(guest_name).await,
+            ManagementClient::Iroh(client) => client.module_history(guest_name).await,
+        }
+    }
+
+    pub async fn rollback_guest(
+        &self,
+        guest_name: &str,
+        to: Option<RollbackTarget>,
+    ) -> Result<UpdateResponse> {
+        match self {
+            ManagementClient::Http(client) => client.rollback_guest(guest_name, to).await,
+            ManagementClient::Iroh(client) => client.rollback_guest(guest_name, to).await,
+        }
+    }
+
+    pub async fn health_check(&self) -> bool {
+        match self {
+            ManagementClient::Http(client) => client.health_check().await,
+            ManagementClient::Iroh(client) => client.health_check().await,
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, Method, StatusCode, header::AUTHORIZATION},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use iroh::{PublicKey, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+
+pub const KEY_HEADER: &str = "x-fern-key";
+pub const TIMESTAMP_HEADER: &str = "x-fern-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-fern-signature";
+
+// Signed requests older (or newer) than this are rejected to limit replays
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+// Signed bodies are buffered to be hashed, modules can be fairly large
+const MAX_SIGNED_BODY: usize = 64 * 1024 * 1024;
+
+/// What a token or key is allowed to do. Each scope includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiScope {
+    /// List guests and module history
+    ReadOnly,
+    /// Create, update and roll back guests
+    Deploy,
+    /// Everything, including removing guests
+    Admin,
+}
+
+impl ApiScope {
+    /// Scope needed for a request. Reads are read-only, removals are admin, everything else deploys
+    pub fn required_for(method: &Method) -> ApiScope {
+        match *method {
+            Method::GET | Method::HEAD => ApiScope::ReadOnly,
+            Method::DELETE => ApiScope::Admin,
+            _ => ApiScope::Deploy,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// Only used for logging
+    pub name: String,
+    pub token: String,
+    pub scope: ApiScope,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedKey {
+    pub key: PublicKey,
+    pub scope: ApiScope,
+}
+
+/// API authentication. With nothing configured the API is open to anyone who can reach it
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApiAuthConfig {
+    /// Bearer tokens accepted by the API
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+    /// Keys allowed to sign requests. The node's own key is always trusted as admin
+    #[serde(default)]
+    pub trusted_keys: Vec<TrustedKey>,
+    /// Require auth even with no tokens or trusted keys, leaving only requests signed by the node's key
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Checks API requests against the configured tokens and keys
+pub struct ApiAuth {
+    enabled: bool,
+    // Tokens are compared by hash, blake3 hash equality is constant time
+    tokens: Vec<(blake3::Hash, String, ApiScope)>,
+    keys: BTreeMap<PublicKey, ApiScope>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    Unauthorized(String),
+    Forbidden(String),
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        match self {
+            AuthError::Unauthorized(reason) => (StatusCode::UNAUTHORIZED, reason).into_response(),
+            AuthError::Forbidden(reason) => (StatusCode::FORBIDDEN, reason).into_response(),
+        }
+    }
+}
+
+impl ApiAuth {
+    pub fn new(config: &ApiAuthConfig, node_key: PublicKey) -> Self {
+        let tokens = config
+            .tokens
+            .iter()
+            .map(|token| (blake3::hash(token.token.as_bytes()), token.name.clone(), token.scope))
+            .collect();
+
+        let mut keys: BTreeMap<_, _> = config
+            .trusted_keys
+            .iter()
+            .map(|trusted| (trusted.key, trusted.scope))
+            .collect();
+        keys.insert(node_key, ApiScope::Admin);
+
+        Self {
+            enabled: config.required || !config.tokens.is_empty() || !config.trusted_keys.is_empty(),
+            tokens,
+            keys,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Scope granted to a request carrying a bearer token
+    pub fn authenticate_token(&self, token: &str) -> Result<ApiScope, AuthError> {
+        let hash = blake3::hash(token.as_bytes());
+        self.tokens
+            .iter()
+            .find(|(token_hash, _, _)| *token_hash == hash)
+            .map(|(_, name, scope)| {
+                log::debug!("API request authenticated with token {name}");
+                *scope
+            })
+            .ok_or_else(|| AuthError::Unauthorized("invalid API token".to_string()))
+    }
+
+    /// Scope granted to a signed request
+    pub fn authenticate_signature(
+        &self,
+        headers: &HeaderMap,
+        method: &Method,
+        path_and_query: &str,
+        body: &[u8],
+        now: u64,
+    ) -> Result<ApiScope, AuthError> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| AuthError::Unauthorized(format!("missing {name} header")))
+        };
+        let unauthorized = |reason: &str| AuthError::Unauthorized(reason.to_string());
+
+        let key: PublicKey = header(KEY_HEADER)?
+            .parse()
+            .map_err(|_| unauthorized("invalid signing key"))?;
+        let timestamp: u64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| unauthorized("invalid timestamp"))?;
+        let signature: [u8; 64] = STANDARD
+            .decode(header(SIGNATURE_HEADER)?)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| unauthorized("invalid signature"))?;
+
+        if now.abs_diff(timestamp) > MAX_CLOCK_SKEW_SECS {
+            return Err(unauthorized("signed request has expired"));
+        }
+
+        let scope = self
+            .keys
+            .get(&key)
+            .copied()
+            .ok_or_else(|| unauthorized("signing key is not trusted"))?;
+
+        let payload = signing_payload(method.as_str(), path_and_query, timestamp, body);
+        key.verify(&payload, &Signature::from_bytes(&signature))
+            .map_err(|_| unauthorized("invalid signature"))?;
+
+        Ok(scope)
+    }
+}
+
+/// Bytes signed for a request: method, path, timestamp and a hash of the body
+pub fn signing_payload(method: &str, path_and_query: &str, timestamp: u64, body: &[u8]) -> Vec<u8> {
+    format!("{method}\n{path_and_query}\n{timestamp}\n{}", blake3::hash(body)).into_bytes()
+}
+
+/// Headers for a request signed with `secret`
+pub fn sign_request(
+    secret: &SecretKey,
+    method: &str,
+    path_and_query: &str,
+    body: &[u8],
+) -> [(&'static str, String); 3] {
+    let timestamp = unix_now();
+    let signature = secret.sign(&signing_payload(method, path_and_query, timestamp, body));
+    [
+        (KEY_HEADER, secret.public().to_string()),
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (SIGNATURE_HEADER, STANDARD.encode(signature.to_bytes())),
+    ]
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Middleware rejecting requests without a token or signature granting the scope they need
+pub async fn require_auth(
+    State(auth): State<Arc<ApiAuth>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    if !auth.is_enabled() {
+        return Ok(next.run(request).await);
+    }
+
+    let required = ApiScope::required_for(request.method());
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    let (scope, request) = if let Some(token) = bearer {
+        (auth.authenticate_token(&token)?, request)
+    } else if request.headers().contains_key(SIGNATURE_HEADER) {
+        // The body is part of the signature so it has to be read up front
+        let (parts, body) = request.into_parts();
+        let body = axum::body::to_bytes(body, MAX_SIGNED_BODY)
+            .await
+            .map_err(|e| AuthError::Unauthorized(format!("failed to read request body: {e}")))?;
+        let path_and_query = parts
+            .uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or_else(|| parts.uri.path());
+        let scope = auth.authenticate_signature(
+            &parts.headers,
+            &parts.method,
+            path_and_query,
+            &body,
+            unix_now(),
+        )?;
+        (scope, Request::from_parts(parts, body.into()))
+    } else {
+        return Err(AuthError::Unauthorized(
+            "missing bearer token or request signature".to_string(),
+        ));
+    };
+
+    if scope < required {
+        return Err(AuthError::Forbidden(format!(
+            "{scope:?} scope can't make {required:?} requests"
+        )));
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn api_auth_tokens_and_signatures() {
+        let node_secret = SecretKey::generate(&mut rand::rng());
+        let other_secret = SecretKey::generate(&mut rand::rng());
+        let config = ApiAuthConfig {
+            tokens: vec![ApiToken {
+                name: "ci".to_string(),
+                token: "deploy-token".to_string(),
+                scope: ApiScope::Deploy,
+            }],
+            ..Default::default()
+        };
+        let auth = ApiAuth::new(&config, node_secret.public());
+        assert!(auth.is_enabled());
+        assert!(!ApiAuth::new(&ApiAuthConfig::default(), node_secret.public()).is_enabled());
+
+        assert_eq!(auth.authenticate_token("deploy-token"), Ok(ApiScope::Deploy));
+        assert!(auth.authenticate_token("nope").is_err());
+
+        assert_eq!(ApiScope::required_for(&Method::GET), ApiScope::ReadOnly);
+        assert_eq!(ApiScope::required_for(&Method::PUT), ApiScope::Deploy);
+        assert_eq!(ApiScope::required_for(&Method::DELETE), ApiScope::Admin);
+        assert!(ApiScope::Deploy < ApiScope::Admin);
+
+        let signed_headers = |secret: &SecretKey, body: &[u8]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in sign_request(secret, "POST", "/api/guest", body) {
+                headers.insert(name, HeaderValue::from_str(&value).unwrap());
+            }
+            headers
+        };
+
+        // The node's own key is admin
+        let headers = signed_headers(&node_secret, b"body");
+        let now = unix_now();
+        assert_eq!(
+            auth.authenticate_signature(&headers, &Method::POST, "/api/guest", b"body", now),
+            Ok(ApiScope::Admin)
+        );
+
+        // Tampered body, different path or stale timestamp
+        assert!(
+            auth.authenticate_signature(&headers, &Method::POST, "/api/guest", b"other", now)
+                .is_err()
+        );
+        assert!(
+            auth.authenticate_signature(&headers, &Method::DELETE, "/api/guest", b"body", now)
+                .is_err()
+        );
+        assert!(
+            auth.authenticate_signature(
+                &headers,
+                &Method::POST,
+                "/api/guest",
+                b"body",
+                now + MAX_CLOCK_SKEW_SECS + 1
+            )
+            .is_err()
+        );
+
+        // Keys which aren't trusted
+        let headers = signed_headers(&other_secret, b"body");
+        assert!(
+            auth.authenticate_signature(&headers, &Method::POST, "/api/guest", b"body", now)
+                .is_err()
+        );
+    }
+}
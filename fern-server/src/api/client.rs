@@ -1,15 +1,40 @@
-use reqwest::{Client, Response};
+use iroh::SecretKey;
+use reqwest::{Client, RequestBuilder, Response, header::{AUTHORIZATION, CONTENT_TYPE}};
 use serde::{Deserialize, Serialize};
 use anyhow::{anyhow, Result};
 use iroh::EndpointId;
 
-use crate::server::{CreateResponse, GuestInfo, UpdateResponse, RemoveResponse};
+use crate::api::auth::sign_request;
+use crate::api::upload::{MODULE_HASH_HEADER, MODULE_OPTIONS_HEADER, WASM_CONTENT_TYPE};
+use fern_runtime::{capabilities::CapabilityManifest, validation::ValidationReport};
+
+use crate::server::{CreateResponse, DeployResponse, GuestInfo, ModuleHistoryEntry, ModuleOptions, RollbackTarget, UpdateResponse, RemoveResponse};
 
 /// HTTP client for interacting with the Fern API server
 #[derive(Debug, Clone)]
 pub struct FernApiClient {
     client: Client,
     base_url: String,
+    auth: Option<ClientAuth>,
+}
+
+/// How the client authenticates with the API
+#[derive(Clone)]
+pub enum ClientAuth {
+    /// Sent as a bearer token
+    Token(String),
+    /// Every request is signed with this key, usually the node's own secret
+    Signed(SecretKey),
+}
+
+// Keep credentials out of logs
+impl std::fmt::Debug for ClientAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAuth::Token(_) => f.write_str("Token(..)"),
+            ClientAuth::Signed(secret) => write!(f, "Signed({})", secret.public()),
+        }
+    }
 }
 
 /// Request payload for creating a new guest module
@@ -17,6 +42,9 @@ pub struct FernApiClient {
 pub struct CreateModuleRequest {
     pub guest_name: String,
     pub module: Vec<u8>,
+    /// Unset options fall back to the server's defaults
+    #[serde(flatten)]
+    pub options: ModuleOptions,
 }
 
 /// Request payload for updating an existing guest module
@@ -24,6 +52,35 @@ pub struct CreateModuleRequest {
 pub struct UpdateModuleRequest {
     pub guest_name: String,
     pub module: Vec<u8>,
+    /// Unset options keep the guest's current settings
+    #[serde(flatten)]
+    pub options: ModuleOptions,
+}
+
+/// Request payload for creating or updating a guest, optionally across the cluster
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployModuleRequest {
+    pub guest_name: String,
+    pub module: Vec<u8>,
+    #[serde(flatten)]
+    pub options: ModuleOptions,
+    pub cluster: bool,
+}
+
+/// Options header for module uploads to `/api/guest/{name}/deploy`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DeployOptions {
+    #[serde(flatten)]
+    pub options: ModuleOptions,
+    /// Announce the deployment so trusting cluster peers run it too
+    #[serde(default)]
+    pub cluster: bool,
+}
+
+/// Request payload for rolling a guest back to a previous module
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackModuleRequest {
+    pub to: Option<RollbackTarget>,
 }
 
 /// Error response from the API
@@ -38,6 +95,7 @@ impl FernApiClient {
         Self {
             client: Client::new(),
             base_url: base_url.into(),
+            auth: None,
         }
     }
 
@@ -51,14 +109,79 @@ impl FernApiClient {
         Self {
             client,
             base_url: base_url.into(),
+            auth: None,
         }
     }
 
+    /// Authenticate requests with a bearer token
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.auth = Some(ClientAuth::Token(token.into()));
+        self
+    }
+
+    /// Sign requests with `secret`, the server must trust its public key
+    pub fn with_signing_key(mut self, secret: SecretKey) -> Self {
+        self.auth = Some(ClientAuth::Signed(secret));
+        self
+    }
+
     /// Get the full URL for an API endpoint
     fn api_url(&self, path: &str) -> String {
         format!("{}/api{}", self.base_url, path)
     }
 
+    /// Attach credentials and send a request
+    async fn send(&self, builder: RequestBuilder) -> Result<Response> {
+        let mut request = builder
+            .build()
+            .map_err(|e| anyhow!("Failed to build request: {}", e))?;
+
+        match &self.auth {
+            Some(ClientAuth::Token(token)) => {
+                request
+                    .headers_mut()
+                    .insert(AUTHORIZATION, format!("Bearer {token}").parse()?);
+            }
+            Some(ClientAuth::Signed(secret)) => {
+                let url = request.url();
+                let path_and_query = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                let body = request
+                    .body()
+                    .and_then(|body| body.as_bytes())
+                    .unwrap_or_default()
+                    .to_vec();
+                for (name, value) in
+                    sign_request(secret, request.method().as_str(), &path_and_query, &body)
+                {
+                    request.headers_mut().insert(name, value.parse()?);
+                }
+            }
+            None => {}
+        }
+
+        self.client
+            .execute(request)
+            .await
+            .map_err(|e| anyhow!("Failed to send request: {}", e))
+    }
+
+    /// Raw module body along with its hash so the server can spot a truncated upload
+    fn upload_module(
+        &self,
+        builder: RequestBuilder,
+        module: Vec<u8>,
+        options: &impl Serialize,
+    ) -> Result<RequestBuilder> {
+        Ok(builder
+            .header(CONTENT_TYPE, WASM_CONTENT_TYPE)
+            .header(MODULE_HASH_HEADER, blake3::hash(&module).to_string())
+            .header(MODULE_OPTIONS_HEADER, serde_json::to_string(options)?)
+            .body(module))
+    }
+
     /// Handle API response and convert errors
     async fn handle_response<T: for<'de> Deserialize<'de>>(response: Response) -> Result<T> {
         if response.status().is_success() {
@@ -80,24 +203,22 @@ impl FernApiClient {
     /// # Returns
     /// 
     /// A vector of `GuestInfo` containing details about each guest including name,
-    /// endpoint ID, and module hash.
+    /// endpoint ID, module hash, and supervisor status.
     /// 
     /// # Errors
     /// 
     /// Returns an error if the request fails or the response cannot be parsed.
     pub async fn list_guests(&self) -> Result<Vec<GuestInfo>> {
-        let response = self.client
-            .get(&self.api_url("/guest"))
-            .send()
-            .await
-            .map_err(|e| anyhow!("Failed to send request: {}", e))?;
+        let request = self.client.get(&self.api_url("/guest"));
+        let response = self.send(request).await?;
 
         Self::handle_response(response).await
     }
 
     /// Create a new guest module
     /// 
-    /// Makes a POST request to `/api/guest` to create a new guest with the specified
+    /// Uploads the module as `application/wasm` with a POST request to
+    /// `/api/guest/{name}/module` to create a new guest with the specified
     /// name and module bytecode.
     /// 
     /// # Arguments
@@ -114,24 +235,44 @@ impl FernApiClient {
     /// Returns an error if the request fails, the guest name already exists,
     /// or the response cannot be parsed.
     pub async fn create_guest(&self, guest_name: String, module: Vec<u8>) -> Result<CreateResponse> {
-        let request_body = CreateModuleRequest {
-            guest_name,
-            module,
-        };
+        self.create_guest_with_options(guest_name, module, ModuleOptions::default()).await
+    }
 
-        let response = self.client
-            .post(&self.api_url("/guest"))
-            .json(&request_body)
-            .send()
-            .await
-            .map_err(|e| anyhow!("Failed to send request: {}", e))?;
+    /// Create a new guest module with its own settings
+    /// 
+    /// Same as [`Self::create_guest`] but overrides the server's default restart
+    /// policy and resource limits for this guest.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `guest_name` - The name for the new guest
+    /// * `module` - The compiled module bytecode
+    /// * `options` - Restart policy and limits, unset options use the server defaults
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an error if the request fails, the guest name already exists,
+    /// or the response cannot be parsed.
+    pub async fn create_guest_with_options(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+    ) -> Result<CreateResponse> {
+        let request = self.upload_module(
+            self.client.post(&self.api_url(&format!("/guest/{guest_name}/module"))),
+            module,
+            &options,
+        )?;
+        let response = self.send(request).await?;
 
         Self::handle_response(response).await
     }
 
     /// Update an existing guest module
     /// 
-    /// Makes a PUT request to `/api/guest` to update an existing guest's module
+    /// Uploads the module as `application/wasm` with a PUT request to
+    /// `/api/guest/{name}/module` to update an existing guest's module
     /// with new bytecode.
     /// 
     /// # Arguments
@@ -149,17 +290,92 @@ impl FernApiClient {
     /// Returns an error if the request fails, the guest doesn't exist,
     /// or the response cannot be parsed.
     pub async fn update_guest(&self, guest_name: String, module: Vec<u8>) -> Result<UpdateResponse> {
-        let request_body = UpdateModuleRequest {
-            guest_name,
+        self.update_guest_with_options(guest_name, module, ModuleOptions::default()).await
+    }
+
+    /// Update an existing guest module and its settings
+    /// 
+    /// Same as [`Self::update_guest`] but also replaces the guest's restart policy
+    /// and resource limits. Unset options keep their current values.
+    /// 
+    /// # Errors
+    /// 
+    /// Returns an error if the request fails, the guest doesn't exist,
+    /// or the response cannot be parsed.
+    pub async fn update_guest_with_options(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+    ) -> Result<UpdateResponse> {
+        let request = self.upload_module(
+            self.client.put(&self.api_url(&format!("/guest/{guest_name}/module"))),
             module,
+            &options,
+        )?;
+        let response = self.send(request).await?;
+
+        Self::handle_response(response).await
+    }
+
+    /// Check a module against the node without deploying it
+    ///
+    /// Uploads the module as `application/wasm` with a POST request to `/api/validate`.
+    /// The node applies its own capability policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `module` - The compiled module bytecode
+    /// * `capabilities` - Manifest to check instead of the one embedded in the module
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response cannot be parsed.
+    /// An invalid module is reported in the returned report, not as an error.
+    pub async fn validate_module(
+        &self,
+        module: Vec<u8>,
+        capabilities: Option<CapabilityManifest>,
+    ) -> Result<ValidationReport> {
+        let options = ModuleOptions {
+            capabilities,
+            ..Default::default()
         };
+        let request = self.upload_module(self.client.post(&self.api_url("/validate")), module, &options)?;
+        let response = self.send(request).await?;
 
-        let response = self.client
-            .put(&self.api_url("/guest"))
-            .json(&request_body)
-            .send()
-            .await
-            .map_err(|e| anyhow!("Failed to send request: {}", e))?;
+        Self::handle_response(response).await
+    }
+
+    /// Create or update a guest module
+    ///
+    /// Uploads the module as `application/wasm` with a POST request to
+    /// `/api/guest/{name}/deploy`. The guest is created if it doesn't exist
+    /// on the node yet, otherwise its module is updated.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_name` - The name of the guest
+    /// * `module` - The compiled module bytecode
+    /// * `options` - Restart policy, limits and capabilities
+    /// * `cluster` - Announce the deployment so trusting cluster peers run it too
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails or the response cannot be parsed.
+    pub async fn deploy_guest(
+        &self,
+        guest_name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+        cluster: bool,
+    ) -> Result<DeployResponse> {
+        let request = self.upload_module(
+            self.client.post(&self.api_url(&format!("/guest/{guest_name}/deploy"))),
+            module,
+            &DeployOptions { options, cluster },
+        )?;
+        let response = self.send(request).await?;
 
         Self::handle_response(response).await
     }
@@ -182,11 +398,64 @@ impl FernApiClient {
     /// Returns an error if the request fails, the guest doesn't exist,
     /// or the response cannot be parsed.
     pub async fn remove_guest(&self, guest_name: String) -> Result<RemoveResponse> {
-        let response = self.client
-            .delete(&format!("{}/api/guest/{}", self.base_url, guest_name))
-            .send()
-            .await
-            .map_err(|e| anyhow!("Failed to send request: {}", e))?;
+        let request = self.client.delete(&format!("{}/api/guest/{}", self.base_url, guest_name));
+        let response = self.send(request).await?;
+
+        Self::handle_response(response).await
+    }
+
+    /// List previously deployed modules for a guest
+    ///
+    /// Makes a GET request to `/api/guest/{name}/history`.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_name` - The name of the guest
+    ///
+    /// # Returns
+    ///
+    /// The guest's module history, newest first. The currently deployed module
+    /// is not included.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails, the guest doesn't exist,
+    /// or the response cannot be parsed.
+    pub async fn module_history(&self, guest_name: &str) -> Result<Vec<ModuleHistoryEntry>> {
+        let request = self.client.get(&self.api_url(&format!("/guest/{}/history", guest_name)));
+        let response = self.send(request).await?;
+
+        Self::handle_response(response).await
+    }
+
+    /// Roll a guest back to a previously deployed module
+    ///
+    /// Makes a POST request to `/api/guest/{name}/rollback`.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_name` - The name of the guest to roll back
+    /// * `to` - History id or module hash to roll back to, `None` for the previous module
+    ///
+    /// # Returns
+    ///
+    /// An `UpdateResponse` with the now deployed module hash and the hash it replaced.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the request fails, the guest doesn't exist,
+    /// there is no matching history entry, or the response cannot be parsed.
+    pub async fn rollback_guest(
+        &self,
+        guest_name: &str,
+        to: Option<RollbackTarget>,
+    ) -> Result<UpdateResponse> {
+        let request_body = RollbackModuleRequest { to };
+
+        let request = self.client
+            .post(&self.api_url(&format!("/guest/{}/rollback", guest_name)))
+            .json(&request_body);
+        let response = self.send(request).await?;
 
         Self::handle_response(response).await
     }
@@ -259,10 +528,31 @@ mod tests {
         assert_eq!(localhost_client.base_url, "http://localhost:3000");
     }
 
+    #[test]
+    fn test_client_auth_is_redacted() {
+        let client = FernApiClient::localhost().with_token("super-secret");
+        assert!(!format!("{client:?}").contains("super-secret"));
+    }
+
     #[test]
     fn test_api_url_generation() {
         let client = FernApiClient::new("http://localhost:3000");
         assert_eq!(client.api_url("/guest"), "http://localhost:3000/api/guest");
         assert_eq!(client.api_url("/health"), "http://localhost:3000/api/health");
     }
+
+    #[test]
+    fn test_rollback_target_parsing() {
+        assert_eq!("42".parse::<RollbackTarget>().unwrap(), RollbackTarget::HistoryId(42));
+        assert_eq!(
+            "af1349b9f5f9a1a6".parse::<RollbackTarget>().unwrap(),
+            RollbackTarget::ModuleHash("af1349b9f5f9a1a6".to_string())
+        );
+
+        // Both forms survive the trip through the rollback request body
+        let body = serde_json::to_string(&RollbackModuleRequest { to: Some(RollbackTarget::HistoryId(7)) }).unwrap();
+        assert_eq!(body, r#"{"to":7}"#);
+        let request: RollbackModuleRequest = serde_json::from_str(r#"{"to":"abc"}"#).unwrap();
+        assert_eq!(request.to, Some(RollbackTarget::ModuleHash("abc".to_string())));
+    }
 }
\ No newline at end of file
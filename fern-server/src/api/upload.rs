@@ -0,0 +1,228 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Multipart, Request},
+    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+
+use serde::de::DeserializeOwned;
+
+use crate::server::ModuleOptions;
+
+pub const WASM_CONTENT_TYPE: &str = "application/wasm";
+/// Hex blake3 hash of the module, checked against what was received
+pub const MODULE_HASH_HEADER: &str = "x-fern-module-hash";
+/// JSON encoded [`ModuleOptions`] for raw uploads, multipart uploads use an `options` part
+pub const MODULE_OPTIONS_HEADER: &str = "x-fern-module-options";
+
+// Well above any module we've seen while still bounding what gets buffered
+pub const DEFAULT_MAX_MODULE_SIZE: usize = 32 * 1024 * 1024;
+
+/// A module uploaded either as a raw `application/wasm` body or as
+/// `multipart/form-data` with a `module` file part and optional `options` part.
+/// Routes which take more than [`ModuleOptions`] pick their own options type
+#[derive(Debug)]
+pub struct ModuleUpload<O = ModuleOptions> {
+    pub module: Vec<u8>,
+    pub options: O,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    BadRequest(String),
+    /// Rejected while reading the body, e.g. it was over the size limit
+    Rejected(StatusCode, String),
+}
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        match self {
+            UploadError::BadRequest(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+            UploadError::Rejected(status, reason) => (status, reason).into_response(),
+        }
+    }
+}
+
+impl<S: Send + Sync, O: DeserializeOwned + Default + Send> FromRequest<S> for ModuleUpload<O> {
+    type Rejection = UploadError;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let headers = request.headers().clone();
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        let upload = if content_type.starts_with("multipart/form-data") {
+            let multipart = Multipart::from_request(request, state)
+                .await
+                .map_err(|e| UploadError::Rejected(e.status(), e.body_text()))?;
+            read_multipart(multipart).await?
+        } else if content_type.starts_with(WASM_CONTENT_TYPE)
+            || content_type.starts_with("application/octet-stream")
+        {
+            let module = Bytes::from_request(request, state)
+                .await
+                .map_err(|e| UploadError::Rejected(e.status(), e.body_text()))?;
+            ModuleUpload {
+                module: module.to_vec(),
+                options: options_from_headers(&headers)?,
+            }
+        } else {
+            return Err(UploadError::Rejected(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("expected {WASM_CONTENT_TYPE} or multipart/form-data"),
+            ));
+        };
+
+        if upload.module.is_empty() {
+            return Err(UploadError::BadRequest("module is empty".to_string()));
+        }
+        verify_checksum(&headers, &upload.module)?;
+
+        Ok(upload)
+    }
+}
+
+async fn read_multipart<O: DeserializeOwned + Default>(
+    mut multipart: Multipart,
+) -> Result<ModuleUpload<O>, UploadError> {
+    let mut module = None;
+    let mut options = O::default();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| UploadError::Rejected(e.status(), e.body_text()))?
+    {
+        match field.name() {
+            Some("module") => {
+                let bytes = field
+                    .bytes()
+                    .await
+                    .map_err(|e| UploadError::Rejected(e.status(), e.body_text()))?;
+                module = Some(bytes.to_vec());
+            }
+            Some("options") => {
+                let bytes = field
+                    .bytes()
+                    .await
+                    .map_err(|e| UploadError::Rejected(e.status(), e.body_text()))?;
+                options = serde_json::from_slice(&bytes)
+                    .map_err(|e| UploadError::BadRequest(format!("invalid options part: {e}")))?;
+            }
+            // Browsers send the rest of the form along too
+            _ => {}
+        }
+    }
+
+    let module =
+        module.ok_or_else(|| UploadError::BadRequest("missing module part".to_string()))?;
+    Ok(ModuleUpload { module, options })
+}
+
+fn options_from_headers<O: DeserializeOwned + Default>(
+    headers: &HeaderMap,
+) -> Result<O, UploadError> {
+    let Some(value) = headers.get(MODULE_OPTIONS_HEADER) else {
+        return Ok(O::default());
+    };
+
+    serde_json::from_slice(value.as_bytes()).map_err(|e| {
+        UploadError::BadRequest(format!("invalid {MODULE_OPTIONS_HEADER} header: {e}"))
+    })
+}
+
+fn verify_checksum(headers: &HeaderMap, module: &[u8]) -> Result<(), UploadError> {
+    let Some(value) = headers.get(MODULE_HASH_HEADER) else {
+        return Ok(());
+    };
+
+    let expected = value
+        .to_str()
+        .ok()
+        .and_then(|value| blake3::Hash::from_hex(value.trim()).ok())
+        .ok_or_else(|| {
+            UploadError::BadRequest(format!("{MODULE_HASH_HEADER} is not a blake3 hex hash"))
+        })?;
+
+    let actual = blake3::hash(module);
+    if actual != expected {
+        return Err(UploadError::BadRequest(format!(
+            "module hash {actual} does not match {MODULE_HASH_HEADER} {expected}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::DeployOptions;
+    use axum::body::Body;
+
+    fn wasm_request(module: &'static [u8], hash: Option<String>) -> Request {
+        let mut builder = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, WASM_CONTENT_TYPE)
+            .header(
+                MODULE_OPTIONS_HEADER,
+                r#"{"restart_policy":{"policy":"never"}}"#,
+            );
+        if let Some(hash) = hash {
+            builder = builder.header(MODULE_HASH_HEADER, hash);
+        }
+        builder.body(Body::from(module)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn module_upload_checksum() {
+        let module: &[u8] = b"\0asm\x01\0\0\0";
+
+        let upload = ModuleUpload::<ModuleOptions>::from_request(
+            wasm_request(module, Some(blake3::hash(module).to_string())),
+            &(),
+        )
+        .await
+        .expect("upload with matching hash should be accepted");
+        assert_eq!(upload.module, module);
+        assert!(upload.options.restart_policy.is_some());
+
+        let mismatch = ModuleUpload::<ModuleOptions>::from_request(
+            wasm_request(module, Some(blake3::hash(b"other").to_string())),
+            &(),
+        )
+        .await;
+        assert!(matches!(mismatch, Err(UploadError::BadRequest(_))));
+
+        let unsupported = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from("[]"))
+            .unwrap();
+        let unsupported = ModuleUpload::<ModuleOptions>::from_request(unsupported, &()).await;
+        assert!(matches!(
+            unsupported,
+            Err(UploadError::Rejected(StatusCode::UNSUPPORTED_MEDIA_TYPE, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn deploy_upload_options() {
+        let request = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, WASM_CONTENT_TYPE)
+            .header(
+                MODULE_OPTIONS_HEADER,
+                r#"{"restart_policy":{"policy":"never"},"cluster":true}"#,
+            )
+            .body(Body::from(&b"\0asm\x01\0\0\0"[..]))
+            .unwrap();
+
+        let upload = ModuleUpload::<DeployOptions>::from_request(request, &())
+            .await
+            .expect("deploy upload should be accepted");
+        assert!(upload.options.cluster);
+        assert!(upload.options.options.restart_policy.is_some());
+    }
+}
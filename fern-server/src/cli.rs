@@ -1,6 +1,15 @@
 use iocraft::prelude::*;
 
-use crate::GuestInfo;
+use crate::{GuestInfo, guest_instance::GuestState, server::ModuleHistoryEntry};
+
+fn state_display(state: GuestState) -> (&'static str, Color) {
+    match state {
+        GuestState::Running => ("running", Color::Green),
+        GuestState::Restarting => ("restarting", Color::Yellow),
+        GuestState::CrashLooping => ("crash-looping", Color::Red),
+        GuestState::Failed => ("failed", Color::Red),
+    }
+}
 
 #[derive(Default, Props)]
 pub struct GuestsTableProps {
@@ -14,7 +23,7 @@ pub fn GuestsTable<'a>(props: &GuestsTableProps) -> impl Into<AnyElement<'a>> {
             margin_top: 1,
             margin_bottom: 1,
             flex_direction: FlexDirection::Column,
-            width: 120,
+            width: 140,
             border_style: BorderStyle::Round,
             border_color: Color::Cyan,
         ) {
@@ -30,6 +39,10 @@ pub fn GuestsTable<'a>(props: &GuestsTableProps) -> impl Into<AnyElement<'a>> {
                 View(width: 50, justify_content: JustifyContent::Center, padding_left: 1) {
                     Text(content: "Module Hash", weight: Weight::Bold, decoration: TextDecoration::Underline)
                 }
+
+                View(width: 20, justify_content: JustifyContent::Center, padding_left: 1) {
+                    Text(content: "State", weight: Weight::Bold, decoration: TextDecoration::Underline)
+                }
             }
 
             #(props.guests.as_ref().map(|guests| guests.iter().enumerate().map(|(i, guest)| {
@@ -48,6 +61,13 @@ pub fn GuestsTable<'a>(props: &GuestsTableProps) -> impl Into<AnyElement<'a>> {
                 } else {
                     module_hash_str
                 };
+
+                let (state, state_color) = state_display(guest.status.state);
+                let state_text = if guest.status.consecutive_failures > 0 {
+                    format!("{} ({})", state, guest.status.consecutive_failures)
+                } else {
+                    state.to_string()
+                };
                 
                 element! {
                     View(background_color: if i % 2 == 0 { None } else { Some(Color::DarkGrey) }, padding_top: 0, padding_bottom: 0) {
@@ -62,6 +82,61 @@ pub fn GuestsTable<'a>(props: &GuestsTableProps) -> impl Into<AnyElement<'a>> {
                         View(width: 50, justify_content: JustifyContent::Start, padding_left: 1) {
                             Text(content: module_display)
                         }
+
+                        View(width: 20, justify_content: JustifyContent::Start, padding_left: 1) {
+                            Text(content: state_text, color: state_color)
+                        }
+                    }
+                }
+            }).collect::<Vec<_>>()).into_iter().flatten())
+        }
+    }
+}
+
+#[derive(Default, Props)]
+pub struct ModuleHistoryTableProps {
+    pub history: Option<Vec<ModuleHistoryEntry>>,
+}
+
+#[component]
+pub fn ModuleHistoryTable<'a>(props: &ModuleHistoryTableProps) -> impl Into<AnyElement<'a>> {
+    element! {
+        View(
+            margin_top: 1,
+            margin_bottom: 1,
+            flex_direction: FlexDirection::Column,
+            width: 110,
+            border_style: BorderStyle::Round,
+            border_color: Color::Cyan,
+        ) {
+            View(border_style: BorderStyle::Single, border_edges: Edges::Bottom, border_color: Color::Grey) {
+                View(width: 10, justify_content: JustifyContent::Center, padding_right: 2) {
+                    Text(content: "ID", weight: Weight::Bold, decoration: TextDecoration::Underline)
+                }
+
+                View(width: 70, justify_content: JustifyContent::Center, padding_left: 1, padding_right: 1) {
+                    Text(content: "Module Hash", weight: Weight::Bold, decoration: TextDecoration::Underline)
+                }
+
+                View(width: 30, justify_content: JustifyContent::Center, padding_left: 1) {
+                    Text(content: "Replaced At", weight: Weight::Bold, decoration: TextDecoration::Underline)
+                }
+            }
+
+            #(props.history.as_ref().map(|history| history.iter().enumerate().map(|(i, entry)| {
+                element! {
+                    View(background_color: if i % 2 == 0 { None } else { Some(Color::DarkGrey) }, padding_top: 0, padding_bottom: 0) {
+                        View(width: 10, justify_content: JustifyContent::Start, padding_right: 2) {
+                            Text(content: entry.id.to_string())
+                        }
+
+                        View(width: 70, justify_content: JustifyContent::Start, padding_left: 1, padding_right: 1) {
+                            Text(content: entry.module_hash.clone())
+                        }
+
+                        View(width: 30, justify_content: JustifyContent::Start, padding_left: 1) {
+                            Text(content: entry.created_at.format("%Y-%m-%d %H:%M:%S").to_string())
+                        }
                     }
                 }
             }).collect::<Vec<_>>()).into_iter().flatten())
@@ -1,5 +1,6 @@
-use std::path::Path;
+use std::{collections::BTreeSet, path::Path};
 
+use anyhow::anyhow;
 use rusqlite::Connection;
 
 pub mod guest_row;
@@ -8,45 +9,87 @@ pub use guest_row::GuestRow;
 pub mod module_row;
 pub use module_row::ModuleRow;
 
+pub mod blob_store;
+pub use blob_store::BlobStore;
+
 pub struct Data {
     pub(crate) conn: Connection,
+    pub(crate) blobs: BlobStore,
 }
 
 impl Data {
     pub fn new_memory() -> Self {
         let conn = Connection::open_in_memory().expect("failed to open memory db");
-        let conn = setup(conn);
+        let blobs = BlobStore::memory();
+        let conn = setup(conn, &blobs);
 
-        Self { conn }
+        Self { conn, blobs }
     }
 
-    pub fn new_path(path: &Path) -> Self {
+    /// Modules are kept as files in `blob_path` rather than in the database
+    pub fn new_path(path: &Path, blob_path: &Path) -> Self {
         let conn = Connection::open(path).expect("failed to open file db");
-        let conn = setup(conn);
-        Self { conn }
+        let blobs = BlobStore::directory(blob_path).expect("failed to open blob store");
+        let conn = setup(conn, &blobs);
+        Self { conn, blobs }
+    }
+
+    /// Bytes of the module with `module_hash`
+    pub fn module(&self, module_hash: &str) -> anyhow::Result<Vec<u8>> {
+        self.blobs
+            .get(module_hash)?
+            .ok_or_else(|| anyhow!("Module {module_hash} is missing from the blob store"))
+    }
+
+    /// Remove modules which no guest or history entry refers to.
+    /// Returns how many were removed
+    pub fn collect_garbage(&self) -> anyhow::Result<usize> {
+        let mut stmt = self.conn.prepare(
+            "SELECT module_hash FROM guests UNION SELECT module_hash FROM module_history",
+        )?;
+        let referenced = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<BTreeSet<_>>>()?;
+
+        let mut removed = 0;
+        for hash in self.blobs.hashes()? {
+            if !referenced.contains(&hash) && self.blobs.remove(&hash)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
     }
 }
 
-fn setup(conn: Connection) -> Connection {
+fn setup(conn: Connection, blobs: &BlobStore) -> Connection {
     conn.execute(
         r#"
   create table if not exists guests (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     name TEXT UNIQUE NOT NULL,
-    module BLOB NOT NULL,
-    module_hash TEXT NOT NULL
+    module_hash TEXT NOT NULL,
+    restart_policy TEXT,
+    limits TEXT,
+    capabilities TEXT
   )
   "#,
         (),
     )
     .expect("failed to create guests table");
 
+    // Databases created before restart policies, limits and capabilities existed
+    add_column_if_missing(&conn, "guests", "restart_policy", "TEXT")
+        .expect("failed to migrate guests table");
+    add_column_if_missing(&conn, "guests", "limits", "TEXT")
+        .expect("failed to migrate guests table");
+    add_column_if_missing(&conn, "guests", "capabilities", "TEXT")
+        .expect("failed to migrate guests table");
+
     conn.execute(
         r#"
   create table if not exists module_history (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     parent_id INTEGER NOT NULL,
-    module BLOB NOT NULL,
     module_hash TEXT NOT NULL,
     created_at TEXT NOT NULL,
     FOREIGN KEY (parent_id) REFERENCES guests (id)
@@ -56,5 +99,57 @@ fn setup(conn: Connection) -> Connection {
     )
     .expect("failed to create module_history table");
 
+    // Databases from before the blob store kept module bytes inline
+    move_modules_to_blobs(&conn, blobs, "guests").expect("failed to migrate guests table");
+    move_modules_to_blobs(&conn, blobs, "module_history")
+        .expect("failed to migrate module_history table");
+
     conn
 }
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    Ok(exists)
+}
+
+fn move_modules_to_blobs(conn: &Connection, blobs: &BlobStore, table: &str) -> anyhow::Result<()> {
+    if !has_column(conn, table, "module")? {
+        return Ok(());
+    }
+
+    let mut stmt = conn.prepare(&format!("SELECT id, module FROM {table}"))?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for (id, module) in rows {
+        let module_hash = blobs.put(&module)?;
+        conn.execute(
+            &format!("UPDATE {table} SET module_hash = ?1 WHERE id = ?2"),
+            (&module_hash, id),
+        )?;
+    }
+
+    conn.execute(&format!("ALTER TABLE {table} DROP COLUMN module"), ())?;
+    Ok(())
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            (),
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Module bytes keyed by their blake3 hash. Rows only store the hash so a
+/// module deployed many times, or shared by several guests, is kept once
+pub enum BlobStore {
+    Memory(RefCell<BTreeMap<String, Vec<u8>>>),
+    /// One file per blob, named by its hash
+    Directory(PathBuf),
+}
+
+impl BlobStore {
+    pub fn memory() -> Self {
+        BlobStore::Memory(RefCell::new(BTreeMap::new()))
+    }
+
+    pub fn directory(path: &Path) -> io::Result<Self> {
+        fs::create_dir_all(path)?;
+        Ok(BlobStore::Directory(path.into()))
+    }
+
+    /// Store `bytes` if they aren't already, returning their hash
+    pub fn put(&self, bytes: &[u8]) -> io::Result<String> {
+        let hash = blake3::hash(bytes).to_string();
+
+        match self {
+            BlobStore::Memory(blobs) => {
+                blobs
+                    .borrow_mut()
+                    .entry(hash.clone())
+                    .or_insert_with(|| bytes.to_vec());
+            }
+            BlobStore::Directory(dir) => {
+                let path = dir.join(&hash);
+                if !path.exists() {
+                    // Write then rename so a crash never leaves a partial blob under its hash
+                    let tmp_path = dir.join(format!("{hash}.tmp"));
+                    fs::write(&tmp_path, bytes)?;
+                    fs::rename(&tmp_path, &path)?;
+                }
+            }
+        }
+
+        Ok(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> io::Result<Option<Vec<u8>>> {
+        if !is_blob_hash(hash) {
+            return Ok(None);
+        }
+
+        match self {
+            BlobStore::Memory(blobs) => Ok(blobs.borrow().get(hash).cloned()),
+            BlobStore::Directory(dir) => match fs::read(dir.join(hash)) {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Returns true if a blob was removed
+    pub fn remove(&self, hash: &str) -> io::Result<bool> {
+        if !is_blob_hash(hash) {
+            return Ok(false);
+        }
+
+        match self {
+            BlobStore::Memory(blobs) => Ok(blobs.borrow_mut().remove(hash).is_some()),
+            BlobStore::Directory(dir) => match fs::remove_file(dir.join(hash)) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Hashes of every stored blob
+    pub fn hashes(&self) -> io::Result<Vec<String>> {
+        match self {
+            BlobStore::Memory(blobs) => Ok(blobs.borrow().keys().cloned().collect()),
+            BlobStore::Directory(dir) => {
+                let mut hashes = Vec::new();
+                for entry in fs::read_dir(dir)? {
+                    let name = entry?.file_name().to_string_lossy().into_owned();
+                    if is_blob_hash(&name) {
+                        hashes.push(name);
+                    }
+                }
+                Ok(hashes)
+            }
+        }
+    }
+}
+
+// Hashes come from requests too, so never let one name a path outside the store
+fn is_blob_hash(hash: &str) -> bool {
+    blake3::Hash::from_hex(hash).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blob_store_dedup() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+
+        for store in [
+            BlobStore::memory(),
+            BlobStore::directory(dir.path()).expect("failed to open blob dir"),
+        ] {
+            let hash = store.put(&[1, 2, 3]).expect("failed to put blob");
+            assert_eq!(hash, blake3::hash(&[1, 2, 3]).to_string());
+            assert_eq!(store.put(&[1, 2, 3]).expect("failed to put blob"), hash);
+            assert_eq!(
+                store.hashes().expect("failed to list blobs"),
+                vec![hash.clone()]
+            );
+
+            assert_eq!(
+                store.get(&hash).expect("failed to get blob"),
+                Some(vec![1, 2, 3])
+            );
+            assert_eq!(
+                store.get("../../etc/passwd").expect("failed to get blob"),
+                None
+            );
+
+            assert!(store.remove(&hash).expect("failed to remove blob"));
+            assert!(!store.remove(&hash).expect("failed to remove blob"));
+            assert_eq!(store.get(&hash).expect("failed to get blob"), None);
+        }
+    }
+}
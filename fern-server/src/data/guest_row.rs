@@ -1,41 +1,79 @@
-use crate::data::{Data, ModuleRow};
+use fern_runtime::{capabilities::CapabilityManifest, limits::GuestLimits};
+use serde::{Serialize, de::DeserializeOwned};
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+use crate::{
+    data::{Data, ModuleRow},
+    guest_instance::RestartPolicy,
+};
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct GuestRow {
     pub id: i64,
     pub name: String,
-    pub module: Vec<u8>,
+    /// The module's bytes live in the blob store, see [`Data::module`]
     pub module_hash: String,
+    /// None means the server's default policy applies
+    pub restart_policy: Option<RestartPolicy>,
+    /// Per guest overrides, unset limits fall back to the server's defaults
+    pub limits: GuestLimits,
+    /// Manifest submitted alongside the module. None uses the one embedded in the module
+    pub capabilities: Option<CapabilityManifest>,
+}
+
+// Guest settings are stored as JSON so adding fields doesn't need a migration
+fn json_from_sql<T: DeserializeOwned>(value: Option<String>) -> Option<T> {
+    value.and_then(|value| serde_json::from_str(&value).ok())
+}
+
+fn json_to_sql<T: Serialize>(value: Option<&T>) -> Option<String> {
+    value.and_then(|value| serde_json::to_string(value).ok())
 }
 
 impl GuestRow {
-    pub fn create(data: &Data, name: String, module: Vec<u8>) -> rusqlite::Result<Self> {
-        let module_hash = blake3::hash(&module).to_string();
+    pub fn create(
+        data: &Data,
+        name: String,
+        module: &[u8],
+        restart_policy: Option<RestartPolicy>,
+        limits: GuestLimits,
+        capabilities: Option<CapabilityManifest>,
+    ) -> anyhow::Result<Self> {
+        let module_hash = data.blobs.put(module)?;
 
         let conn = &data.conn;
         conn.execute(
-            "INSERT INTO guests (name, module, module_hash) VALUES (?1, ?2, ?3)",
-            (&name, &module, &module_hash),
+            "INSERT INTO guests (name, module_hash, restart_policy, limits, capabilities) VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                &name,
+                &module_hash,
+                json_to_sql(restart_policy.as_ref()),
+                json_to_sql(Some(&limits)),
+                json_to_sql(capabilities.as_ref()),
+            ),
         )?;
 
         Ok(Self {
             id: conn.last_insert_rowid(),
             name,
-            module,
             module_hash,
+            restart_policy,
+            limits,
+            capabilities,
         })
     }
 
     pub fn by_id(data: &Data, id: i64) -> rusqlite::Result<Option<GuestRow>> {
         let conn = &data.conn;
         let mut stmt =
-            conn.prepare("SELECT id, name, module, module_hash FROM guests WHERE id = ?1")?;
+            conn.prepare("SELECT id, name, module_hash, restart_policy, limits, capabilities FROM guests WHERE id = ?1")?;
         let mut rows = stmt.query_map([id], |row| {
             Ok(GuestRow {
                 id: row.get(0)?,
                 name: row.get(1)?,
-                module: row.get(2)?,
-                module_hash: row.get(3)?,
+                module_hash: row.get(2)?,
+                restart_policy: json_from_sql(row.get(3)?),
+                limits: json_from_sql(row.get(4)?).unwrap_or_default(),
+                capabilities: json_from_sql(row.get(5)?),
             })
         })?;
 
@@ -48,13 +86,15 @@ impl GuestRow {
     pub fn by_name(data: &Data, name: &str) -> rusqlite::Result<Option<GuestRow>> {
         let conn = &data.conn;
         let mut stmt =
-            conn.prepare("SELECT id, name, module, module_hash FROM guests WHERE name = ?1")?;
+            conn.prepare("SELECT id, name, module_hash, restart_policy, limits, capabilities FROM guests WHERE name = ?1")?;
         let mut rows = stmt.query_map([name], |row| {
             Ok(GuestRow {
                 id: row.get(0)?,
                 name: row.get(1)?,
-                module: row.get(2)?,
-                module_hash: row.get(3)?,
+                module_hash: row.get(2)?,
+                restart_policy: json_from_sql(row.get(3)?),
+                limits: json_from_sql(row.get(4)?).unwrap_or_default(),
+                capabilities: json_from_sql(row.get(5)?),
             })
         })?;
 
@@ -64,41 +104,80 @@ impl GuestRow {
         }
     }
 
-    pub fn update_module_by_name(data: &Data, name: &str, module: &[u8]) -> rusqlite::Result<bool> {
-        let new_module_hash = blake3::hash(module).to_string();
+    pub fn update_module_by_name(data: &Data, name: &str, module: &[u8]) -> anyhow::Result<bool> {
+        let new_module_hash = data.blobs.put(module)?;
 
         // First, get the current guest to save their old module to history
         if let Some(current_guest) = Self::by_name(data, name)? {
             // Save the current module to history before updating
-            ModuleRow::create(
-                data,
-                current_guest.id,
-                current_guest.module,
-                current_guest.module_hash,
-            )?;
+            ModuleRow::create(data, current_guest.id, current_guest.module_hash)?;
         }
 
         // Now update the guest with the new module
         let conn = &data.conn;
         let rows_affected = conn.execute(
-            "UPDATE guests SET module = ?1, module_hash = ?2 WHERE name = ?3",
-            (&module, &new_module_hash, name),
+            "UPDATE guests SET module_hash = ?1 WHERE name = ?2",
+            (&new_module_hash, name),
+        )?;
+        Ok(rows_affected == 1)
+    }
+
+    pub fn update_restart_policy_by_name(
+        data: &Data,
+        name: &str,
+        restart_policy: Option<&RestartPolicy>,
+    ) -> rusqlite::Result<bool> {
+        let conn = &data.conn;
+        let rows_affected = conn.execute(
+            "UPDATE guests SET restart_policy = ?1 WHERE name = ?2",
+            (json_to_sql(restart_policy), name),
+        )?;
+        Ok(rows_affected == 1)
+    }
+
+    pub fn update_limits_by_name(
+        data: &Data,
+        name: &str,
+        limits: &GuestLimits,
+    ) -> rusqlite::Result<bool> {
+        let conn = &data.conn;
+        let rows_affected = conn.execute(
+            "UPDATE guests SET limits = ?1 WHERE name = ?2",
+            (json_to_sql(Some(limits)), name),
+        )?;
+        Ok(rows_affected == 1)
+    }
+
+    pub fn update_capabilities_by_name(
+        data: &Data,
+        name: &str,
+        capabilities: Option<&CapabilityManifest>,
+    ) -> rusqlite::Result<bool> {
+        let conn = &data.conn;
+        let rows_affected = conn.execute(
+            "UPDATE guests SET capabilities = ?1 WHERE name = ?2",
+            (json_to_sql(capabilities), name),
         )?;
         Ok(rows_affected == 1)
     }
 
-    /// Remove a guest by ID
+    /// Remove a guest by ID along with its module history
     /// Returns true if a row was deleted, false if no row was found
     pub fn remove_by_id(data: &Data, id: i64) -> rusqlite::Result<bool> {
         let conn = &data.conn;
+        conn.execute("DELETE FROM module_history WHERE parent_id = ?1", [id])?;
         let rows_affected = conn.execute("DELETE FROM guests WHERE id = ?1", [id])?;
         Ok(rows_affected == 1)
     }
 
-    /// Remove a guest by name
+    /// Remove a guest by name along with its module history
     /// Returns true if a row was deleted, false if no row was found
     pub fn remove_by_name(data: &Data, name: &str) -> rusqlite::Result<bool> {
         let conn = &data.conn;
+        conn.execute(
+            "DELETE FROM module_history WHERE parent_id IN (SELECT id FROM guests WHERE name = ?1)",
+            [name],
+        )?;
         let rows_affected = conn.execute("DELETE FROM guests WHERE name = ?1", [name])?;
         Ok(rows_affected == 1)
     }
@@ -110,14 +189,16 @@ impl GuestRow {
     ) -> rusqlite::Result<Vec<GuestRow>> {
         let conn = &data.conn;
         let mut stmt = conn.prepare(
-            "SELECT id, name, module, module_hash FROM guests ORDER BY id LIMIT ?1 OFFSET ?2",
+            "SELECT id, name, module_hash, restart_policy, limits, capabilities FROM guests ORDER BY id LIMIT ?1 OFFSET ?2",
         )?;
         let rows = stmt.query_map([limit, offset], |row| {
             Ok(GuestRow {
                 id: row.get(0)?,
                 name: row.get(1)?,
-                module: row.get(2)?,
-                module_hash: row.get(3)?,
+                module_hash: row.get(2)?,
+                restart_policy: json_from_sql(row.get(3)?),
+                limits: json_from_sql(row.get(4)?).unwrap_or_default(),
+                capabilities: json_from_sql(row.get(5)?),
             })
         })?;
 
@@ -133,6 +214,7 @@ impl GuestRow {
 mod tests {
     use super::*;
     use crate::data::Data;
+    use fern_runtime::capabilities::Capability;
 
     #[test]
     fn sql_guest_row() {
@@ -140,7 +222,10 @@ mod tests {
         let guest = GuestRow::create(
             &data,
             "test module".to_string(),
-            uuid::Uuid::new_v4().as_bytes().into(),
+            uuid::Uuid::new_v4().as_bytes(),
+            None,
+            GuestLimits::default(),
+            None,
         )
         .expect("failed to create guest row");
 
@@ -164,12 +249,50 @@ mod tests {
             .expect("failed to execute sql")
             .expect("failed to find row");
 
-        assert_eq!(got_guest.module, new_module.as_bytes());
+        assert_eq!(
+            data.module(&got_guest.module_hash).expect("failed to load module"),
+            new_module.as_bytes()
+        );
+
+        let policy = RestartPolicy::OnFailure { max_restarts: 3 };
+        GuestRow::update_restart_policy_by_name(&data, "test module", Some(&policy))
+            .expect("failed to update restart policy");
+
+        let got_guest = GuestRow::by_name(&data, "test module")
+            .expect("failed to execute sql")
+            .expect("failed to find row");
+
+        assert_eq!(got_guest.restart_policy, Some(policy));
+
+        let limits = GuestLimits {
+            fuel_per_call: Some(10_000),
+            ..Default::default()
+        };
+        GuestRow::update_limits_by_name(&data, "test module", &limits)
+            .expect("failed to update limits");
+
+        let got_guest = GuestRow::by_name(&data, "test module")
+            .expect("failed to execute sql")
+            .expect("failed to find row");
+
+        assert_eq!(got_guest.limits, limits);
+
+        let manifest = CapabilityManifest {
+            capabilities: [Capability::Kv, Capability::Debug].into(),
+        };
+        GuestRow::update_capabilities_by_name(&data, "test module", Some(&manifest))
+            .expect("failed to update capabilities");
+
+        let got_guest = GuestRow::by_name(&data, "test module")
+            .expect("failed to execute sql")
+            .expect("failed to find row");
+
+        assert_eq!(got_guest.capabilities, Some(manifest));
 
         // Test pagination - create a few more guests first
-        GuestRow::create(&data, "guest2".to_string(), vec![1, 2, 3])
+        GuestRow::create(&data, "guest2".to_string(), &[1, 2, 3], None, GuestLimits::default(), None)
             .expect("failed to create guest2");
-        GuestRow::create(&data, "guest3".to_string(), vec![4, 5, 6])
+        GuestRow::create(&data, "guest3".to_string(), &[4, 5, 6], Some(RestartPolicy::Never), GuestLimits::default(), None)
             .expect("failed to create guest3");
 
         // Test getting all guests with pagination
@@ -211,5 +334,10 @@ mod tests {
         // Test removing non-existent guest
         let not_removed = GuestRow::remove_by_name(&data, "nonexistent").expect("failed to attempt remove");
         assert!(!not_removed, "should return false for non-existent guest");
+
+        // Only the removed guests' modules are unreferenced now
+        assert_eq!(data.collect_garbage().expect("failed to collect garbage"), 2);
+        assert!(data.module(&got_guest.module_hash).is_ok());
+        assert!(data.module(&guest.module_hash).is_ok(), "history still refers to the first module");
     }
 }
@@ -7,32 +7,26 @@ use crate::data::Data;
 pub struct ModuleRow {
     pub id: i64,
     pub parent_id: i64,
-    pub module: Vec<u8>,
+    /// The module's bytes live in the blob store, see [`Data::module`]
     pub module_hash: String,
     pub created_at: DateTime<Utc>,
 }
 
 impl ModuleRow {
-    /// Create a new module history entry
-    pub fn create(
-        data: &Data,
-        parent_id: i64,
-        module: Vec<u8>,
-        module_hash: String,
-    ) -> rusqlite::Result<Self> {
+    /// Create a new module history entry for a module already in the blob store
+    pub fn create(data: &Data, parent_id: i64, module_hash: String) -> rusqlite::Result<Self> {
         let created_at = Utc::now();
         let created_at_str = created_at.to_rfc3339();
 
         let conn = &data.conn;
         conn.execute(
-            "INSERT INTO module_history (parent_id, module, module_hash, created_at) VALUES (?1, ?2, ?3, ?4)",
-            (&parent_id, &module, &module_hash, &created_at_str),
+            "INSERT INTO module_history (parent_id, module_hash, created_at) VALUES (?1, ?2, ?3)",
+            (&parent_id, &module_hash, &created_at_str),
         )?;
 
         Ok(Self {
             id: conn.last_insert_rowid(),
             parent_id,
-            module,
             module_hash,
             created_at,
         })
@@ -42,39 +36,99 @@ impl ModuleRow {
     pub fn latest_by_guest_id(data: &Data, guest_id: i64) -> rusqlite::Result<Option<ModuleRow>> {
         let conn = &data.conn;
         let mut stmt = conn.prepare(
-            "SELECT id, parent_id, module, module_hash, created_at
+            "SELECT id, parent_id, module_hash, created_at
              FROM module_history
              WHERE parent_id = ?1
-             ORDER BY created_at DESC
+             ORDER BY created_at DESC, id DESC
              LIMIT 1",
         )?;
 
-        let mut rows = stmt.query_map([guest_id], |row| {
-            let created_at_str: String = row.get(4)?;
-            let created_at = DateTime::parse_from_rfc3339(&created_at_str)
-                .map_err(|e| {
-                    rusqlite::Error::FromSqlConversionFailure(
-                        4,
-                        rusqlite::types::Type::Text,
-                        Box::new(e),
-                    )
-                })?
-                .with_timezone(&Utc);
-
-            Ok(ModuleRow {
-                id: row.get(0)?,
-                parent_id: row.get(1)?,
-                module: row.get(2)?,
-                module_hash: row.get(3)?,
-                created_at,
-            })
-        })?;
+        let mut rows = stmt.query_map([guest_id], Self::from_row)?;
 
         match rows.next() {
             Some(row) => Ok(Some(row?)),
             None => Ok(None),
         }
     }
+
+    /// All previously deployed modules for a guest, newest first
+    pub fn all_by_guest_id(data: &Data, guest_id: i64) -> rusqlite::Result<Vec<ModuleRow>> {
+        let conn = &data.conn;
+        let mut stmt = conn.prepare(
+            "SELECT id, parent_id, module_hash, created_at
+             FROM module_history
+             WHERE parent_id = ?1
+             ORDER BY created_at DESC, id DESC",
+        )?;
+
+        let rows = stmt.query_map([guest_id], Self::from_row)?;
+
+        let mut modules = Vec::new();
+        for row in rows {
+            modules.push(row?);
+        }
+        Ok(modules)
+    }
+
+    /// Get a history entry by id, scoped to the guest it belongs to
+    pub fn by_id(data: &Data, guest_id: i64, id: i64) -> rusqlite::Result<Option<ModuleRow>> {
+        let conn = &data.conn;
+        let mut stmt = conn.prepare(
+            "SELECT id, parent_id, module_hash, created_at
+             FROM module_history
+             WHERE parent_id = ?1 AND id = ?2",
+        )?;
+
+        let mut rows = stmt.query_map([guest_id, id], Self::from_row)?;
+
+        match rows.next() {
+            Some(row) => Ok(Some(row?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the most recent history entry with the given module hash
+    pub fn latest_by_hash(
+        data: &Data,
+        guest_id: i64,
+        module_hash: &str,
+    ) -> rusqlite::Result<Option<ModuleRow>> {
+        let conn = &data.conn;
+        let mut stmt = conn.prepare(
+            "SELECT id, parent_id, module_hash, created_at
+             FROM module_history
+             WHERE parent_id = ?1 AND module_hash = ?2
+             ORDER BY created_at DESC, id DESC
+             LIMIT 1",
+        )?;
+
+        let mut rows = stmt.query_map((guest_id, module_hash), Self::from_row)?;
+
+        match rows.next() {
+            Some(row) => Ok(Some(row?)),
+            None => Ok(None),
+        }
+    }
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<ModuleRow> {
+        let created_at_str: String = row.get(3)?;
+        let created_at = DateTime::parse_from_rfc3339(&created_at_str)
+            .map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    3,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })?
+            .with_timezone(&Utc);
+
+        Ok(ModuleRow {
+            id: row.get(0)?,
+            parent_id: row.get(1)?,
+            module_hash: row.get(2)?,
+            created_at,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -87,19 +141,18 @@ mod tests {
         let data = Data::new_memory();
 
         // Create a guest first
-        let guest = GuestRow::create(&data, "test_guest".to_string(), vec![1, 2, 3, 4])
+        let guest = GuestRow::create(&data, "test_guest".to_string(), &[1, 2, 3, 4], None, Default::default(), None)
             .expect("failed to create guest");
 
         // Create a module history entry
         let module_data = vec![5, 6, 7, 8];
-        let module_hash = blake3::hash(&module_data).to_string();
+        let module_hash = data.blobs.put(&module_data).expect("failed to store module");
 
         let module_row =
-            ModuleRow::create(&data, guest.id, module_data.clone(), module_hash.clone())
+            ModuleRow::create(&data, guest.id, module_hash.clone())
                 .expect("failed to create module row");
 
         assert_eq!(module_row.parent_id, guest.id);
-        assert_eq!(module_row.module, module_data);
         assert_eq!(module_row.module_hash, module_hash);
 
         // Test getting latest module by guest id
@@ -109,14 +162,14 @@ mod tests {
 
         assert_eq!(latest.id, module_row.id);
         assert_eq!(latest.parent_id, guest.id);
-        assert_eq!(latest.module, module_data);
+        assert_eq!(data.module(&latest.module_hash).expect("failed to load module"), module_data);
 
         // Create another module entry for the same guest
         let module_data2 = vec![9, 10, 11, 12];
-        let module_hash2 = blake3::hash(&module_data2).to_string();
+        let module_hash2 = data.blobs.put(&module_data2).expect("failed to store module");
 
         let module_row2 =
-            ModuleRow::create(&data, guest.id, module_data2.clone(), module_hash2.clone())
+            ModuleRow::create(&data, guest.id, module_hash2.clone())
                 .expect("failed to create second module row");
 
         // Latest should now return the second module
@@ -125,7 +178,30 @@ mod tests {
             .expect("no module found");
 
         assert_eq!(latest.id, module_row2.id);
-        assert_eq!(latest.module, module_data2);
+        assert_eq!(latest.module_hash, module_hash2);
+
+        // History is listed newest first
+        let history = ModuleRow::all_by_guest_id(&data, guest.id).expect("failed to list history");
+        assert_eq!(
+            history.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![module_row2.id, module_row.id]
+        );
+
+        // Lookups by id and hash
+        let by_id = ModuleRow::by_id(&data, guest.id, module_row.id)
+            .expect("failed to query by id")
+            .expect("no module found");
+        assert_eq!(by_id.module_hash, module_hash);
+        assert!(
+            ModuleRow::by_id(&data, 999, module_row.id)
+                .expect("failed to query by id")
+                .is_none()
+        );
+
+        let by_hash = ModuleRow::latest_by_hash(&data, guest.id, &module_hash)
+            .expect("failed to query by hash")
+            .expect("no module found");
+        assert_eq!(by_hash.id, module_row.id);
 
         // Test with non-existent guest id
         let no_module =
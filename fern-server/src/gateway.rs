@@ -0,0 +1,192 @@
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use fern_runtime::http::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::Server;
+
+// Same bound rpc puts on a message
+pub const DEFAULT_MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+pub const DEFAULT_GATEWAY_TIMEOUT_MS: u64 = 30_000;
+
+/// Routes `/{guest_name}/...` to the named guest's `httpHandler` export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpGatewayConfig {
+    pub listen: SocketAddr,
+    /// Largest request body passed to a guest in bytes, defaults to 4MiB
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    /// How long to wait on a guest before answering 504, defaults to 30s
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+struct Gateway {
+    server: Server,
+    max_body_size: usize,
+    timeout: Duration,
+}
+
+/// Serve guests over HTTP. The gateway has no auth of its own, guests decide what to allow
+pub async fn gateway_server(server: Server, config: HttpGatewayConfig) -> anyhow::Result<()> {
+    let gateway = Gateway {
+        server,
+        max_body_size: config.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE),
+        timeout: Duration::from_millis(config.timeout_ms.unwrap_or(DEFAULT_GATEWAY_TIMEOUT_MS)),
+    };
+    // Every path is a guest's, so there is nothing to route on
+    let app = Router::new()
+        .fallback(gateway_request)
+        .with_state(Arc::new(gateway));
+
+    let listener = tokio::net::TcpListener::bind(config.listen).await?;
+    log::info!("HTTP gateway listening on http://{}", config.listen);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn gateway_request(State(gateway): State<Arc<Gateway>>, request: Request) -> Response {
+    let (parts, body) = request.into_parts();
+    let Some((guest_name, path)) = split_guest_path(parts.uri.path()) else {
+        return (StatusCode::NOT_FOUND, "no guest named in the path").into_response();
+    };
+
+    let Ok(body) = axum::body::to_bytes(body, gateway.max_body_size).await else {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("request body is over {} bytes", gateway.max_body_size),
+        )
+            .into_response();
+    };
+
+    let request = HttpRequest::new(
+        parts.method.to_string(),
+        path,
+        parts.uri.query().map(str::to_string),
+        request_headers(&parts.headers),
+        body.to_vec(),
+    );
+
+    let response = tokio::time::timeout(
+        gateway.timeout,
+        gateway.server.guest_http_request(guest_name.clone(), request),
+    )
+    .await;
+    match response {
+        Ok(Ok(Some(response))) => guest_response(response),
+        Ok(Ok(None)) => (StatusCode::NOT_FOUND, format!("no guest named {guest_name}")).into_response(),
+        Ok(Err(e)) => {
+            log::warn!("gateway request to guest {guest_name} failed {e}");
+            (StatusCode::BAD_GATEWAY, "guest failed to answer").into_response()
+        }
+        Err(_) => (StatusCode::GATEWAY_TIMEOUT, "guest took too long to answer").into_response(),
+    }
+}
+
+/// `/name/rest/of/path` into the guest name and the path the guest sees
+fn split_guest_path(path: &str) -> Option<(String, String)> {
+    let path = path.strip_prefix('/')?;
+    let (guest_name, rest) = match path.split_once('/') {
+        Some((guest_name, rest)) => (guest_name, format!("/{rest}")),
+        None => (path, "/".to_string()),
+    };
+    if guest_name.is_empty() {
+        return None;
+    }
+    Some((guest_name.to_string(), rest))
+}
+
+fn request_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut joined = BTreeMap::new();
+    for (name, value) in headers {
+        // Guests get JSON strings, drop anything which isn't text
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        joined
+            .entry(name.as_str().to_string())
+            .and_modify(|existing: &mut String| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    joined
+}
+
+fn guest_response(response: HttpResponse) -> Response {
+    let Ok(status) = StatusCode::from_u16(response.status) else {
+        return (StatusCode::BAD_GATEWAY, "guest answered with an invalid status").into_response();
+    };
+    let Ok(body) = response.body_bytes() else {
+        return (StatusCode::BAD_GATEWAY, "guest answered with an invalid body").into_response();
+    };
+
+    let mut builder = axum::http::Response::builder().status(status);
+    for (name, value) in &response.headers {
+        // Worked out from the body we actually send
+        if name.eq_ignore_ascii_case(header::CONTENT_LENGTH.as_str())
+            || name.eq_ignore_ascii_case(header::TRANSFER_ENCODING.as_str())
+        {
+            continue;
+        }
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+
+    builder.body(Body::from(body)).unwrap_or_else(|_| {
+        (StatusCode::BAD_GATEWAY, "guest answered with an invalid header").into_response()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gateway_routing() {
+        assert_eq!(
+            split_guest_path("/api/users/1"),
+            Some(("api".to_string(), "/users/1".to_string()))
+        );
+        assert_eq!(
+            split_guest_path("/api"),
+            Some(("api".to_string(), "/".to_string()))
+        );
+        assert_eq!(
+            split_guest_path("/api/"),
+            Some(("api".to_string(), "/".to_string()))
+        );
+        assert_eq!(split_guest_path("/"), None);
+
+        let mut headers = HeaderMap::new();
+        headers.append("accept", "text/html".parse().unwrap());
+        headers.append("accept", "application/json".parse().unwrap());
+        assert_eq!(
+            request_headers(&headers)["accept"],
+            "text/html, application/json"
+        );
+
+        let response = guest_response(HttpResponse {
+            status: 201,
+            headers: BTreeMap::from([
+                ("x-guest".to_string(), "hi".to_string()),
+                ("Content-Length".to_string(), "999".to_string()),
+            ]),
+            body: "created".to_string(),
+            body_encoding: Default::default(),
+        });
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["x-guest"], "hi");
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+
+        let invalid = guest_response(HttpResponse::text(1000, ""));
+        assert_eq!(invalid.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -1,14 +1,15 @@
 use std::{
+    collections::BTreeSet,
     sync::Arc,
     thread::{self, JoinHandle},
 };
 
-use fern_runtime::guest::Guest;
+use fern_runtime::guest::{Delivery, Guest, GuestConfig, GuestExport};
 use iroh::EndpointId;
-use log::warn;
+use log::{info, warn};
 use tokio::{
     sync::mpsc,
-    time::{Duration, interval},
+    time::{Duration, Instant, interval, sleep_until},
 };
 
 pub mod update_module;
@@ -17,37 +18,63 @@ pub use update_module::*;
 pub mod shutdown_module;
 pub use shutdown_module::*;
 
+pub mod supervisor;
+pub use supervisor::*;
+
+pub mod http_request;
+pub use http_request::*;
 
 pub enum GuestCommand {
     UpdateModule(update_module::UpdateModule),
     ShutdownModule(shutdown_module::ShutdownModule),
+    HttpRequest(http_request::HttpRequestCommand),
 }
 
+// Inbound messages, schedules and restarts are checked this often whatever the guest's tick rate
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// Expired kv keys already read as absent, sweeping them can wait
+const KV_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
 pub type CommandSender = mpsc::Sender<GuestCommand>;
 pub type CommandReceiver = mpsc::Receiver<GuestCommand>;
 
+/// Everything needed to rebuild a guest from scratch when it has to be restarted
+#[derive(Clone)]
+pub struct GuestSpec {
+    pub module: Vec<u8>,
+    pub guest_config: GuestConfig,
+    pub bootstrap: Vec<EndpointId>,
+}
+
 pub struct GuestInstance {
     sender: CommandSender,
     node_id: EndpointId,
     pub module_hash: String,
     pub id : i64,
+    /// Exports the running module implements
+    pub exports: BTreeSet<GuestExport>,
+    status: StatusHandle,
     handle: Arc<JoinHandle<anyhow::Result<()>>>,
 }
 
 impl GuestInstance {
-    pub fn new(guest: Guest, module_hash: String, id : i64) -> Self {
+    pub fn new(guest: Guest, spec: GuestSpec, module_hash: String, id : i64, supervisor: Supervisor) -> Self {
         let (sender, receiver) = mpsc::channel(100);
 
         let node_id = guest.get_node_id();
+        let status = supervisor.status_handle();
+        let exports = guest.exports.clone();
 
-        let handle = thread::spawn(move || guest_instance_thread(guest, receiver)).into();
+        let handle = thread::spawn(move || guest_instance_thread(guest, spec, supervisor, receiver)).into();
 
         Self {
             handle,
             sender,
             node_id,
             id,
-            module_hash
+            module_hash,
+            exports,
+            status,
         }
     }
 
@@ -55,17 +82,17 @@ impl GuestInstance {
         &mut self,
         module: Vec<u8>,
         module_hash: String,
-        guest_name: String,
-        host_data_path: Option<std::path::PathBuf>,
+        guest_config: GuestConfig,
         bootstrap: Vec<EndpointId>,
+        restart_policy: Option<RestartPolicy>,
     ) -> anyhow::Result<update_module::UpdateModuleResponse> {
         let (tx, rx) = tokio::sync::oneshot::channel();
         let cmd = update_module::UpdateModule {
             module,
             module_hash: module_hash.clone(),
-            guest_name,
-            host_data_path,
+            guest_config,
             bootstrap,
+            restart_policy,
             reply: tx,
         };
 
@@ -73,14 +100,25 @@ impl GuestInstance {
         let res = rx.await?;
         if res.success {
             self.module_hash = module_hash.clone();
+            self.exports = res.exports.clone();
         }
         Ok(res)
     }
 
+    /// For talking to the guest without going through the server task
+    pub fn sender(&self) -> CommandSender {
+        self.sender.clone()
+    }
+
     pub fn node_id(&self) -> EndpointId {
         self.node_id.clone()
     }
 
+    /// Snapshot of the supervisor's view of this guest
+    pub fn status(&self) -> GuestStatus {
+        self.status.lock().unwrap().clone()
+    }
+
     /// Shutdown the guest instance gracefully
     pub async fn shutdown(&self) -> anyhow::Result<shutdown_module::ShutdownModuleResponse> {
         let (tx, rx) = tokio::sync::oneshot::channel();
@@ -91,33 +129,93 @@ impl GuestInstance {
     }
 }
 
-fn guest_instance_thread(guest: Guest, receiver: CommandReceiver) -> anyhow::Result<()> {
+fn guest_instance_thread(
+    guest: Guest,
+    spec: GuestSpec,
+    supervisor: Supervisor,
+    receiver: CommandReceiver,
+) -> anyhow::Result<()> {
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()?;
-    rt.block_on(guest_instance_task(receiver, guest))?;
+    rt.block_on(guest_instance_task(receiver, guest, spec, supervisor))?;
     Ok(())
 }
 
 async fn guest_instance_task(
     mut receiver: CommandReceiver,
     mut guest: Guest,
+    mut spec: GuestSpec,
+    mut supervisor: Supervisor,
 ) -> anyhow::Result<()> {
-    // Create interval for 5 times per second (200ms)
-    let mut tick_interval = interval(Duration::from_millis(200));
+    let mut poll_interval = interval(POLL_INTERVAL);
+    let mut kv_sweep_interval = interval(KV_SWEEP_INTERVAL);
+    let mut tick_timer = TickTimer::new(guest.tick_interval());
 
     loop {
+        let next_tick = tick_timer.next;
+        // Timers wait for a stopped guest to come back rather than being spun on
+        let next_timer = supervisor
+            .should_tick()
+            .then(|| guest.next_timer())
+            .flatten()
+            .map(|wait| Instant::now() + wait);
         tokio::select! {
-            // Handle guest ticking at 5Hz
-            _ = tick_interval.tick() => {
-                if let Err(e) = tick_guest(&mut guest).await {
-                    warn!("failed to tick guest {e}");
+            // Deliver anything waiting for the guest at 5Hz
+            _ = poll_interval.tick() => {
+                if supervisor.should_tick() {
+                    match poll_guest(&mut guest).await {
+                        Ok(delivery) => record_delivery(&mut supervisor, &spec, delivery),
+                        Err(e) => {
+                            warn!("failed to poll guest {} {e}", spec.guest_config.name);
+                            supervisor.record_failure(&e);
+                        }
+                    }
+                } else if supervisor.restart_due() {
+                    info!("restarting guest {}", spec.guest_config.name);
+                    let res = restart_guest(&spec, &mut guest).await;
+                    if let Err(e) = &res {
+                        warn!("failed to restart guest {} {e}", spec.guest_config.name);
+                    }
+                    supervisor.record_restart(res);
+                }
+            }
+
+            // Call the guest's tick export at whatever rate it asked for
+            _ = wait_until(next_tick) => {
+                if supervisor.should_tick() {
+                    match guest.tick() {
+                        Ok(()) => supervisor.record_success(),
+                        Err(e) => {
+                            warn!("failed to tick guest {} {e}", spec.guest_config.name);
+                            supervisor.record_failure(&e);
+                        }
+                    }
+                }
+                tick_timer.ticked();
+            }
+
+            // Fire guest timers when they're due rather than on the next poll
+            _ = wait_until(next_timer) => {
+                match guest.tick_timers() {
+                    Ok(delivery) => record_delivery(&mut supervisor, &spec, delivery),
+                    Err(e) => {
+                        warn!("failed to fire timers for guest {} {e}", spec.guest_config.name);
+                        supervisor.record_failure(&e);
+                    }
+                }
+            }
+
+            // Reclaim expired kv keys, doesn't involve the guest so runs even when it's stopped
+            _ = kv_sweep_interval.tick() => {
+                if let Err(e) = guest.sweep_kv() {
+                    warn!("failed to sweep kv for guest {} {e}", spec.guest_config.name);
                 }
             }
 
             // Handle incoming commands
             Some(cmd) = receiver.recv() => {
-                if handle_command(cmd, &mut guest).await {
+                if handle_command(cmd, &mut guest, &mut spec, &mut supervisor).await {
                     // If handle_command returns true, it means shutdown was requested
                     break;
                 }
@@ -128,20 +226,87 @@ async fn guest_instance_task(
                 break;
             }
         }
+
+        // The guest may have changed its tick rate, or been replaced by an update or restart
+        tick_timer.update(guest.tick_interval());
     }
     Ok(())
 }
 
-async fn tick_guest(guest: &mut Guest) -> anyhow::Result<()> {
-    guest.tick_gossip().await?;
-    guest.tick()?;
-    Ok(())
+/// Deliver whatever is waiting for the guest
+async fn poll_guest(guest: &mut Guest) -> anyhow::Result<Delivery> {
+    let mut delivery = guest.tick_gossip().await?;
+    delivery.merge(guest.tick_rpc().await?);
+    delivery.merge(guest.tick_tcp().await?);
+    delivery.merge(guest.tick_schedules()?);
+    delivery.merge(guest.tick_kv_changes()?);
+    Ok(delivery)
+}
+
+fn record_delivery(supervisor: &mut Supervisor, spec: &GuestSpec, delivery: Delivery) {
+    if let Some(e) = &delivery.first_error {
+        warn!(
+            "{} handler call(s) failed for guest {} {e}",
+            delivery.failed, spec.guest_config.name
+        );
+    }
+    supervisor.record_delivery(&delivery);
+}
+
+/// When to next call the guest's tick export
+struct TickTimer {
+    interval: Option<Duration>,
+    next: Option<Instant>,
 }
 
-async fn handle_command(cmd: GuestCommand, guest: &mut Guest) -> bool {
+impl TickTimer {
+    fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            next: interval.map(|interval| Instant::now() + interval),
+        }
+    }
+
+    /// Start over when the rate changes, otherwise keep the current deadline
+    fn update(&mut self, interval: Option<Duration>) {
+        if interval != self.interval {
+            *self = TickTimer::new(interval);
+        }
+    }
+
+    fn ticked(&mut self) {
+        self.next = self.interval.map(|interval| Instant::now() + interval);
+    }
+}
+
+// Never completes when ticking is off
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn restart_guest(spec: &GuestSpec, guest: &mut Guest) -> anyhow::Result<()> {
+    update_module::rebuild_guest(
+        spec.module.clone(),
+        spec.guest_config.clone(),
+        spec.bootstrap.clone(),
+        guest,
+    )
+    .await?;
+    guest.initialize()
+}
+
+async fn handle_command(
+    cmd: GuestCommand,
+    guest: &mut Guest,
+    spec: &mut GuestSpec,
+    supervisor: &mut Supervisor,
+) -> bool {
     match cmd {
         GuestCommand::UpdateModule(update_cmd) => {
-            if let Err(e) = update_module::handle_update_module(update_cmd, guest).await {
+            if let Err(e) = update_module::handle_update_module(update_cmd, guest, spec, supervisor).await {
                 warn!("Failed to handle UpdateModule command: {}", e);
             }
             false // Continue running
@@ -152,5 +317,11 @@ async fn handle_command(cmd: GuestCommand, guest: &mut Guest) -> bool {
             }
             true // Signal to exit the loop after shutdown
         }
+        GuestCommand::HttpRequest(http_cmd) => {
+            if let Err(e) = http_request::handle_http_request(http_cmd, guest, supervisor).await {
+                warn!("Failed to handle HttpRequest command: {}", e);
+            }
+            false
+        }
     }
 }
@@ -0,0 +1,45 @@
+use fern_runtime::http::{HttpRequest, HttpResponse};
+use tokio::sync::oneshot;
+
+use crate::guest_instance::{CommandSender, GuestCommand, Supervisor};
+
+pub struct HttpRequestCommand {
+    pub request: HttpRequest,
+    pub reply: oneshot::Sender<HttpResponse>,
+}
+
+/// Hand a request to the guest behind `sender`. Goes straight to the guest instance
+/// task so the server task isn't held up while the guest handles it
+pub async fn send_http_request(
+    sender: &CommandSender,
+    request: HttpRequest,
+) -> anyhow::Result<HttpResponse> {
+    let (tx, rx) = oneshot::channel();
+    let cmd = HttpRequestCommand { request, reply: tx };
+
+    sender.send(GuestCommand::HttpRequest(cmd)).await?;
+    Ok(rx.await?)
+}
+
+pub(crate) async fn handle_http_request(
+    cmd: HttpRequestCommand,
+    guest: &mut fern_runtime::guest::Guest,
+    supervisor: &mut Supervisor,
+) -> anyhow::Result<()> {
+    // Don't call into a guest the supervisor has stopped
+    let response = if supervisor.should_tick() {
+        let (response, delivery) = guest.handle_http(cmd.request);
+        // A handler which always traps shouldn't leave the guest looking healthy
+        supervisor.record_delivery(&delivery);
+        response
+    } else {
+        HttpResponse::text(503, "guest is not running")
+    };
+
+    // The gateway may have timed out already
+    if let Err(_) = cmd.reply.send(response) {
+        log::warn!("Failed to send HttpRequest response");
+    }
+
+    Ok(())
+}
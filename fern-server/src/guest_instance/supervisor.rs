@@ -0,0 +1,262 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use fern_runtime::guest::Delivery;
+use serde::{Deserialize, Serialize};
+
+// First restart happens after this, doubling on each consecutive failure
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// Consecutive failures before a guest is reported as crash looping
+const CRASH_LOOP_THRESHOLD: u32 = 5;
+
+/// What the supervisor does when a guest fails to initialize or traps during a call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Stop calling the guest after the first failure
+    Never,
+    /// Restart with exponential backoff, giving up after `max_restarts` consecutive failures
+    OnFailure { max_restarts: u32 },
+    /// Restart with exponential backoff forever
+    Always,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self::OnFailure { max_restarts: 10 }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GuestState {
+    #[default]
+    Running,
+    /// Waiting on backoff before the next restart attempt
+    Restarting,
+    /// Failing repeatedly. Still restarted if the policy allows it
+    CrashLooping,
+    /// Stopped by its restart policy, needs a module update to run again
+    Failed,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GuestStatus {
+    pub state: GuestState,
+    pub restart_policy: RestartPolicy,
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+}
+
+pub type StatusHandle = Arc<Mutex<GuestStatus>>;
+
+/// Tracks failures for a single guest instance and decides when it should be restarted.
+/// Lives on the guest instance thread, the status is shared with the server for reporting.
+pub struct Supervisor {
+    status: StatusHandle,
+    restart_at: Option<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(restart_policy: RestartPolicy) -> Self {
+        let status = GuestStatus {
+            restart_policy,
+            ..Default::default()
+        };
+        Self {
+            status: Arc::new(Mutex::new(status)),
+            restart_at: None,
+        }
+    }
+
+    pub fn status_handle(&self) -> StatusHandle {
+        self.status.clone()
+    }
+
+    /// Only running guests get ticked, everything else is waiting on a restart or stopped
+    pub fn should_tick(&self) -> bool {
+        self.status.lock().unwrap().state == GuestState::Running
+    }
+
+    pub fn restart_due(&self) -> bool {
+        self.restart_at
+            .is_some_and(|restart_at| Instant::now() >= restart_at)
+    }
+
+    pub fn record_success(&mut self) {
+        let mut status = self.status.lock().unwrap();
+        status.consecutive_failures = 0;
+        status.state = GuestState::Running;
+    }
+
+    pub fn record_failure(&mut self, error: &anyhow::Error) {
+        let mut status = self.status.lock().unwrap();
+        status.consecutive_failures += 1;
+        status.total_failures += 1;
+        status.last_error = Some(error.to_string());
+
+        let consecutive_failures = status.consecutive_failures;
+        let restart = match status.restart_policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure { max_restarts } => consecutive_failures <= max_restarts,
+            RestartPolicy::Always => true,
+        };
+
+        if restart {
+            self.restart_at = Some(Instant::now() + backoff(consecutive_failures));
+            status.state = if consecutive_failures >= CRASH_LOOP_THRESHOLD {
+                GuestState::CrashLooping
+            } else {
+                GuestState::Restarting
+            };
+        } else {
+            self.restart_at = None;
+            status.state = GuestState::Failed;
+        }
+    }
+
+    /// A trapped handler counts against the guest, a delivery which didn't call
+    /// anything says nothing about whether it's healthy
+    pub fn record_delivery(&mut self, delivery: &Delivery) {
+        if let Some(e) = &delivery.first_error {
+            self.record_failure(e);
+        } else if delivery.succeeded > 0 {
+            self.record_success();
+        }
+    }
+
+    pub fn record_restart(&mut self, res: anyhow::Result<()>) {
+        match res {
+            Ok(()) => {
+                self.restart_at = None;
+                let mut status = self.status.lock().unwrap();
+                status.restarts += 1;
+                // Failures aren't cleared until the guest makes it through a tick
+                status.state = GuestState::Running;
+            }
+            Err(e) => self.record_failure(&e),
+        }
+    }
+
+    pub fn set_restart_policy(&mut self, restart_policy: RestartPolicy) {
+        self.status.lock().unwrap().restart_policy = restart_policy;
+    }
+
+    /// A new module was deployed, start over with a clean slate
+    pub fn reset(&mut self) {
+        self.restart_at = None;
+        let mut status = self.status.lock().unwrap();
+        status.consecutive_failures = 0;
+        status.state = GuestState::Running;
+    }
+}
+
+fn backoff(consecutive_failures: u32) -> Duration {
+    let exponent = consecutive_failures.saturating_sub(1).min(16);
+    (BASE_BACKOFF * 2u32.pow(exponent)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(supervisor: &Supervisor) -> GuestStatus {
+        supervisor.status_handle().lock().unwrap().clone()
+    }
+
+    #[test]
+    fn supervisor_restart_policies() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+        assert_eq!(backoff(100), MAX_BACKOFF);
+
+        // Never stops on the first failure
+        let mut never = Supervisor::new(RestartPolicy::Never);
+        never.record_failure(&anyhow::anyhow!("trap"));
+        assert_eq!(state(&never).state, GuestState::Failed);
+        assert!(!never.should_tick());
+        assert!(never.restart_at.is_none());
+
+        // OnFailure restarts until it runs out of attempts
+        let mut on_failure = Supervisor::new(RestartPolicy::OnFailure { max_restarts: 2 });
+        on_failure.record_failure(&anyhow::anyhow!("trap"));
+        assert_eq!(state(&on_failure).state, GuestState::Restarting);
+        assert!(on_failure.restart_at.is_some());
+        on_failure.record_restart(Ok(()));
+        assert!(on_failure.should_tick());
+        assert_eq!(state(&on_failure).restarts, 1);
+        on_failure.record_failure(&anyhow::anyhow!("trap"));
+        on_failure.record_failure(&anyhow::anyhow!("trap"));
+        let status = state(&on_failure);
+        assert_eq!(status.state, GuestState::Failed);
+        assert_eq!(status.consecutive_failures, 3);
+        assert_eq!(status.last_error.as_deref(), Some("trap"));
+        assert!(on_failure.restart_at.is_none());
+
+        // Always keeps going but reports crash looping
+        let mut always = Supervisor::new(RestartPolicy::Always);
+        for _ in 0..CRASH_LOOP_THRESHOLD {
+            always.record_failure(&anyhow::anyhow!("trap"));
+        }
+        assert_eq!(state(&always).state, GuestState::CrashLooping);
+        assert!(always.restart_at.is_some());
+
+        // A successful tick clears the failure streak
+        always.record_success();
+        let status = state(&always);
+        assert_eq!(status.state, GuestState::Running);
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.total_failures, CRASH_LOOP_THRESHOLD as u64);
+    }
+
+    #[test]
+    fn supervisor_handler_deliveries() {
+        let mut supervisor = Supervisor::new(RestartPolicy::Always);
+        supervisor.record_failure(&anyhow::anyhow!("trap"));
+        supervisor.record_restart(Ok(()));
+
+        // Nothing was called, the failure streak stands
+        supervisor.record_delivery(&Delivery::default());
+        assert_eq!(state(&supervisor).consecutive_failures, 1);
+
+        // A handler which traps fails the guest even if others succeeded
+        supervisor.record_delivery(&Delivery {
+            succeeded: 2,
+            failed: 1,
+            first_error: Some(anyhow::anyhow!("scheduledTask failed trap")),
+        });
+        let status = state(&supervisor);
+        assert_eq!(status.state, GuestState::Restarting);
+        assert_eq!(status.consecutive_failures, 2);
+        supervisor.record_restart(Ok(()));
+
+        supervisor.record_delivery(&Delivery {
+            succeeded: 1,
+            ..Default::default()
+        });
+        assert_eq!(state(&supervisor).consecutive_failures, 0);
+    }
+
+    #[test]
+    fn supervisor_gives_up_when_out_of_restarts() {
+        let mut supervisor = Supervisor::new(RestartPolicy::OnFailure { max_restarts: 1 });
+        supervisor.record_failure(&anyhow::anyhow!("trap"));
+        assert_eq!(state(&supervisor).state, GuestState::Restarting);
+        supervisor.record_restart(Ok(()));
+
+        // Restarting again would exceed max_restarts
+        supervisor.record_failure(&anyhow::anyhow!("trap"));
+        let status = state(&supervisor);
+        assert_eq!(status.state, GuestState::Failed);
+        assert_eq!(status.restarts, 1);
+        assert!(!supervisor.should_tick());
+        assert!(!supervisor.restart_due());
+        assert!(supervisor.restart_at.is_none());
+    }
+}
@@ -1,41 +1,61 @@
-use std::{mem, path::PathBuf};
+use std::{collections::BTreeSet, mem};
 
 use fern_runtime::{
-    guest::{Guest, GuestConfig, new_guest_with_userdata},
+    guest::{Guest, GuestConfig, GuestExport, new_guest_with_userdata},
     iroh_helpers::iroh_bundle_with_secret,
 };
 use iroh::EndpointId;
 use tokio::sync::oneshot;
 
+use crate::guest_instance::{GuestSpec, RestartPolicy, Supervisor};
+
 
 pub struct UpdateModule {
     pub module: Vec<u8>,
     pub module_hash: String,
-    pub guest_name: String,
-    pub host_data_path: Option<PathBuf>,
+    pub guest_config: GuestConfig,
     pub reply: oneshot::Sender<UpdateModuleResponse>,
     pub bootstrap: Vec<EndpointId>,
+    /// Replaces the current restart policy once the update succeeds
+    pub restart_policy: Option<RestartPolicy>,
 }
 
 pub struct UpdateModuleResponse {
     pub success: bool,
     pub error_message: Option<String>,
+    /// Exports of whichever module is running after the update
+    pub exports: BTreeSet<GuestExport>,
 }
 
 pub(crate) async fn handle_update_module(
     cmd: UpdateModule,
     guest: &mut fern_runtime::guest::Guest,
+    spec: &mut GuestSpec,
+    supervisor: &mut Supervisor,
 ) -> anyhow::Result<()> {
-    let response = match perform_module_update(cmd.module, cmd.guest_name, cmd.host_data_path, cmd.bootstrap, guest).await {
-        Ok(()) => UpdateModuleResponse {
-            success: true,
-            error_message: None,
-        },
+    let new_spec = GuestSpec {
+        module: cmd.module,
+        guest_config: cmd.guest_config,
+        bootstrap: cmd.bootstrap,
+    };
+
+    let response = match perform_module_update(new_spec, guest, spec, supervisor).await {
+        Ok(()) => {
+            if let Some(restart_policy) = cmd.restart_policy {
+                supervisor.set_restart_policy(restart_policy);
+            }
+            UpdateModuleResponse {
+                success: true,
+                error_message: None,
+                exports: guest.exports.clone(),
+            }
+        }
         Err(e) => {
             log::error!("Failed to update guest module: {}", e);
             UpdateModuleResponse {
                 success: false,
                 error_message: Some(e.to_string()),
+                exports: guest.exports.clone(),
             }
         }
     };
@@ -49,9 +69,68 @@ pub(crate) async fn handle_update_module(
 }
 
 async fn perform_module_update(
+    new_spec: GuestSpec,
+    guest: &mut Guest,
+    spec: &mut GuestSpec,
+    supervisor: &mut Supervisor,
+) -> anyhow::Result<()> {
+    // 1. Capture the secret key to maintain network identity
+    let secret_key = guest.endpoint.secret_key().clone();
+    // Timers the new module sets from init are kept, the old module's aren't
+    let timers_before = guest.next_timer_id();
+
+    // 2. Build and initialize the new guest alongside the running one
+    log::info!("Creating new guest instance with updated module");
+    let (endpoint, router_builder) = iroh_bundle_with_secret(secret_key).await?;
+
+    let mut new_guest = new_guest_with_userdata(
+        new_spec.guest_config.clone(),
+        new_spec.module.clone(),
+        (endpoint, router_builder, new_spec.bootstrap.clone()),
+        Some(guest.plugin_userdata.clone()),
+    )?;
+
+    // The guests share a sqlite connection, so the new module's init can't start
+    // inside a transaction the old guest left open
+    guest.rollback_transactions("module update");
+
+    if let Err(e) = new_guest.initialize() {
+        // The old guest never stopped so there is nothing to restore,
+        // just tear down the networking the new one brought up
+        new_guest.endpoint.close().await;
+        let _ = new_guest.router.shutdown().await;
+        return Err(e.context("updated module failed to initialize"));
+    }
+
+    // 3. The new guest is good, shutdown the existing guest without
+    //    rolling back whatever the new module has open
+    log::info!("Shutting down existing guest instance");
+    let _ = guest.shutdown_replaced();
+    guest.endpoint.close().await;
+    let _ = guest.router.shutdown().await;
+
+    // 4. Swap the guests - the old guest will be dropped
+    // NOTE: After this swap, `new_guest` contains the old guest instance
+    mem::swap(guest, &mut new_guest);
+    drop(new_guest);
+    if let Some(timers_before) = timers_before {
+        guest.drop_transient_timers(timers_before);
+    }
+
+    // Restarts should bring back the module we just deployed
+    *spec = new_spec;
+    supervisor.reset();
+
+    log::info!("Guest module update completed successfully");
+    Ok(())
+}
+
+/// Replace `guest` with a fresh instance of `module`, keeping its network identity
+/// and host side state. The new guest is not initialized.
+/// Used to restart a failed guest, updates go through `perform_module_update`.
+pub(crate) async fn rebuild_guest(
     module: Vec<u8>,
-    guest_name: String,
-    host_data_path: Option<PathBuf>,
+    guest_config: GuestConfig,
     bootstrap: Vec<EndpointId>,
     guest: &mut Guest,
 ) -> anyhow::Result<()> {
@@ -64,30 +143,122 @@ async fn perform_module_update(
     guest.endpoint.close().await;
     let _ = guest.router.shutdown().await;
 
-    // 3. Create a new guest with the updated module using the same secret key
-    log::info!("Creating new guest instance with updated module");
+    // 3. Create a new guest with the module using the same secret key
+    log::info!("Creating new guest instance");
     let (endpoint, router_builder) = iroh_bundle_with_secret(secret_key).await?;
 
-    let guest_config = GuestConfig {
-        name: guest_name,
-        host_data_path: host_data_path,
-    };
     let mut new_guest = new_guest_with_userdata(guest_config, module, (endpoint, router_builder, bootstrap), Some(guest.plugin_userdata.clone()))?;
 
-    // TODO how should we handle a guest failing to initialize here?
-    // at the very least we should report it..
-    // Should we rollback? Just allow it to be created and failing?
-    let _ = new_guest.initialize();
-
     // 4. Swap the guests - the old guest will be dropped
     // NOTE: After this swap, `new_guest` contains the old guest instance
     mem::swap(guest, &mut new_guest);
+    // Not initialized yet, so every transient timer belonged to the failed guest
+    guest.drop_transient_timers(u64::MAX);
 
     // 5. Clean up the old guest instance (now in new_guest)
     //    we don't really have to force a drop. But, this way
     //    accidents might be avoided in future.
     drop(new_guest);
 
-    log::info!("Guest module update completed successfully");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fern_runtime::{guest::new_guest, iroh_helpers::iroh_bundle};
+
+    // Hand assembled module with `() -> i32` exports sharing one mutable global.
+    // Each export is named along with its body, a non zero result fails the call
+    fn module(exports: &[(&str, &[u8])]) -> Vec<u8> {
+        fn section(wasm: &mut Vec<u8>, id: u8, body: Vec<u8>) {
+            wasm.push(id);
+            wasm.push(body.len() as u8);
+            wasm.extend(body);
+        }
+
+        let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+        section(&mut wasm, 0x01, vec![0x01, 0x60, 0x00, 0x01, 0x7f]);
+
+        let mut body = vec![exports.len() as u8];
+        body.extend(exports.iter().map(|_| 0x00));
+        section(&mut wasm, 0x03, body);
+
+        // (global (mut i32) (i32.const 0))
+        section(&mut wasm, 0x06, vec![0x01, 0x7f, 0x01, 0x41, 0x00, 0x0b]);
+
+        let mut body = vec![exports.len() as u8];
+        for (i, (name, _)) in exports.iter().enumerate() {
+            body.push(name.len() as u8);
+            body.extend(name.as_bytes());
+            body.extend([0x00, i as u8]);
+        }
+        section(&mut wasm, 0x07, body);
+
+        let mut body = vec![exports.len() as u8];
+        for (_, code) in exports {
+            body.push(code.len() as u8 + 1);
+            // No locals
+            body.push(0x00);
+            body.extend(*code);
+        }
+        section(&mut wasm, 0x0a, body);
+        wasm
+    }
+
+    const RETURN_ZERO: &[u8] = &[0x41, 0x00, 0x0b];
+    // Sets the global before returning 0
+    const SET_GLOBAL: &[u8] = &[0x41, 0x01, 0x24, 0x00, 0x41, 0x00, 0x0b];
+    // Returns the global
+    const GET_GLOBAL: &[u8] = &[0x23, 0x00, 0x0b];
+    const UNREACHABLE: &[u8] = &[0x00, 0x0b];
+
+    #[tokio::test]
+    async fn failed_update_keeps_the_old_guest_running() {
+        let guest_config = GuestConfig {
+            name: "updated".to_string(),
+            capabilities: Some(BTreeSet::new()),
+            ..Default::default()
+        };
+        let old_module = module(&[
+            ("init", RETURN_ZERO),
+            ("tick", GET_GLOBAL),
+            ("shutdown", SET_GLOBAL),
+        ]);
+        let mut spec = GuestSpec {
+            module: old_module.clone(),
+            guest_config: guest_config.clone(),
+            bootstrap: vec![],
+        };
+
+        let (endpoint, router_builder) = iroh_bundle().await.unwrap();
+        let mut guest = new_guest(
+            guest_config.clone(),
+            old_module.clone(),
+            (endpoint, router_builder, vec![]),
+        )
+        .unwrap();
+        guest.initialize().unwrap();
+        guest.tick().expect("shutdown hasn't run yet");
+
+        let new_spec = GuestSpec {
+            module: module(&[("init", UNREACHABLE)]),
+            guest_config,
+            bootstrap: vec![],
+        };
+        let mut supervisor = Supervisor::new(RestartPolicy::Never);
+        let res = perform_module_update(new_spec, &mut guest, &mut spec, &mut supervisor).await;
+        assert!(res.is_err());
+
+        // Still the old module, and its shutdown export was never called
+        assert!(guest.implements(GuestExport::Shutdown));
+        guest
+            .tick()
+            .expect("old guest's shutdown export was called");
+        assert_eq!(spec.module, old_module);
+
+        let _ = guest.shutdown();
+        guest.endpoint.close().await;
+        let _ = guest.router.shutdown().await;
+    }
+}
@@ -16,6 +16,7 @@ pub mod guest_instance;
 pub mod server;
 pub mod api;
 pub mod cli;
+pub mod gateway;
 
 // Re-export commonly used types
 pub use data::Data;
@@ -23,7 +24,7 @@ pub use guest_instance::GuestInstance;
 pub use server::{Server, GuestInfo};
 pub use api::FernApiClient;
 
-use crate::{api::api_server, server::Config};
+use crate::{api::{ApiAuth, ApiListen, DEFAULT_API_LISTEN, DEFAULT_MAX_MODULE_SIZE, api_server}, gateway::gateway_server, server::Config};
 
 
 /// Start a Fern server with the given secret key
@@ -35,6 +36,16 @@ pub async fn start_server(config: Config) -> Result<()> {
         SecretKey::generate(&mut rand::rng())
     };
 
+    // Requests signed with the node's own key are always accepted
+    let api_auth = ApiAuth::new(&config.api_auth, secret.public());
+    let api_listen = ApiListen {
+        addr: config.api_listen.unwrap_or(DEFAULT_API_LISTEN),
+        tls: config.api_tls.clone(),
+        unix_socket: config.api_unix_socket.clone(),
+        max_module_size: config.api_max_module_size.unwrap_or(DEFAULT_MAX_MODULE_SIZE),
+    };
+    let http_gateway = config.http_gateway.clone();
+
     let endpoint = Endpoint::builder()
         .discovery(DnsDiscovery::n0_dns())
         .secret_key(secret)
@@ -52,7 +63,20 @@ pub async fn start_server(config: Config) -> Result<()> {
         .run_until(async move {
             let server = Server::new(endpoint, router_builder, config);
 
-            tokio::spawn(api_server(server));
+            if let Some(http_gateway) = http_gateway {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = gateway_server(server, http_gateway).await {
+                        log::error!("HTTP gateway stopped {e}");
+                    }
+                });
+            }
+
+            tokio::spawn(async move {
+                if let Err(e) = api_server(server, api_auth, api_listen).await {
+                    log::error!("API server stopped {e}");
+                }
+            });
             loop {
                 tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
             }
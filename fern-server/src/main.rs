@@ -1,11 +1,17 @@
 use std::path::PathBuf;
 
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use log::{error, info};
 
-use fern_server::{FernApiClient, cli::{GuestsTable, GuestsTableProps}, generate_secret_key, server::Config, start_server};
+use fern_server::{FernApiClient, api::{FernAdminClient, ManagementClient}, cli::{GuestsTable, GuestsTableProps, ModuleHistoryTable}, generate_secret_key, load_secret_key, guest_instance::RestartPolicy, server::{Config, ModuleOptions, RollbackTarget}, start_server};
+use fern_runtime::{
+    capabilities::{Capability, CapabilityManifest},
+    limits::GuestLimits,
+    validation::validate_module,
+};
 use iocraft::prelude::*;
+use iroh::EndpointId;
 use tokio::{fs::File, io::AsyncReadExt};
 
 /// Fern Server - A weird distributed WASM runtime 🌿
@@ -16,6 +22,38 @@ use tokio::{fs::File, io::AsyncReadExt};
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    /// Base URL of the node's API
+    #[arg(long, global = true, env = "FERN_SERVER", default_value = "http://localhost:3000")]
+    pub server: String,
+    /// API token sent as a bearer token
+    #[arg(long, global = true, env = "FERN_API_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// Sign API requests with this secret key file instead of using a token.
+    /// Also the identity used when connecting with --node
+    #[arg(long, global = true, env = "FERN_SIGNING_KEY", conflicts_with = "token")]
+    pub signing_key: Option<PathBuf>,
+    /// Manage the node with this endpoint id over iroh instead of HTTP
+    #[arg(long, global = true, env = "FERN_NODE", requires = "signing_key")]
+    pub node: Option<EndpointId>,
+}
+
+async fn api_client(
+    server: String,
+    token: Option<String>,
+    signing_key: Option<PathBuf>,
+    node: Option<EndpointId>,
+) -> Result<ManagementClient> {
+    if let (Some(node), Some(path)) = (node, &signing_key) {
+        let client = FernAdminClient::connect(load_secret_key(path.clone()).await?, node).await?;
+        return Ok(ManagementClient::Iroh(client));
+    }
+
+    let client = FernApiClient::new(server.trim_end_matches('/'));
+    Ok(ManagementClient::Http(match (token, signing_key) {
+        (Some(token), _) => client.with_token(token),
+        (None, Some(path)) => client.with_signing_key(load_secret_key(path).await?),
+        (None, None) => client,
+    }))
 }
 
 #[derive(Subcommand)]
@@ -39,10 +77,120 @@ pub enum Commands {
     ListGuests {},
     CreateModule {
         name: String,
-        module_path: PathBuf
+        module_path: PathBuf,
+        #[command(flatten)]
+        options: ModuleArgs,
+    },
+    /// Create or update a guest, optionally on every node trusting this one
+    Deploy {
+        name: String,
+        module_path: PathBuf,
+        /// Announce the module so cluster peers deploy it too
+        #[arg(long)]
+        cluster: bool,
+        #[command(flatten)]
+        options: ModuleArgs,
     },
     RemoveModule {
         name: String,
+    },
+    /// Redeploy a previously deployed module
+    RollbackModule {
+        name: String,
+        /// History id or module hash, defaults to the previous module
+        #[arg(long)]
+        to: Option<RollbackTarget>,
+    },
+    /// List previously deployed modules for a guest
+    ModuleHistory {
+        name: String,
+    },
+    /// Check a module's imports and exports without deploying it, exits non-zero if it's invalid
+    Validate {
+        module_path: PathBuf,
+        /// Capabilities to check instead of the manifest embedded in the module
+        #[arg(long = "capability", value_delimiter = ',')]
+        capabilities: Option<Vec<Capability>>,
+        /// Ask the node, which also applies its capability policy
+        #[arg(long)]
+        remote: bool,
+    },
+}
+
+/// Settings for a deployed module, anything not given uses the server's defaults
+#[derive(Args)]
+pub struct ModuleArgs {
+    /// What to do when the guest fails, defaults to the server's policy
+    #[arg(long, value_enum)]
+    restart_policy: Option<RestartPolicyArg>,
+    /// Consecutive failures before giving up, only used with on-failure
+    #[arg(long, default_value_t = 10)]
+    max_restarts: u32,
+    #[command(flatten)]
+    limits: LimitArgs,
+    /// Host functions to grant (kv, sqlite, tcp, debug, gossip, rpc, schedule). Overrides the
+    /// manifest embedded in the module, without either the module gets what it imports
+    #[arg(long = "capability", value_delimiter = ',')]
+    capabilities: Option<Vec<Capability>>,
+}
+
+impl ModuleArgs {
+    fn into_options(self) -> ModuleOptions {
+        ModuleOptions {
+            restart_policy: self
+                .restart_policy
+                .map(|policy| policy.into_policy(self.max_restarts)),
+            limits: self.limits.into_limits(),
+            capabilities: self.capabilities.map(|capabilities| CapabilityManifest {
+                capabilities: capabilities.into_iter().collect(),
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum RestartPolicyArg {
+    Never,
+    OnFailure,
+    Always,
+}
+
+/// Resource limits for a guest, anything not given uses the server's defaults
+#[derive(Args)]
+pub struct LimitArgs {
+    /// Max linear memory in 64KiB wasm pages
+    #[arg(long)]
+    max_memory_pages: Option<u32>,
+    /// Fuel (roughly wasm instructions) available to each export call
+    #[arg(long)]
+    fuel_per_call: Option<u64>,
+    /// Wall clock limit on each export call
+    #[arg(long)]
+    call_timeout_ms: Option<u64>,
+    /// Host function calls allowed per second
+    #[arg(long)]
+    max_host_calls_per_sec: Option<u32>,
+}
+
+impl LimitArgs {
+    fn into_limits(self) -> Option<GuestLimits> {
+        let limits = GuestLimits {
+            max_memory_pages: self.max_memory_pages,
+            fuel_per_call: self.fuel_per_call,
+            call_timeout_ms: self.call_timeout_ms,
+            max_host_calls_per_sec: self.max_host_calls_per_sec,
+        };
+        (limits != GuestLimits::default()).then_some(limits)
+    }
+}
+
+impl RestartPolicyArg {
+    fn into_policy(self, max_restarts: u32) -> RestartPolicy {
+        match self {
+            RestartPolicyArg::Never => RestartPolicy::Never,
+            RestartPolicyArg::OnFailure => RestartPolicy::OnFailure { max_restarts },
+            RestartPolicyArg::Always => RestartPolicy::Always,
+        }
     }
 }
 
@@ -62,8 +210,7 @@ async fn handle_generate_secret_command(path: PathBuf) -> Result<()> {
     generate_secret_key(path).await
 }
 
-async fn handle_health_check_command() -> Result<()> {
-    let client = FernApiClient::localhost();
+async fn handle_health_check_command(client: ManagementClient) -> Result<()> {
     if client.health_check().await {
         element! {
             View(
@@ -89,8 +236,7 @@ async fn handle_health_check_command() -> Result<()> {
     Ok(())
 }
 
-async fn handle_list_guest_command() -> Result<()> {
-    let client = FernApiClient::localhost();
+async fn handle_list_guest_command(client: ManagementClient) -> Result<()> {
     let guests = client.list_guests().await?;
 
     // let guests = GuestsTableProps {
@@ -105,15 +251,18 @@ async fn handle_list_guest_command() -> Result<()> {
     Ok(())
 }
 
-async fn handle_create_module_command(name: String, module_path: PathBuf) -> Result<()> {
-    let client = FernApiClient::localhost();
-    
+async fn handle_create_module_command(
+    client: ManagementClient,
+    name: String,
+    module_path: PathBuf,
+    options: ModuleOptions,
+) -> Result<()> {
     // Read the module file
     let module_bytes = std::fs::read(&module_path)
         .map_err(|e| anyhow::anyhow!("Failed to read module file at {:?}: {}", module_path, e))?;
     
     // Create the guest module
-    match client.create_guest(name.clone(), module_bytes).await {
+    match client.create_guest_with_options(name.clone(), module_bytes, options).await {
         Ok(response) => {
             element! {
                 View(
@@ -146,9 +295,55 @@ async fn handle_create_module_command(name: String, module_path: PathBuf) -> Res
     Ok(())
 }
 
-async fn handle_remove_module_command(name: String) -> Result<()> {
-    let client = FernApiClient::localhost();
-    
+async fn handle_deploy_command(
+    client: ManagementClient,
+    name: String,
+    module_path: PathBuf,
+    options: ModuleOptions,
+    cluster: bool,
+) -> Result<()> {
+    let module_bytes = std::fs::read(&module_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read module file at {:?}: {}", module_path, e))?;
+
+    let response = client.deploy_guest(name.clone(), module_bytes, options, cluster).await?;
+    if response.success {
+        let action = if response.created { "created" } else { "updated" };
+        let cluster_status = match (response.announced, response.error) {
+            (true, _) => "Announced to cluster peers".to_string(),
+            (false, Some(e)) => format!("Warning: {}", e),
+            (false, None) => "Deployed to this node only".to_string(),
+        };
+        element! {
+            View(
+                border_style: BorderStyle::Round,
+                border_color: Color::Green,
+                padding: 1,
+            ) {
+                Text(content: format!("✅ Successfully {} guest '{}'", action, name), weight: Weight::Bold)
+                Text(content: format!("Module hash: {}", response.module_hash))
+                Text(content: cluster_status)
+            }
+        }
+        .print();
+        Ok(())
+    } else {
+        let error = response.error.unwrap_or_else(|| "unknown error".to_string());
+        element! {
+            View(
+                border_style: BorderStyle::Round,
+                border_color: Color::Red,
+                padding: 1,
+            ) {
+                Text(content: format!("❌ Failed to deploy guest '{}'", name), weight: Weight::Bold)
+                Text(content: format!("Error: {}", error))
+            }
+        }
+        .print();
+        Err(anyhow::anyhow!(error))
+    }
+}
+
+async fn handle_remove_module_command(client: ManagementClient, name: String) -> Result<()> {
     match client.remove_guest(name.clone()).await {
         Ok(response) => {
             if response.success {
@@ -195,25 +390,160 @@ async fn handle_remove_module_command(name: String) -> Result<()> {
     Ok(())
 }
 
+async fn handle_rollback_module_command(client: ManagementClient, name: String, to: Option<RollbackTarget>) -> Result<()> {
+    match client.rollback_guest(&name, to).await {
+        Ok(response) if response.success => {
+            element! {
+                View(
+                    border_style: BorderStyle::Round,
+                    border_color: Color::Green,
+                    padding: 1,
+                ) {
+                    Text(content: format!("✅ Rolled back guest '{}'", name), weight: Weight::Bold)
+                    Text(content: format!("Module Hash: {}", response.module_hash))
+                    Text(content: format!("Replaced: {}", response.previous_hash.unwrap_or_default()))
+                }
+            }
+            .print();
+        }
+        Ok(response) => {
+            element! {
+                View(
+                    border_style: BorderStyle::Round,
+                    border_color: Color::Red,
+                    padding: 1,
+                ) {
+                    Text(content: format!("❌ Failed to roll back guest '{}'", name), weight: Weight::Bold)
+                    Text(content: format!("Module Hash: {}", response.module_hash))
+                    Text(content: format!("Error: {}", response.error.clone().unwrap_or_default()))
+                }
+            }
+            .print();
+            return Err(anyhow::anyhow!("Failed to roll back guest {}", name));
+        }
+        Err(e) => {
+            element! {
+                View(
+                    border_style: BorderStyle::Round,
+                    border_color: Color::Red,
+                    padding: 1,
+                ) {
+                    Text(content: format!("❌ Failed to roll back guest '{}'", name), weight: Weight::Bold)
+                    Text(content: format!("Error: {}", e))
+                }
+            }
+            .print();
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_module_history_command(client: ManagementClient, name: String) -> Result<()> {
+    let history = client.module_history(&name).await?;
+
+    element! {
+        ModuleHistoryTable(history: Some(history))
+    }
+    .print();
+
+    Ok(())
+}
+
+async fn handle_validate_command(
+    client: Option<ManagementClient>,
+    module_path: PathBuf,
+    capabilities: Option<CapabilityManifest>,
+) -> Result<()> {
+    let module_bytes = std::fs::read(&module_path)
+        .map_err(|e| anyhow::anyhow!("Failed to read module file at {:?}: {}", module_path, e))?;
+
+    let report = match client {
+        Some(client) => client.validate_module(module_bytes, capabilities).await?,
+        None => validate_module(&module_bytes, capabilities.as_ref(), None),
+    };
+
+    let (border_color, title) = if report.is_valid() {
+        (Color::Green, format!("✅ {} is a valid Fern module", module_path.display()))
+    } else {
+        (Color::Red, format!("❌ {} failed validation", module_path.display()))
+    };
+    let capabilities = report
+        .capabilities
+        .iter()
+        .map(|capability| format!("{capability:?}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let issues = report
+        .errors
+        .iter()
+        .map(|issue| format!("error: {}", issue.message))
+        .chain(report.warnings.iter().map(|issue| format!("warning: {}", issue.message)))
+        .collect::<Vec<_>>();
+
+    element! {
+        View(
+            border_style: BorderStyle::Round,
+            border_color: border_color,
+            padding: 1,
+            flex_direction: FlexDirection::Column,
+        ) {
+            Text(content: title, weight: Weight::Bold)
+            Text(content: format!("Module hash: {}", report.module_hash))
+            Text(content: format!("Exports: {}", report.exports.join(", ")))
+            Text(content: format!("Capabilities: {}", capabilities))
+            #(issues.into_iter().map(|issue| element! { Text(content: issue) }))
+        }
+    }
+    .print();
+
+    if report.is_valid() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(report.error_message()))
+    }
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .init();
 
-    let cli = Cli::parse();
+    if let Err(e) = run(Cli::parse()).await {
+        error!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let Cli { command, server, token, signing_key, node } = cli;
+    // Credentials are only loaded by commands which talk to the API
+    let client = || api_client(server, token, signing_key, node);
 
-    let result = match cli.command {
+    match command {
         Commands::Start { config } => handle_start_command(config).await,
         Commands::GenerateSecret { path } => handle_generate_secret_command(path).await,
-        Commands::HealthCheck {} => handle_health_check_command().await,
-        Commands::ListGuests {} => handle_list_guest_command().await,
-        Commands::CreateModule { name, module_path } => handle_create_module_command(name, module_path).await,
-        Commands::RemoveModule { name } => handle_remove_module_command(name).await,
-    };
-
-    if let Err(e) = result {
-        error!("Error: {}", e);
-        std::process::exit(1);
+        Commands::HealthCheck {} => handle_health_check_command(client().await?).await,
+        Commands::ListGuests {} => handle_list_guest_command(client().await?).await,
+        Commands::CreateModule { name, module_path, options } => {
+            handle_create_module_command(client().await?, name, module_path, options.into_options()).await
+        }
+        Commands::Deploy { name, module_path, cluster, options } => {
+            handle_deploy_command(client().await?, name, module_path, options.into_options(), cluster).await
+        }
+        Commands::RemoveModule { name } => handle_remove_module_command(client().await?, name).await,
+        Commands::RollbackModule { name, to } => {
+            handle_rollback_module_command(client().await?, name, to).await
+        }
+        Commands::ModuleHistory { name } => handle_module_history_command(client().await?, name).await,
+        Commands::Validate { module_path, capabilities, remote } => {
+            let capabilities = capabilities.map(|capabilities| CapabilityManifest {
+                capabilities: capabilities.into_iter().collect(),
+            });
+            let client = if remote { Some(client().await?) } else { None };
+            handle_validate_command(client, module_path, capabilities).await
+        }
     }
 }
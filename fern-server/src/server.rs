@@ -1,5 +1,6 @@
-use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::Arc, thread, time::Duration};
+use std::{collections::{BTreeMap, BTreeSet}, net::SocketAddr, path::{Path, PathBuf}, sync::Arc, thread, time::Duration};
 
+use fern_runtime::{capabilities::Capability, guest::GuestConfig, guest_fns::tcp::TcpPermissions, limits::GuestLimits};
 use iroh::{
     Endpoint, PublicKey, SecretKey, discovery::dns::DnsDiscovery, protocol::{Router, RouterBuilder}
 };
@@ -10,7 +11,9 @@ use tokio::{
 };
 
 use crate::{
-    data::Data, guest_instance::GuestInstance, server::get_info::handle_get_info,
+    api::{ApiAuthConfig, ApiTlsConfig, auth::TrustedKey},
+    gateway::HttpGatewayConfig,
+    data::Data, guest_instance::{GuestInstance, RestartPolicy}, server::get_info::handle_get_info,
     server::gossip::setup_gossip,
 };
 
@@ -26,8 +29,27 @@ pub use update_module::*;
 pub mod remove_module;
 pub use remove_module::*;
 
+pub mod rollback_module;
+pub use rollback_module::*;
+
 pub mod gossip;
 
+pub mod cluster;
+pub use cluster::{ClusterConfig, ClusterTrust};
+use cluster::{AnnounceModule, FetchModule, handle_announce_module, handle_fetch_module, setup_cluster};
+
+pub mod deploy_module;
+pub use deploy_module::*;
+
+pub mod validate_module;
+pub use validate_module::*;
+
+pub mod guest_http;
+pub use guest_http::*;
+
+pub mod admin;
+use admin::setup_admin;
+
 pub mod get_info;
 pub use get_info::*;
 
@@ -40,6 +62,42 @@ pub struct Config {
     pub server_secret : Option<SecretKey>,
     pub db_path : Option<PathBuf>,
     pub host_data_path : Option<PathBuf>,
+    /// TCP access granted to every guest on this node
+    #[serde(default)]
+    pub guest_tcp : TcpPermissions,
+    /// Restart policy for guests which weren't created with their own
+    #[serde(default)]
+    pub default_restart_policy : RestartPolicy,
+    /// Resource limits for guests which don't set their own
+    #[serde(default)]
+    pub default_guest_limits : GuestLimits,
+    /// Capabilities guests on this node may be granted. Unset allows everything
+    #[serde(default)]
+    pub allowed_capabilities : Option<BTreeSet<Capability>>,
+    /// API tokens and trusted signing keys. Unset leaves the API open
+    #[serde(default)]
+    pub api_auth : ApiAuthConfig,
+    /// Address the management API listens on, defaults to 0.0.0.0:3000
+    #[serde(default)]
+    pub api_listen : Option<SocketAddr>,
+    /// Serve the API over https with this certificate
+    #[serde(default)]
+    pub api_tls : Option<ApiTlsConfig>,
+    /// Also serve the API on a unix socket
+    #[serde(default)]
+    pub api_unix_socket : Option<PathBuf>,
+    /// Largest module the API accepts in bytes, defaults to 32MiB
+    #[serde(default)]
+    pub api_max_module_size : Option<usize>,
+    /// Endpoints allowed to manage this node over the fern/admin ALPN. Empty disables it
+    #[serde(default)]
+    pub admin_endpoints : Vec<TrustedKey>,
+    /// Control plane shared with other Fern servers for cluster wide deployments
+    #[serde(default)]
+    pub cluster : ClusterConfig,
+    /// Serve guests which export httpHandler over HTTP. Unset leaves it off
+    #[serde(default)]
+    pub http_gateway : Option<HttpGatewayConfig>,
 }
 
 pub enum Commands {
@@ -47,6 +105,12 @@ pub enum Commands {
     UpdateBootstrap(UpdateBootstrap),
     UpdateModule(UpdateModule),
     RemoveModule(RemoveModule),
+    RollbackModule(RollbackModule),
+    ModuleHistory(ModuleHistory),
+    AnnounceModule(AnnounceModule),
+    FetchModule(FetchModule),
+    ValidateModule(ValidateModule),
+    GuestSender(GuestSender),
     GetInfo(GetInfo),
 }
 
@@ -64,7 +128,15 @@ pub struct ServerBuilder {
 
 impl ServerBuilder {
     pub fn start(self) -> Server {
+        let server = Server {
+            sender: self.sender,
+        };
+        let router_builder = setup_admin(self.router_builder, server.clone(), &self.config.admin_endpoints);
+        let (endpoint, receiver, config) = (self.endpoint, self.receiver, self.config);
+
+        let task_server = server.clone();
         thread::spawn(move || {
+            let server = task_server;
             let rt = tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
                 .build()
@@ -72,16 +144,14 @@ impl ServerBuilder {
             let local_set = LocalSet::new();
 
             local_set.block_on(&rt, async move {
-                let _task = Server::start(self.endpoint, self.router_builder, self.receiver, self.config);
+                let _task = Server::start(endpoint, router_builder, receiver, server, config);
                 loop {
                     tokio::time::sleep(Duration::from_secs(1)).await;
                 }
             });
         });
 
-        Server {
-            sender: self.sender,
-        }
+        server
     }
 
     pub fn with_secret(mut self, node_secret : SecretKey) -> Self {
@@ -95,7 +165,7 @@ impl ServerBuilder {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Server {
     sender: CommandSender,
     //task: Arc<JoinHandle<anyhow::Result<()>>>,
@@ -127,27 +197,32 @@ impl Server {
         endpoint: Endpoint,
         router_builder: RouterBuilder,
         receiver: CommandReceiver,
+        server: Server,
         config: Config,
     ) -> Arc<JoinHandle<anyhow::Result<()>>> {
         Arc::new(tokio::task::spawn_local(server_task(
             endpoint,
             router_builder,
             receiver,
+            server,
             config,
         )))
     }
 
     pub fn new(endpoint: Endpoint, router_builder: RouterBuilder, config: Config) -> Self {
         let (sender, rx) = mpsc::channel(100);
+        let server = Self { sender };
+        let router_builder = setup_admin(router_builder, server.clone(), &config.admin_endpoints);
 
         let task = Arc::new(tokio::task::spawn_local(server_task(
             endpoint,
             router_builder,
             rx,
+            server.clone(),
             config,
         )));
 
-        Self { sender }
+        server
     }
 }
 
@@ -155,36 +230,59 @@ pub async fn server_task(
     endpoint: Endpoint,
     router_builder: RouterBuilder,
     mut command_receiver: CommandReceiver,
+    // Handle back to this server for protocols which issue commands themselves
+    server: Server,
     config : Config,
 ) -> anyhow::Result<()> {
-    let Config { db_path, host_data_path,.. } = config;
+    let Config { db_path, host_data_path, guest_tcp, default_restart_policy, default_guest_limits, allowed_capabilities, cluster, .. } = config;
     info!("Starting Fern 🌿 Server");
 
     let data = if let Some(db_path) = db_path {
-        Data::new_path(&db_path)
+        // Modules go next to the guests' data when there is somewhere for it
+        let blob_path = host_data_path
+            .as_ref()
+            .map(|path| path.join(".blobs"))
+            .unwrap_or_else(|| db_path.with_extension("blobs"));
+        Data::new_path(&db_path, &blob_path)
     } else {
         warn!("Database path was not configured using in memory DB. All data will be lost!");
         Data::new_memory()
     };
 
-    let (router_builder, _gossip) = setup_gossip(router_builder, endpoint.clone());
+    // Clean up after deploys which stored a module but failed before a row referred to it
+    match data.collect_garbage() {
+        Ok(removed) => info!("Removed {removed} unreferenced module blobs"),
+        Err(e) => warn!("Failed to collect unreferenced module blobs: {e}"),
+    }
+
+    let (router_builder, gossip) = setup_gossip(router_builder, endpoint.clone());
+    let (router_builder, cluster) = setup_cluster(router_builder, gossip, endpoint.clone(), server, cluster);
     let _router = router_builder.spawn();
 
     // TODO we should store additional known peers somewhere..
     let mut bootstrap = vec![endpoint.id()];
 
+    // Node wide guest settings, the name is filled in per guest
+    let guest_defaults = GuestConfig {
+        name: String::new(),
+        host_data_path,
+        tcp: guest_tcp,
+        limits: default_guest_limits,
+        capabilities: allowed_capabilities,
+    };
+
     // Guest Instances
     let mut instance_map: InstanceMap = BTreeMap::new();
 
     // Bring any existing guests back online
-    handle_start_start(&data, bootstrap.clone(), &mut instance_map, host_data_path.clone()).await?;
+    handle_start_start(&data, bootstrap.clone(), &mut instance_map, &guest_defaults, &default_restart_policy).await?;
 
     info!("Entering server event loop");
     while let Some(cmd) = command_receiver.recv().await {
         let res = match cmd {
             Commands::CreateModule(create_module) => {
                 info!("Processing CreateModule Command");
-                handle_create_module(&data, host_data_path.clone(), create_module, bootstrap.clone(), &mut instance_map)
+                handle_create_module(&data, &guest_defaults, &default_restart_policy, create_module, bootstrap.clone(), &mut instance_map)
                     .await
             }
             Commands::UpdateBootstrap(update_bootstrap) => {
@@ -193,13 +291,39 @@ pub async fn server_task(
             }
             Commands::UpdateModule(update_module) => {
                 info!("Processing UpdateModule Command");
-                handle_update_module(&data, update_module, &mut instance_map, bootstrap.clone(), host_data_path.clone())
+                handle_update_module(&data, update_module, &mut instance_map, bootstrap.clone(), &guest_defaults)
                     .await
             }
             Commands::RemoveModule(remove_module) => {
                 info!("Processing RemoveModule Command");
                 handle_remove_module(&data, remove_module, &mut instance_map).await
             }
+            Commands::RollbackModule(rollback_module) => {
+                info!("Processing RollbackModule Command");
+                handle_rollback_module(&data, rollback_module, &mut instance_map, bootstrap.clone(), &guest_defaults)
+                    .await
+            }
+            Commands::ModuleHistory(module_history) => {
+                info!("Processing ModuleHistory Command");
+                handle_module_history(&data, module_history).await
+            }
+            Commands::AnnounceModule(announce_module) => {
+                info!("Processing AnnounceModule Command");
+                handle_announce_module(announce_module, cluster.as_ref()).await
+            }
+            Commands::FetchModule(fetch_module) => {
+                info!("Processing FetchModule Command");
+                handle_fetch_module(&data, fetch_module).await
+            }
+            Commands::ValidateModule(validate_module) => {
+                info!("Processing ValidateModule Command");
+                handle_validate_module(&guest_defaults, validate_module).await
+            }
+            Commands::GuestSender(guest_sender) => {
+                // Once per gateway request, too often for info
+                log::debug!("Processing GuestSender Command");
+                handle_guest_sender(guest_sender, &instance_map).await
+            }
             Commands::GetInfo(get_info) => {
                 info!("Processing GetInfo Command");
                 handle_get_info(get_info, &endpoint, &instance_map).await
@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use fern_runtime::capabilities::CapabilityManifest;
+use iroh::{
+    EndpointId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler, RouterBuilder},
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    api::{ApiScope, auth::TrustedKey},
+    server::{ModuleOptions, RollbackTarget, Server},
+};
+
+pub const ADMIN_ALPN: &[u8] = b"fern/admin/0";
+
+// Requests carry whole modules
+pub const MAX_ADMIN_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Management commands sent over the admin ALPN, one per connection.
+/// Modules aren't part of the JSON, see [`AdminRequest::encode`]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum AdminRequest {
+    ListGuests,
+    CreateModule {
+        guest_name: String,
+        #[serde(skip)]
+        module: Vec<u8>,
+        #[serde(flatten)]
+        options: ModuleOptions,
+    },
+    UpdateModule {
+        guest_name: String,
+        #[serde(skip)]
+        module: Vec<u8>,
+        #[serde(flatten)]
+        options: ModuleOptions,
+    },
+    RemoveModule {
+        guest_name: String,
+    },
+    DeployModule {
+        guest_name: String,
+        #[serde(skip)]
+        module: Vec<u8>,
+        #[serde(flatten)]
+        options: ModuleOptions,
+        #[serde(default)]
+        cluster: bool,
+    },
+    RollbackModule {
+        guest_name: String,
+        to: Option<RollbackTarget>,
+    },
+    ValidateModule {
+        #[serde(skip)]
+        module: Vec<u8>,
+        #[serde(default)]
+        capabilities: Option<CapabilityManifest>,
+    },
+    ModuleHistory {
+        guest_name: String,
+    },
+}
+
+impl AdminRequest {
+    /// Same scopes as the equivalent HTTP routes
+    pub fn required_scope(&self) -> ApiScope {
+        match self {
+            AdminRequest::ListGuests | AdminRequest::ModuleHistory { .. } => ApiScope::ReadOnly,
+            AdminRequest::CreateModule { .. }
+            | AdminRequest::UpdateModule { .. }
+            | AdminRequest::RollbackModule { .. }
+            | AdminRequest::DeployModule { .. }
+            | AdminRequest::ValidateModule { .. } => ApiScope::Deploy,
+            AdminRequest::RemoveModule { .. } => ApiScope::Admin,
+        }
+    }
+
+    fn module(&self) -> Option<&[u8]> {
+        match self {
+            AdminRequest::CreateModule { module, .. }
+            | AdminRequest::UpdateModule { module, .. }
+            | AdminRequest::DeployModule { module, .. }
+            | AdminRequest::ValidateModule { module, .. } => Some(module),
+            _ => None,
+        }
+    }
+
+    fn module_mut(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            AdminRequest::CreateModule { module, .. }
+            | AdminRequest::UpdateModule { module, .. }
+            | AdminRequest::DeployModule { module, .. }
+            | AdminRequest::ValidateModule { module, .. } => Some(module),
+            _ => None,
+        }
+    }
+
+    /// A big endian u32 length and the request as JSON, followed by the raw module
+    /// bytes for requests which carry one. Keeps modules out of the JSON
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let header = serde_json::to_vec(self)?;
+        let module = self.module().unwrap_or_default();
+        let mut bytes = Vec::with_capacity(4 + header.len() + module.len());
+        bytes.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(module);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let (len, rest) = bytes
+            .split_first_chunk::<4>()
+            .ok_or_else(|| anyhow!("missing header length"))?;
+        let len = u32::from_be_bytes(*len) as usize;
+        if rest.len() < len {
+            return Err(anyhow!("header is truncated"));
+        }
+        let (header, module) = rest.split_at(len);
+
+        let mut request: AdminRequest = serde_json::from_slice(header)?;
+        match request.module_mut() {
+            Some(slot) => *slot = module.to_vec(),
+            None if !module.is_empty() => return Err(anyhow!("request doesn't take a module")),
+            None => {}
+        }
+        Ok(request)
+    }
+}
+
+/// The command's response as JSON, or why it failed
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdminResponse {
+    Ok(Value),
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+struct AdminProtocol {
+    server: Server,
+    allowed: BTreeMap<EndpointId, ApiScope>,
+}
+
+impl ProtocolHandler for AdminProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let from = connection.remote_id();
+        // Unknown endpoints are turned away before anything is read
+        let Some(scope) = self.allowed.get(&from).copied() else {
+            warn!("Rejected admin connection from unknown endpoint {from}");
+            connection.close(1u32.into(), b"not allowed");
+            return Ok(());
+        };
+        let (mut send, mut recv) = connection.accept_bi().await?;
+
+        let bytes = recv
+            .read_to_end(MAX_ADMIN_MESSAGE_SIZE)
+            .await
+            .map_err(AcceptError::from_err)?;
+
+        let response = match AdminRequest::decode(&bytes) {
+            Ok(request) => self.handle(from, scope, request).await,
+            Err(e) => AdminResponse::Error(format!("malformed admin request: {e}")),
+        };
+
+        let bytes = serde_json::to_vec(&response).map_err(AcceptError::from_err)?;
+        send.write_all(&bytes)
+            .await
+            .map_err(AcceptError::from_err)?;
+        send.finish().map_err(AcceptError::from_err)?;
+
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+impl AdminProtocol {
+    async fn handle(
+        &self,
+        from: EndpointId,
+        scope: ApiScope,
+        request: AdminRequest,
+    ) -> AdminResponse {
+        let required = request.required_scope();
+        if scope < required {
+            warn!("Admin request from {from} needs {required:?} but has {scope:?}");
+            return AdminResponse::Error(format!(
+                "{scope:?} scope can't make {required:?} requests"
+            ));
+        }
+
+        info!("Processing admin request from {from}");
+        match self.dispatch(request).await {
+            Ok(value) => AdminResponse::Ok(value),
+            Err(e) => AdminResponse::Error(e.to_string()),
+        }
+    }
+
+    async fn dispatch(&self, request: AdminRequest) -> anyhow::Result<Value> {
+        let server = &self.server;
+        Ok(match request {
+            AdminRequest::ListGuests => serde_json::to_value(server.guest_info().await?)?,
+            AdminRequest::CreateModule {
+                guest_name,
+                module,
+                options,
+            } => serde_json::to_value(server.create_module(guest_name, module, options).await?)?,
+            AdminRequest::UpdateModule {
+                guest_name,
+                module,
+                options,
+            } => serde_json::to_value(server.update_module(guest_name, module, options).await?)?,
+            AdminRequest::RemoveModule { guest_name } => {
+                serde_json::to_value(server.remove_module(guest_name).await?)?
+            }
+            AdminRequest::DeployModule {
+                guest_name,
+                module,
+                options,
+                cluster,
+            } => serde_json::to_value(
+                server
+                    .deploy_module(guest_name, module, options, cluster)
+                    .await?,
+            )?,
+            AdminRequest::RollbackModule { guest_name, to } => {
+                serde_json::to_value(server.rollback_module(guest_name, to).await?)?
+            }
+            AdminRequest::ValidateModule {
+                module,
+                capabilities,
+            } => serde_json::to_value(server.validate_module(module, capabilities).await?)?,
+            AdminRequest::ModuleHistory { guest_name } => {
+                serde_json::to_value(server.module_history(guest_name).await?)?
+            }
+        })
+    }
+}
+
+/// Serve management commands over iroh for the allow-listed endpoints.
+/// Nothing is registered when no endpoints are allowed.
+pub fn setup_admin(router_builder: RouterBuilder, server: Server, admin_endpoints: &[TrustedKey]) -> RouterBuilder {
+    if admin_endpoints.is_empty() {
+        return router_builder;
+    }
+
+    let allowed = admin_endpoints
+        .iter()
+        .map(|trusted| (trusted.key, trusted.scope))
+        .collect();
+
+    router_builder.accept(ADMIN_ALPN, AdminProtocol { server, allowed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_request_wire_format() {
+        let request: AdminRequest = serde_json::from_str(
+            r#"{"command":"create-module","guest_name":"a","restart_policy":{"policy":"never"}}"#,
+        )
+        .unwrap();
+        assert_eq!(request.required_scope(), ApiScope::Deploy);
+        let AdminRequest::CreateModule { options, .. } = request else {
+            panic!("expected create-module");
+        };
+        assert!(options.restart_policy.is_some());
+
+        // Modules travel after the JSON rather than inside it
+        let request = AdminRequest::DeployModule {
+            guest_name: "a".to_string(),
+            module: b"\0asm".to_vec(),
+            options: ModuleOptions::default(),
+            cluster: true,
+        };
+        let bytes = request.encode().unwrap();
+        assert!(bytes.ends_with(b"\0asm"));
+        assert!(!String::from_utf8_lossy(&bytes).contains(r#""module""#));
+        let AdminRequest::DeployModule {
+            module, cluster, ..
+        } = AdminRequest::decode(&bytes).unwrap()
+        else {
+            panic!("expected deploy-module");
+        };
+        assert_eq!(module, b"\0asm");
+        assert!(cluster);
+
+        let request: AdminRequest = serde_json::from_str(r#"{"command":"list-guests"}"#).unwrap();
+        assert_eq!(request.required_scope(), ApiScope::ReadOnly);
+
+        let request: AdminRequest =
+            serde_json::from_str(r#"{"command":"remove-module","guest_name":"a"}"#).unwrap();
+        assert_eq!(request.required_scope(), ApiScope::Admin);
+        let mut bytes = request.encode().unwrap();
+        bytes.extend_from_slice(b"\0asm");
+        assert!(AdminRequest::decode(&bytes).is_err());
+    }
+}
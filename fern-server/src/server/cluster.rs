@@ -0,0 +1,479 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use fern_runtime::{gossip::GossipMessage, guest_fns::gossip::topic_id};
+use iroh::{
+    Endpoint, EndpointId, SecretKey, Signature,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler, RouterBuilder},
+};
+use iroh_gossip::{Gossip, api::Event};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::StreamExt;
+
+use crate::{
+    data::{Data, GuestRow},
+    server::{ModuleOptions, Server},
+};
+
+/// Gossip topic shared by every Fern server in a cluster
+pub const CLUSTER_TOPIC: &str = "fern-cluster";
+/// Serves module bytes to peers acting on a deployment announcement
+pub const MODULE_ALPN: &[u8] = b"fern/module/0";
+
+const MAX_MODULE_SIZE: usize = 64 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Which nodes' deployments this node runs
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "policy", rename_all = "kebab-case")]
+pub enum ClusterTrust {
+    /// Announce our own deployments but never act on others
+    #[default]
+    None,
+    /// Deployments from the configured cluster peers
+    Peers,
+    /// Deployments from these nodes
+    Nodes { nodes: Vec<EndpointId> },
+    /// Deployments from any node which can join the topic
+    Any,
+}
+
+/// Control plane shared with other Fern servers
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Other Fern servers to join the control plane topic through
+    #[serde(default)]
+    pub peers: Vec<EndpointId>,
+    #[serde(default)]
+    pub trust: ClusterTrust,
+}
+
+impl ClusterConfig {
+    pub fn trusts(&self, node: &EndpointId) -> bool {
+        match &self.trust {
+            ClusterTrust::None => false,
+            ClusterTrust::Peers => self.peers.contains(node),
+            ClusterTrust::Nodes { nodes } => nodes.contains(node),
+            ClusterTrust::Any => true,
+        }
+    }
+
+    /// Module bytes only go to nodes we'd share a deployment with
+    pub fn serves(&self, node: &EndpointId) -> bool {
+        self.peers.contains(node) || self.trusts(node)
+    }
+}
+
+// Gossip only tells us who forwarded a message, so announcements are signed by the
+// node they claim to come from
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedMessage {
+    message: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl SignedMessage {
+    fn sign(secret: &SecretKey, message: &GossipMessage) -> anyhow::Result<Self> {
+        let message = serde_json::to_vec(message)?;
+        let signature = secret.sign(&message).to_bytes().to_vec();
+        Ok(Self { message, signature })
+    }
+
+    /// The message, if it's a deployment announcement signed by the node it names
+    fn verify(&self) -> anyhow::Result<GossipMessage> {
+        let message: GossipMessage = serde_json::from_slice(&self.message)?;
+        let GossipMessage::GuestCreated { node_id, .. } = &message else {
+            return Err(anyhow!("unexpected control plane message"));
+        };
+        let signature: [u8; 64] = self
+            .signature
+            .as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("malformed signature"))?;
+        node_id
+            .verify(&self.message, &Signature::from_bytes(&signature))
+            .map_err(|_| anyhow!("announcement from {node_id} has an invalid signature"))?;
+        Ok(message)
+    }
+}
+
+/// Deployment announced by this node
+pub struct Announcement {
+    pub name: String,
+    pub module_hash: blake3::Hash,
+}
+
+/// Held by the server task to announce local deployments
+#[derive(Clone)]
+pub struct ClusterHandle {
+    announce_tx: mpsc::Sender<Announcement>,
+}
+
+impl ClusterHandle {
+    pub async fn announce(&self, announcement: Announcement) -> anyhow::Result<()> {
+        self.announce_tx
+            .send(announcement)
+            .await
+            .map_err(|_| anyhow!("cluster task is not running"))
+    }
+}
+
+pub struct AnnounceModule {
+    pub name: String,
+    pub module_hash: String,
+    pub reply: oneshot::Sender<bool>,
+}
+
+pub struct FetchModule {
+    pub name: String,
+    pub module_hash: String,
+    pub reply: oneshot::Sender<Option<Vec<u8>>>,
+}
+
+impl Server {
+    /// Announce a locally deployed guest to the cluster. False if clustering is disabled
+    pub async fn announce_module(&self, name: String, module_hash: String) -> anyhow::Result<bool> {
+        let (tx, rx) = oneshot::channel();
+        let cmd = AnnounceModule {
+            name,
+            module_hash,
+            reply: tx,
+        };
+
+        self.sender.send(super::Commands::AnnounceModule(cmd)).await?;
+
+        Ok(rx.await?)
+    }
+
+    /// Module bytes for a guest, only if `module_hash` is what it currently runs
+    pub async fn fetch_module(&self, name: String, module_hash: String) -> anyhow::Result<Option<Vec<u8>>> {
+        let (tx, rx) = oneshot::channel();
+        let cmd = FetchModule {
+            name,
+            module_hash,
+            reply: tx,
+        };
+
+        self.sender.send(super::Commands::FetchModule(cmd)).await?;
+
+        Ok(rx.await?)
+    }
+}
+
+pub(crate) async fn handle_announce_module(
+    cmd: AnnounceModule,
+    cluster: Option<&ClusterHandle>,
+) -> anyhow::Result<()> {
+    let announced = match cluster {
+        Some(cluster) => {
+            let module_hash = blake3::Hash::from_hex(&cmd.module_hash)?;
+            cluster
+                .announce(Announcement {
+                    name: cmd.name,
+                    module_hash,
+                })
+                .await?;
+            true
+        }
+        None => false,
+    };
+
+    cmd.reply
+        .send(announced)
+        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+    Ok(())
+}
+
+pub(crate) async fn handle_fetch_module(data: &Data, cmd: FetchModule) -> anyhow::Result<()> {
+    // Only the current module is served so replayed announcements can't roll peers back
+    let module = match GuestRow::by_name(data, &cmd.name)? {
+        Some(guest) if guest.module_hash == cmd.module_hash => Some(data.module(&guest.module_hash)?),
+        _ => None,
+    };
+
+    cmd.reply
+        .send(module)
+        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ModuleFetchRequest {
+    name: String,
+    module_hash: String,
+}
+
+#[derive(Debug, Clone)]
+struct ModuleFetchProtocol {
+    server: Server,
+    config: ClusterConfig,
+}
+
+impl ProtocolHandler for ModuleFetchProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let from = connection.remote_id();
+        if !self.config.serves(&from) {
+            warn!("Refusing module fetch from unknown node {from}");
+            connection.close(1u32.into(), b"not allowed");
+            return Ok(());
+        }
+        let (mut send, mut recv) = connection.accept_bi().await?;
+
+        let bytes = recv
+            .read_to_end(64 * 1024)
+            .await
+            .map_err(AcceptError::from_err)?;
+        let request: ModuleFetchRequest =
+            serde_json::from_slice(&bytes).map_err(AcceptError::from_err)?;
+
+        // An empty response means we don't have it
+        let module = self
+            .server
+            .fetch_module(request.name, request.module_hash)
+            .await
+            .map_err(|e| AcceptError::from_err(std::io::Error::other(e.to_string())))?
+            .unwrap_or_default();
+
+        send.write_all(&module)
+            .await
+            .map_err(AcceptError::from_err)?;
+        send.finish().map_err(AcceptError::from_err)?;
+
+        connection.closed().await;
+        Ok(())
+    }
+}
+
+async fn fetch_remote_module(
+    endpoint: &Endpoint,
+    node: EndpointId,
+    name: &str,
+    module_hash: &blake3::Hash,
+) -> anyhow::Result<Vec<u8>> {
+    let connection = endpoint.connect(node, MODULE_ALPN).await?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    let request = ModuleFetchRequest {
+        name: name.to_string(),
+        module_hash: module_hash.to_string(),
+    };
+    send.write_all(&serde_json::to_vec(&request)?).await?;
+    send.finish()?;
+
+    let module = recv.read_to_end(MAX_MODULE_SIZE).await?;
+    connection.close(0u32.into(), b"done");
+
+    if module.is_empty() {
+        return Err(anyhow!("{node} no longer serves {name} {module_hash}"));
+    }
+    if blake3::hash(&module) != *module_hash {
+        return Err(anyhow!("module for {name} from {node} doesn't match {module_hash}"));
+    }
+    Ok(module)
+}
+
+/// Join the control plane topic and serve our modules to peers.
+/// Nothing is set up unless clustering is enabled.
+pub fn setup_cluster(
+    router_builder: RouterBuilder,
+    gossip: Gossip,
+    endpoint: Endpoint,
+    server: Server,
+    config: ClusterConfig,
+) -> (RouterBuilder, Option<ClusterHandle>) {
+    if !config.enabled {
+        return (router_builder, None);
+    }
+
+    let router_builder = router_builder.accept(
+        MODULE_ALPN,
+        ModuleFetchProtocol {
+            server: server.clone(),
+            config: config.clone(),
+        },
+    );
+
+    let (announce_tx, announce_rx) = mpsc::channel(100);
+    tokio::spawn(async move {
+        if let Err(e) = cluster_task(gossip, endpoint, server, config, announce_rx).await {
+            warn!("Cluster task exited {e}");
+        }
+    });
+
+    (router_builder, Some(ClusterHandle { announce_tx }))
+}
+
+async fn cluster_task(
+    gossip: Gossip,
+    endpoint: Endpoint,
+    server: Server,
+    config: ClusterConfig,
+    mut announce_rx: mpsc::Receiver<Announcement>,
+) -> anyhow::Result<()> {
+    let topic = gossip
+        .subscribe(topic_id(CLUSTER_TOPIC), config.peers.clone())
+        .await?;
+    let (topic_tx, mut topic_rx) = topic.split();
+    info!("Joined cluster control plane with {} configured peers", config.peers.len());
+
+    loop {
+        tokio::select! {
+            announcement = announce_rx.recv() => {
+                let Some(Announcement { name, module_hash }) = announcement else {
+                    break;
+                };
+                let message = GossipMessage::GuestCreated {
+                    node_id: endpoint.id(),
+                    name: name.clone(),
+                    hash: module_hash.as_bytes().to_vec(),
+                };
+                let signed = SignedMessage::sign(endpoint.secret_key(), &message)?;
+                let res = topic_tx.broadcast(serde_json::to_vec(&signed)?.into()).await;
+                info!("Announced deployment of {name} {module_hash} to cluster {res:?}");
+            }
+
+            next = topic_rx.next() => {
+                let Some(Ok(next)) = next else {
+                    break;
+                };
+                let Event::Received(message) = next else {
+                    continue;
+                };
+                let message = serde_json::from_slice::<SignedMessage>(&message.content)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|signed| signed.verify());
+                match message {
+                    Ok(GossipMessage::GuestCreated { node_id, name, hash }) => {
+                        if node_id == endpoint.id() || !config.trusts(&node_id) {
+                            info!("Ignoring deployment of {name} from untrusted node {node_id}");
+                            continue;
+                        }
+                        let Ok(hash) = <[u8; 32]>::try_from(hash.as_slice()) else {
+                            warn!("Deployment of {name} from {node_id} has a malformed hash");
+                            continue;
+                        };
+                        let endpoint = endpoint.clone();
+                        let server = server.clone();
+                        // Fetching can be slow, don't hold up the topic
+                        tokio::spawn(async move {
+                            let module_hash = blake3::Hash::from_bytes(hash);
+                            if let Err(e) = deploy_announced(&endpoint, &server, node_id, &name, module_hash).await {
+                                warn!("Failed to deploy {name} {module_hash} from {node_id} {e}");
+                            }
+                        });
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Dropping control plane message {e}"),
+                }
+            }
+        }
+    }
+
+    warn!("Cluster task exiting");
+    Ok(())
+}
+
+async fn deploy_announced(
+    endpoint: &Endpoint,
+    server: &Server,
+    node: EndpointId,
+    name: &str,
+    module_hash: blake3::Hash,
+) -> anyhow::Result<()> {
+    let current = server
+        .guest_info()
+        .await?
+        .into_iter()
+        .find(|guest| guest.name == name);
+    if current
+        .as_ref()
+        .is_some_and(|guest| guest.module_hash == module_hash.to_string())
+    {
+        return Ok(());
+    }
+
+    let module = tokio::time::timeout(
+        FETCH_TIMEOUT,
+        fetch_remote_module(endpoint, node, name, &module_hash),
+    )
+    .await??;
+
+    // Peers use their own defaults for restart policy, limits and capabilities
+    if current.is_some() {
+        let response = server
+            .update_module(name.to_string(), module, ModuleOptions::default())
+            .await?;
+        if !response.success {
+            return Err(anyhow!(response.error.unwrap_or_default()));
+        }
+    } else {
+        server
+            .create_module(name.to_string(), module, ModuleOptions::default())
+            .await?;
+    }
+
+    info!("Deployed {name} {module_hash} announced by {node}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cluster_announcements_and_trust() {
+        let secret = SecretKey::generate(&mut rand::rng());
+        let other = SecretKey::generate(&mut rand::rng());
+        let message = GossipMessage::GuestCreated {
+            node_id: secret.public(),
+            name: "counter".to_string(),
+            hash: blake3::hash(b"module").as_bytes().to_vec(),
+        };
+
+        let signed = SignedMessage::sign(&secret, &message).unwrap();
+        assert!(signed.verify().is_ok());
+
+        // Signed by someone other than the node it names
+        let forged = SignedMessage::sign(&other, &message).unwrap();
+        assert!(forged.verify().is_err());
+
+        let config = ClusterConfig {
+            enabled: true,
+            peers: vec![secret.public()],
+            trust: ClusterTrust::Peers,
+        };
+        assert!(config.trusts(&secret.public()));
+        assert!(!config.trusts(&other.public()));
+        assert!(!ClusterConfig::default().trusts(&secret.public()));
+    }
+
+    #[test]
+    fn module_fetch_refuses_unknown_nodes() {
+        let peer = SecretKey::generate(&mut rand::rng()).public();
+        let trusted = SecretKey::generate(&mut rand::rng()).public();
+        let stranger = SecretKey::generate(&mut rand::rng()).public();
+
+        // Peers are served even when we don't run their deployments
+        let config = ClusterConfig {
+            enabled: true,
+            peers: vec![peer],
+            trust: ClusterTrust::Nodes {
+                nodes: vec![trusted],
+            },
+        };
+        assert!(config.serves(&peer));
+        assert!(config.serves(&trusted));
+        assert!(!config.serves(&stranger));
+
+        let any = ClusterConfig {
+            trust: ClusterTrust::Any,
+            ..config
+        };
+        assert!(any.serves(&stranger));
+    }
+}
@@ -1,21 +1,41 @@
-use std::{collections::btree_map::Entry, path::PathBuf};
+use std::collections::btree_map::Entry;
 
 use anyhow::anyhow;
-use fern_runtime::{guest::{GuestConfig, new_guest}, iroh_helpers::iroh_bundle};
+use fern_runtime::{
+    capabilities::CapabilityManifest,
+    guest::{GuestConfig, new_guest},
+    iroh_helpers::iroh_bundle,
+    limits::GuestLimits,
+    validation::validate_module,
+};
 use iroh::EndpointId;
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
 
 use crate::{
     data::{Data, GuestRow},
-    guest_instance::GuestInstance,
+    guest_instance::{GuestInstance, GuestSpec, RestartPolicy, Supervisor},
     server::{InstanceMap, Server},
 };
 
 pub struct CreateModule {
     name: String,
     module: Vec<u8>,
-    reply: oneshot::Sender<CreateResponse>,
+    options: ModuleOptions,
+    reply: oneshot::Sender<Result<CreateResponse, String>>,
+}
+
+/// Per guest settings which can be given on create and update.
+/// Anything left unset uses the server's defaults, or keeps the current value on update
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ModuleOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<GuestLimits>,
+    /// Overrides the manifest embedded in the module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<CapabilityManifest>,
 }
 
 impl Server {
@@ -23,17 +43,19 @@ impl Server {
         &self,
         name: String,
         module: Vec<u8>,
+        options: ModuleOptions,
     ) -> anyhow::Result<CreateResponse> {
         let (tx, rx) = oneshot::channel();
         let cmd = CreateModule {
             name,
             module,
+            options,
             reply: tx,
         };
 
         self.sender.send(super::Commands::CreateModule(cmd)).await?;
 
-        Ok(rx.await?)
+        rx.await?.map_err(|e| anyhow!(e))
     }
 }
 
@@ -44,38 +66,95 @@ pub struct CreateResponse {
 
 pub(crate) async fn handle_create_module(
     data: &Data,
-    host_data_path: Option<PathBuf>,
+    guest_defaults: &GuestConfig,
+    default_restart_policy: &RestartPolicy,
     cmd: CreateModule,
     bootstrap: Vec<EndpointId>,
     instance_map: &mut InstanceMap,
 ) -> anyhow::Result<()> {
-    let entry = match instance_map.entry(cmd.name.clone()) {
+    let res = create_guest(data, guest_defaults, default_restart_policy, &cmd, bootstrap, instance_map).await;
+
+    // Failures go back to the caller rather than just dropping the reply
+    let outcome = res.as_ref().map(|_| ()).map_err(|e| anyhow!("{e}"));
+    cmd.reply
+        .send(res.map_err(|e| e.to_string()))
+        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+    outcome
+}
+
+async fn create_guest(
+    data: &Data,
+    guest_defaults: &GuestConfig,
+    default_restart_policy: &RestartPolicy,
+    cmd: &CreateModule,
+    bootstrap: Vec<EndpointId>,
+    instance_map: &mut InstanceMap,
+) -> anyhow::Result<CreateResponse> {
+    let CreateModule { name, module, options, .. } = cmd;
+    let entry = match instance_map.entry(name.clone()) {
         Entry::Vacant(vacant_entry) => vacant_entry,
         Entry::Occupied(_) => {
-            return Err(anyhow!("Module with name {} already exists", cmd.name));
+            return Err(anyhow!("Module with name {} already exists", name));
         }
     };
 
-    let (endpoint, router_builder) = iroh_bundle().await?;
-    let guest_row = GuestRow::create(data, cmd.name, cmd.module)?;
+    // Refuse the module before anything is stored if it can't run here
+    let report = validate_module(module, options.capabilities.as_ref(), guest_defaults.capabilities.as_ref());
+    if !report.is_valid() {
+        return Err(anyhow!("Module failed validation: {}", report.error_message()));
+    }
 
+    let limits = options.limits.clone().unwrap_or_default();
     let guest_config = GuestConfig {
-        name: guest_row.name.clone(),
-        host_data_path: host_data_path,
+        name: name.clone(),
+        limits: limits.or(&guest_defaults.limits),
+        capabilities: Some(report.capabilities),
+        ..guest_defaults.clone()
+    };
+    let spec = GuestSpec {
+        module: module.clone(),
+        guest_config: guest_config.clone(),
+        bootstrap: bootstrap.clone(),
     };
-    let mut guest = new_guest(guest_config, guest_row.module, (endpoint, router_builder, bootstrap))?;
 
-    // TODO report module initialize failure
-    guest.initialize()?;
-    
-    let guest_instance = GuestInstance::new(guest, guest_row.module_hash, guest_row.id);
+    // The guest has to build and initialize before it's written down, so a
+    // broken module doesn't leave a row behind with no instance
+    let (endpoint, router_builder) = iroh_bundle().await?;
+    let mut guest = new_guest(guest_config, module.clone(), (endpoint, router_builder, bootstrap))?;
+    if let Err(e) = guest.initialize() {
+        // Nothing else holds the guest, tear down the networking it brought up
+        guest.endpoint.close().await;
+        let _ = guest.router.shutdown().await;
+        return Err(e);
+    }
+
+    let guest_row = match GuestRow::create(
+        data,
+        name.clone(),
+        module,
+        options.restart_policy.clone(),
+        limits,
+        options.capabilities.clone(),
+    ) {
+        Ok(guest_row) => guest_row,
+        Err(e) => {
+            // Initialized but never handed to an instance, so shut it down here
+            let _ = guest.shutdown();
+            guest.endpoint.close().await;
+            let _ = guest.router.shutdown().await;
+            return Err(e);
+        }
+    };
+
+    let restart_policy = guest_row
+        .restart_policy
+        .unwrap_or_else(|| default_restart_policy.clone());
+    let supervisor = Supervisor::new(restart_policy);
+
+    let guest_instance = GuestInstance::new(guest, spec, guest_row.module_hash, guest_row.id, supervisor);
     let endpoint_id = guest_instance.node_id();
 
-    
     entry.insert(guest_instance);
 
-    cmd.reply
-        .send(CreateResponse { endpoint_id })
-        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
-    Ok(())
+    Ok(CreateResponse { endpoint_id })
 }
@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+
+use crate::server::{ModuleOptions, Server};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployResponse {
+    pub success: bool,
+    /// False when an existing guest was updated
+    pub created: bool,
+    pub module_hash: String,
+    /// Whether the deployment was announced to the cluster
+    pub announced: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl Server {
+    /// Create the guest, or update it if it already exists. With `cluster` the
+    /// deployment is announced so trusting peers run the same module.
+    pub async fn deploy_module(
+        &self,
+        name: String,
+        module: Vec<u8>,
+        options: ModuleOptions,
+        cluster: bool,
+    ) -> anyhow::Result<DeployResponse> {
+        let module_hash = blake3::hash(&module).to_string();
+        let exists = self
+            .guest_info()
+            .await?
+            .iter()
+            .any(|guest| guest.name == name);
+
+        if exists {
+            let response = self.update_module(name.clone(), module, options).await?;
+            if !response.success {
+                return Ok(DeployResponse {
+                    success: false,
+                    created: false,
+                    module_hash,
+                    announced: false,
+                    error: response.error,
+                });
+            }
+        } else {
+            self.create_module(name.clone(), module, options).await?;
+        }
+
+        let announced = if cluster {
+            self.announce_module(name, module_hash.clone()).await?
+        } else {
+            false
+        };
+
+        let error = (cluster && !announced)
+            .then(|| "deployed locally but clustering is not enabled on this node".to_string());
+
+        Ok(DeployResponse {
+            success: true,
+            created: !exists,
+            module_hash,
+            announced,
+            error,
+        })
+    }
+}
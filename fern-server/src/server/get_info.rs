@@ -1,8 +1,14 @@
+use std::collections::BTreeSet;
+
+use fern_runtime::guest::GuestExport;
 use iroh::{Endpoint, EndpointId};
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
 
-use crate::server::{InstanceMap, Server};
+use crate::{
+    guest_instance::GuestStatus,
+    server::{InstanceMap, Server},
+};
 
 pub struct NodeAddress {
     pub reply: oneshot::Sender<NodeAddressResponse>,
@@ -13,6 +19,11 @@ pub struct GuestInfo {
     pub name: String,
     pub endpoint_id: EndpointId,
     pub module_hash: String,
+    #[serde(default)]
+    pub status: GuestStatus,
+    /// Exports Fern calls on this guest, anything else it never gets woken for
+    #[serde(default)]
+    pub exports: BTreeSet<GuestExport>,
 }
 
 pub struct Guests {
@@ -88,6 +99,8 @@ pub(crate) async fn handle_guests(cmd: Guests, instance_map: &InstanceMap) -> an
             name: name.clone(),
             endpoint_id: instance.node_id(),
             module_hash: instance.module_hash.clone(),
+            status: instance.status(),
+            exports: instance.exports.clone(),
         });
     }
 
@@ -0,0 +1,49 @@
+use fern_runtime::http::{HttpRequest, HttpResponse};
+use tokio::sync::oneshot;
+
+use crate::{
+    guest_instance::{self, send_http_request},
+    server::{InstanceMap, Server},
+};
+
+/// Look up a guest's command sender so requests skip the server task
+pub struct GuestSender {
+    pub guest_name: String,
+    pub reply: oneshot::Sender<Option<guest_instance::CommandSender>>,
+}
+
+impl Server {
+    /// Hand a request to the guest's `httpHandler`, None when there is no such guest
+    pub async fn guest_http_request(
+        &self,
+        guest_name: String,
+        request: HttpRequest,
+    ) -> anyhow::Result<Option<HttpResponse>> {
+        let (tx, rx) = oneshot::channel();
+        let cmd = GuestSender {
+            guest_name,
+            reply: tx,
+        };
+
+        self.sender.send(super::Commands::GuestSender(cmd)).await?;
+
+        let Some(sender) = rx.await? else {
+            return Ok(None);
+        };
+        Ok(Some(send_http_request(&sender, request).await?))
+    }
+}
+
+pub(crate) async fn handle_guest_sender(
+    cmd: GuestSender,
+    instance_map: &InstanceMap,
+) -> anyhow::Result<()> {
+    let sender = instance_map
+        .get(&cmd.guest_name)
+        .map(|instance| instance.sender());
+
+    cmd.reply
+        .send(sender)
+        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+    Ok(())
+}
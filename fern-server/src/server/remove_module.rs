@@ -63,6 +63,12 @@ pub(crate) async fn handle_remove_module(
 
     // 3. Remove from the database
     let db_removal_success = GuestRow::remove_by_name(data, &cmd.name)?;
+
+    // 4. Drop modules nothing refers to anymore, other guests may share this one
+    match data.collect_garbage() {
+        Ok(removed) => log::info!("Removed {} unreferenced module blobs", removed),
+        Err(e) => log::warn!("Failed to collect unreferenced module blobs: {}", e),
+    }
     
     let response = match (shutdown_result, db_removal_success) {
         (Ok(shutdown_response), true) if shutdown_response.success => {
@@ -0,0 +1,159 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use fern_runtime::guest::GuestConfig;
+use iroh::EndpointId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::{
+    data::{Data, GuestRow, ModuleRow},
+    server::{InstanceMap, ModuleOptions, Server, UpdateResponse, apply_module_update},
+};
+
+/// Which previously deployed module to go back to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RollbackTarget {
+    HistoryId(i64),
+    ModuleHash(String),
+}
+
+impl std::str::FromStr for RollbackTarget {
+    type Err = std::convert::Infallible;
+
+    // Anything numeric is treated as a history id, everything else as a module hash
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse::<i64>() {
+            Ok(id) => RollbackTarget::HistoryId(id),
+            Err(_) => RollbackTarget::ModuleHash(s.to_string()),
+        })
+    }
+}
+
+pub struct RollbackModule {
+    pub name: String,
+    pub to: Option<RollbackTarget>,
+    pub reply: oneshot::Sender<UpdateResponse>,
+}
+
+pub struct ModuleHistory {
+    pub name: String,
+    pub reply: oneshot::Sender<Vec<ModuleHistoryEntry>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModuleHistoryEntry {
+    pub id: i64,
+    pub module_hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Server {
+    /// Redeploy a module from the guest's history. Without a target the most recent
+    /// module which differs from the one currently deployed is used.
+    pub async fn rollback_module(
+        &self,
+        name: String,
+        to: Option<RollbackTarget>,
+    ) -> anyhow::Result<UpdateResponse> {
+        let (tx, rx) = oneshot::channel();
+        let cmd = RollbackModule {
+            name,
+            to,
+            reply: tx,
+        };
+
+        self.sender
+            .send(super::Commands::RollbackModule(cmd))
+            .await?;
+
+        Ok(rx.await?)
+    }
+
+    /// Previously deployed modules for a guest, newest first
+    pub async fn module_history(&self, name: String) -> anyhow::Result<Vec<ModuleHistoryEntry>> {
+        let (tx, rx) = oneshot::channel();
+        let cmd = ModuleHistory { name, reply: tx };
+
+        self.sender
+            .send(super::Commands::ModuleHistory(cmd))
+            .await?;
+
+        Ok(rx.await?)
+    }
+}
+
+pub(crate) async fn handle_rollback_module(
+    data: &Data,
+    cmd: RollbackModule,
+    instance_map: &mut InstanceMap,
+    bootstrap: Vec<EndpointId>,
+    guest_defaults: &GuestConfig,
+) -> anyhow::Result<()> {
+    let guest_row = GuestRow::by_name(data, &cmd.name)?
+        .ok_or_else(|| anyhow!("Guest with name {} does not exist", cmd.name))?;
+
+    let target = match &cmd.to {
+        Some(RollbackTarget::HistoryId(id)) => ModuleRow::by_id(data, guest_row.id, *id)?,
+        Some(RollbackTarget::ModuleHash(hash)) => {
+            ModuleRow::latest_by_hash(data, guest_row.id, hash)?
+        }
+        None => ModuleRow::all_by_guest_id(data, guest_row.id)?
+            .into_iter()
+            .find(|module| module.module_hash != guest_row.module_hash),
+    };
+
+    let Some(target) = target else {
+        return Err(anyhow!(
+            "No module history entry matching {:?} for guest {}",
+            cmd.to,
+            cmd.name
+        ));
+    };
+
+    log::info!(
+        "Rolling back guest {} from {} to {} (history id {})",
+        cmd.name,
+        guest_row.module_hash,
+        target.module_hash,
+        target.id
+    );
+
+    // The rollback is just another deploy so the module being replaced lands in history too
+    let response = apply_module_update(
+        data,
+        &cmd.name,
+        data.module(&target.module_hash)?,
+        ModuleOptions::default(),
+        instance_map,
+        bootstrap,
+        guest_defaults,
+    )
+    .await?;
+
+    cmd.reply
+        .send(response)
+        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+
+    Ok(())
+}
+
+pub(crate) async fn handle_module_history(data: &Data, cmd: ModuleHistory) -> anyhow::Result<()> {
+    let guest_row = GuestRow::by_name(data, &cmd.name)?
+        .ok_or_else(|| anyhow!("Guest with name {} does not exist", cmd.name))?;
+
+    let history = ModuleRow::all_by_guest_id(data, guest_row.id)?
+        .into_iter()
+        .map(|module| ModuleHistoryEntry {
+            id: module.id,
+            module_hash: module.module_hash,
+            created_at: module.created_at,
+        })
+        .collect();
+
+    cmd.reply
+        .send(history)
+        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+
+    Ok(())
+}
@@ -1,19 +1,24 @@
-use std::path::PathBuf;
-
 use fern_runtime::{
+    capabilities::resolve_capabilities,
     guest::{GuestConfig, new_guest},
     iroh_helpers::iroh_bundle,
 };
 use iroh::EndpointId;
 use log::{error, info};
 
-use crate::{Data, GuestInstance, data::GuestRow, server::InstanceMap};
+use crate::{
+    Data, GuestInstance,
+    data::GuestRow,
+    guest_instance::{GuestSpec, RestartPolicy, Supervisor},
+    server::InstanceMap,
+};
 
 pub async fn handle_start_start(
     data: &Data,
     bootstrap: Vec<EndpointId>,
     instance_map: &mut InstanceMap,
-    db_path: Option<PathBuf>,
+    guest_defaults: &GuestConfig,
+    default_restart_policy: &RestartPolicy,
 ) -> anyhow::Result<()> {
     let mut offset = 0;
     let limit = 10;
@@ -29,16 +34,47 @@ pub async fn handle_start_start(
             let guest_id = guest_row.id.clone();
             let guest_name = guest_row.name.clone();
 
+            let module = match data.module(&guest_row.module_hash) {
+                Ok(module) => module,
+                Err(e) => {
+                    error!("Can't start guest id={} name={} {e}", guest_id, guest_name);
+                    continue;
+                }
+            };
+
+            // Node policy may have changed since the guest was deployed
+            let capabilities = match resolve_capabilities(
+                &module,
+                guest_row.capabilities.as_ref(),
+                guest_defaults.capabilities.as_ref(),
+            ) {
+                Ok(capabilities) => capabilities,
+                Err(e) => {
+                    error!("Refusing to start guest id={} name={} {e}", guest_id, guest_name);
+                    continue;
+                }
+            };
+
             let guest_config = GuestConfig {
                 name: guest_name.clone(),
-                host_data_path: db_path.clone(),
+                limits: guest_row.limits.or(&guest_defaults.limits),
+                capabilities: Some(capabilities),
+                ..guest_defaults.clone()
             };
 
-            if let Ok(instance) = start_guest(guest_row, bootstrap.clone(), guest_config).await {
-                info!("Started guest id={} name={}", guest_id, guest_name);
-                instance_map.insert(guest_name, instance);
-            } else {
-                error!("Failed to start guest id={} name={}", guest_id, guest_name);
+            let restart_policy = guest_row
+                .restart_policy
+                .clone()
+                .unwrap_or_else(|| default_restart_policy.clone());
+
+            match start_guest(guest_row, module, bootstrap.clone(), guest_config, restart_policy).await {
+                Ok(instance) => {
+                    info!("Started guest id={} name={}", guest_id, guest_name);
+                    instance_map.insert(guest_name, instance);
+                }
+                Err(e) => {
+                    error!("Failed to start guest id={} name={} {e}", guest_id, guest_name);
+                }
             }
         }
     }
@@ -47,14 +83,29 @@ pub async fn handle_start_start(
 
 async fn start_guest(
     guest_row: GuestRow,
+    module: Vec<u8>,
     bootstrap: Vec<EndpointId>,
     guest_config: GuestConfig,
+    restart_policy: RestartPolicy,
 ) -> anyhow::Result<GuestInstance> {
+    let spec = GuestSpec {
+        module: module.clone(),
+        guest_config: guest_config.clone(),
+        bootstrap: bootstrap.clone(),
+    };
+
     let (endpoint, router_builder) = iroh_bundle().await?;
-    let mut guest = new_guest(guest_config, guest_row.module, (endpoint, router_builder, bootstrap))?;
-    guest.initialize()?;
+    let mut guest = new_guest(guest_config, module, (endpoint, router_builder, bootstrap))?;
+
+    // A guest which fails to initialize is still brought online so the failure
+    // shows up in its status. The supervisor decides if and when it restarts
+    let mut supervisor = Supervisor::new(restart_policy);
+    if let Err(e) = guest.initialize() {
+        error!("Guest id={} name={} failed to initialize {e}", guest_row.id, guest_row.name);
+        supervisor.record_failure(&e);
+    }
 
-    let guest_instance = GuestInstance::new(guest, guest_row.module_hash, guest_row.id);
+    let guest_instance = GuestInstance::new(guest, spec, guest_row.module_hash, guest_row.id, supervisor);
 
     Ok(guest_instance)
 }
@@ -1,6 +1,7 @@
 use std::collections::btree_map::Entry;
 
 use anyhow::anyhow;
+use fern_runtime::{guest::GuestConfig, validation::validate_module};
 use iroh::EndpointId;
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
@@ -8,12 +9,13 @@ use tokio::sync::oneshot;
 use crate::{
     data::{Data, GuestRow},
     guest_instance::UpdateModuleResponse,
-    server::{InstanceMap, Server},
+    server::{InstanceMap, ModuleOptions, Server},
 };
 
 pub struct UpdateModule {
     pub name: String,
     pub module: Vec<u8>,
+    pub options: ModuleOptions,
     pub reply: oneshot::Sender<UpdateResponse>,
 }
 
@@ -22,11 +24,13 @@ impl Server {
         &self,
         name: String,
         module: Vec<u8>,
+        options: ModuleOptions,
     ) -> anyhow::Result<UpdateResponse> {
         let (tx, rx) = oneshot::channel();
         let cmd = UpdateModule {
             name,
             module,
+            options,
             reply: tx,
         };
 
@@ -41,6 +45,9 @@ pub struct UpdateResponse {
     pub success: bool,
     pub module_hash: String,
     pub previous_hash: Option<String>,
+    /// Why the update was rejected, the previous module keeps running
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 pub(crate) async fn handle_update_module(
@@ -48,58 +55,134 @@ pub(crate) async fn handle_update_module(
     cmd: UpdateModule,
     instance_map: &mut InstanceMap,
     bootstrap: Vec<EndpointId>,
-    host_data_path: Option<std::path::PathBuf>,
+    guest_defaults: &GuestConfig,
 ) -> anyhow::Result<()> {
-    let mut entry = match instance_map.entry(cmd.name.clone()) {
+    let response = apply_module_update(
+        data,
+        &cmd.name,
+        cmd.module,
+        cmd.options,
+        instance_map,
+        bootstrap,
+        guest_defaults,
+    )
+    .await?;
+
+    cmd.reply
+        .send(response)
+        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+
+    Ok(())
+}
+
+/// Persist `module` as the guest's current module and hot swap the running instance
+pub(crate) async fn apply_module_update(
+    data: &Data,
+    name: &str,
+    module: Vec<u8>,
+    options: ModuleOptions,
+    instance_map: &mut InstanceMap,
+    bootstrap: Vec<EndpointId>,
+    guest_defaults: &GuestConfig,
+) -> anyhow::Result<UpdateResponse> {
+    let mut entry = match instance_map.entry(name.to_string()) {
         Entry::Vacant(_) => {
-            return Err(anyhow!("Guest with name {} does not exist", cmd.name));
+            return Err(anyhow!("Guest with name {} does not exist", name));
         }
         Entry::Occupied(occupied_entry) => occupied_entry,
     };
 
-    // Get the current guest to capture the previous hash
-    let previous_hash = if let Some(current_guest) = GuestRow::by_name(data, &cmd.name)? {
-        Some(current_guest.module_hash)
-    } else {
-        None
-    };
-
-    // Update the module (this will automatically save the old version to history)
-    let db_update_success = GuestRow::update_module_by_name(data, &cmd.name, &cmd.module)?;
+    // Get the current guest to capture the previous hash and settings
+    let current_guest = GuestRow::by_name(data, name)?;
+    let previous_hash = current_guest.as_ref().map(|guest| guest.module_hash.clone());
+
+    // Options which weren't given keep their current value
+    let limits = options
+        .limits
+        .clone()
+        .or_else(|| current_guest.as_ref().map(|guest| guest.limits.clone()))
+        .unwrap_or_default();
+    let manifest = options
+        .capabilities
+        .clone()
+        .or_else(|| current_guest.as_ref().and_then(|guest| guest.capabilities.clone()));
 
     // Calculate the new hash for the response
-    let module_hash = blake3::hash(&cmd.module).to_string();
-
-    // Only proceed with guest instance update if database update was successful
-    let final_success = if db_update_success {
-        // Send the command to update the guest instance
-        let guest_instance = entry.get_mut();
-        let UpdateModuleResponse {
-            success: instance_update_success,
-            error_message,
-        } = guest_instance
-            .update_module(cmd.module, module_hash.clone(), cmd.name.clone(), host_data_path, bootstrap)
-            .await?;
-
-        if !instance_update_success {
-            if let Some(error) = error_message {
-                log::warn!("Guest instance update failed: {}", error);
-            }
-        }
-
-        instance_update_success
-    } else {
-        log::warn!("Database update failed for guest: {}", cmd.name);
-        false
+    let module_hash = blake3::hash(&module).to_string();
+
+    let report = validate_module(&module, manifest.as_ref(), guest_defaults.capabilities.as_ref());
+    if !report.is_valid() {
+        let error = report.error_message();
+        log::warn!("Rejected module update for {}: {error}", name);
+        return Ok(UpdateResponse {
+            success: false,
+            module_hash,
+            previous_hash,
+            error: Some(error),
+        });
+    }
+    let capabilities = report.capabilities;
+
+    // Bring the new module up first. The guest instance only swaps over once
+    // the new module has initialized, otherwise the old one keeps running
+    let guest_instance = entry.get_mut();
+    let guest_config = GuestConfig {
+        name: name.to_string(),
+        limits: limits.or(&guest_defaults.limits),
+        capabilities: Some(capabilities),
+        ..guest_defaults.clone()
     };
-
-    cmd.reply
-        .send(UpdateResponse {
-            success: final_success,
+    let UpdateModuleResponse {
+        success: instance_update_success,
+        error_message,
+        ..
+    } = guest_instance
+        .update_module(
+            module.clone(),
+            module_hash.clone(),
+            guest_config,
+            bootstrap,
+            options.restart_policy.clone(),
+        )
+        .await?;
+
+    if !instance_update_success {
+        log::warn!(
+            "Guest instance update failed for {}: {}",
+            name,
+            error_message.as_deref().unwrap_or("unknown error")
+        );
+        return Ok(UpdateResponse {
+            success: false,
             module_hash,
             previous_hash,
-        })
-        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+            error: error_message,
+        });
+    }
 
-    Ok(())
+    // Only persist modules which made it through initialization
+    // (this will automatically save the old version to history)
+    let db_update_success = GuestRow::update_module_by_name(data, name, &module)?;
+    if options.limits.is_some() {
+        GuestRow::update_limits_by_name(data, name, &limits)?;
+    }
+    if options.capabilities.is_some() {
+        GuestRow::update_capabilities_by_name(data, name, manifest.as_ref())?;
+    }
+    if let Some(restart_policy) = &options.restart_policy {
+        GuestRow::update_restart_policy_by_name(data, name, Some(restart_policy))?;
+    }
+    let error = if db_update_success {
+        None
+    } else {
+        log::warn!("Database update failed for guest: {}", name);
+        Some("module is running but could not be saved".to_string())
+    };
+
+    Ok(UpdateResponse {
+        success: db_update_success,
+        module_hash,
+        previous_hash,
+        error,
+    })
 }
@@ -0,0 +1,51 @@
+use fern_runtime::{
+    capabilities::CapabilityManifest,
+    guest::GuestConfig,
+    validation::{ValidationReport, validate_module},
+};
+use tokio::sync::oneshot;
+
+use crate::server::Server;
+
+pub struct ValidateModule {
+    pub module: Vec<u8>,
+    pub capabilities: Option<CapabilityManifest>,
+    pub reply: oneshot::Sender<ValidationReport>,
+}
+
+impl Server {
+    /// Check a module the way create and update would, against this node's policy,
+    /// without deploying it
+    pub async fn validate_module(
+        &self,
+        module: Vec<u8>,
+        capabilities: Option<CapabilityManifest>,
+    ) -> anyhow::Result<ValidationReport> {
+        let (tx, rx) = oneshot::channel();
+        let cmd = ValidateModule {
+            module,
+            capabilities,
+            reply: tx,
+        };
+
+        self.sender.send(super::Commands::ValidateModule(cmd)).await?;
+
+        Ok(rx.await?)
+    }
+}
+
+pub(crate) async fn handle_validate_module(
+    guest_defaults: &GuestConfig,
+    cmd: ValidateModule,
+) -> anyhow::Result<()> {
+    let report = validate_module(
+        &cmd.module,
+        cmd.capabilities.as_ref(),
+        guest_defaults.capabilities.as_ref(),
+    );
+
+    cmd.reply
+        .send(report)
+        .map_err(|_| anyhow::anyhow!("Failed to send response"))?;
+    Ok(())
+}
@@ -3,7 +3,7 @@ use std::time::Duration;
 use dioxus::{
     fullstack::{
         serde::{Deserialize, Serialize},
-        ServerEvents,
+        MultipartFormData, ServerEvents,
     },
     prelude::*,
 };
@@ -67,15 +67,24 @@ pub async fn listen_list_guests() -> Result<ServerEvents<Vec<GuestInfo>>> {
     Ok(sse)
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
-pub struct CreateGuest {
-    pub name: String,
-    pub module: Vec<u8>,
-}
-
+/// Takes the create guest form as multipart so the module isn't sent as a JSON array
 #[post("/api/server/guest", ext: crate::AppStateExtension)]
-pub async fn create_guest(req: CreateGuest) -> Result<String> {
-    let res = ext.server.create_module(req.name, req.module).await?;
+pub async fn create_guest(mut form: MultipartFormData) -> Result<String> {
+    let mut name = None;
+    let mut module = None;
+    while let Some(field) = form.next_field().await? {
+        match field.name() {
+            Some("guest_name") => name = Some(field.text().await?),
+            Some("module_file") => module = Some(field.bytes().await?.to_vec()),
+            _ => {}
+        }
+    }
+
+    let (Some(name), Some(module)) = (name, module) else {
+        return Err(ServerFnError::new("guest_name and module_file are required").into());
+    };
+
+    let res = ext.server.create_module(name, module, Default::default()).await?;
     Ok(res.endpoint_id.to_string())
 }
 
@@ -87,6 +96,6 @@ pub struct UpdateGuest {
 
 #[put("/api/server/guest", ext: crate::AppStateExtension)]
 pub async fn update_guest(req: UpdateGuest) -> Result<bool> {
-    let res = ext.server.update_module(req.name, req.module).await?;
+    let res = ext.server.update_module(req.name, req.module, Default::default()).await?;
     Ok(res.success)
 }
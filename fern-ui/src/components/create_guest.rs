@@ -1,5 +1,6 @@
 use dioxus::prelude::*;
-use crate::api::{create_guest, CreateGuest};
+use dioxus::fullstack::MultipartFormData;
+use crate::api::create_guest;
 
 #[component]
 pub fn CreateGuestForm() -> Element {
@@ -46,19 +47,14 @@ pub fn CreateGuestForm() -> Element {
             return;
         }
 
-        let name = guest_name();
-        let module_data = file_data().unwrap();
+        // The form's guest_name and module_file fields are uploaded as multipart
+        let form: MultipartFormData = evt.into();
         
         is_creating.set(true);
         create_result.set(None);
         
         spawn(async move {
-            let request = CreateGuest {
-                name,
-                module: module_data,
-            };
-            
-            match create_guest(request).await {
+            match create_guest(form).await {
                 Ok(endpoint_id) => {
                     create_result.set(Some(Ok(endpoint_id)));
                     // Clear the form